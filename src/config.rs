@@ -0,0 +1,144 @@
+use std::{collections::HashMap, fs, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+	errors::DjError,
+	playlist::{Playlists, ScheduledSwitch, TagQuery},
+};
+
+/// Settings loaded from the file given with `--config`.
+///
+/// ```toml
+/// device = "CABLE Input"
+/// fallback_devices = ["Speakers"]
+/// playlist = "calm"
+///
+/// [playlists]
+/// calm = "calm and not menu"
+/// starting-soon = "menu or brb"
+///
+/// [[schedule]]
+/// at = "0s"
+/// playlist = "starting-soon"
+///
+/// [[schedule]]
+/// at = "10m"
+/// playlist = "calm"
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// The output device to play on, by name or by its number in `--list-devices`.
+	pub device: Option<String>,
+	/// Devices to play on if `device` goes away, in order of preference, until it comes back.
+	pub fallback_devices: Vec<String>,
+	/// The playlist to start with. Plays every song if not set.
+	pub playlist: Option<String>,
+	/// Playlist names, and the tag queries that select their songs.
+	pub playlists: HashMap<String, String>,
+	pub schedule: Vec<ScheduleEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+	/// How long after starting to switch, eg. `10m` or `1h 30m`.
+	pub at: String,
+	pub playlist: String,
+}
+
+impl Config {
+	pub fn load(path: &str) -> Result<Config, DjError> {
+		let text = fs::read_to_string(path).map_err(|e| DjError::InvalidConfig(path.to_string(), e.to_string()))?;
+		Config::parse(path, &text)
+	}
+
+	pub fn parse(path: &str, text: &str) -> Result<Config, DjError> {
+		toml::from_str(text).map_err(|e| DjError::InvalidConfig(path.to_string(), e.to_string()))
+	}
+
+	/// Builds the playlists described by the config. `active` overrides the configured starting playlist.
+	pub fn playlists(&self, active: Option<&str>) -> Result<Playlists, DjError> {
+		let mut playlists = HashMap::new();
+		for (name, query) in &self.playlists {
+			let query = query
+				.parse::<TagQuery>()
+				.map_err(|e| DjError::InvalidTagQuery(name.to_string(), e))?;
+			playlists.insert(name.to_string(), query);
+		}
+		let schedule = self
+			.schedule
+			.iter()
+			.map(|entry| {
+				Ok(ScheduledSwitch {
+					at: parse_duration(&entry.at)?,
+					playlist: entry.playlist.to_string(),
+				})
+			})
+			.collect::<Result<Vec<_>, DjError>>()?;
+		let active = active.map(String::from).or_else(|| self.playlist.clone());
+		Playlists::new(playlists, active, schedule)
+	}
+}
+
+fn parse_duration(text: &str) -> Result<Duration, DjError> {
+	humantime::parse_duration(text)
+		.map_err(|e| DjError::InvalidConfig("schedule".to_string(), format!("'{}': {}", text, e)))
+}
+
+#[cfg(test)]
+mod test_config {
+	use super::*;
+
+	#[test]
+	fn test_parse_config() {
+		let config = Config::parse(
+			"config.toml",
+			r#"
+			device = "Speakers"
+			fallback_devices = ["Headphones", "1"]
+			playlist = "calm"
+
+			[playlists]
+			calm = "calm and not menu"
+			starting-soon = "menu"
+
+			[[schedule]]
+			at = "0s"
+			playlist = "starting-soon"
+
+			[[schedule]]
+			at = "10m"
+			playlist = "calm"
+			"#,
+		)
+		.unwrap();
+		assert_eq!(config.device, Some("Speakers".to_string()));
+		assert_eq!(config.fallback_devices, vec!["Headphones".to_string(), "1".to_string()]);
+		assert_eq!(config.playlist, Some("calm".to_string()));
+		assert_eq!(config.playlists["calm"], "calm and not menu");
+		assert_eq!(
+			config.schedule[1],
+			ScheduleEntry {
+				at: "10m".to_string(),
+				playlist: "calm".to_string(),
+			}
+		);
+		let mut playlists = config.playlists(None).unwrap();
+		assert_eq!(playlists.active(), Some("calm"));
+		playlists.update_schedule();
+		assert_eq!(playlists.active(), Some("starting-soon"));
+	}
+
+	#[test]
+	fn test_invalid_config() {
+		assert!(Config::parse("config.toml", "playlist = 5").is_err());
+		assert!(Config::parse("config.toml", "unknown = true").is_err());
+
+		let config = Config::parse("config.toml", "[playlists]\ncalm = \"calm and\"").unwrap();
+		assert!(config.playlists(None).is_err());
+		let config = Config::parse("config.toml", "[[schedule]]\nat = \"soon\"\nplaylist = \"calm\"").unwrap();
+		assert!(config.playlists(None).is_err());
+	}
+}
@@ -0,0 +1,219 @@
+use std::{
+	io::{BufRead, BufReader, Write},
+	net::{TcpListener, TcpStream},
+	str::FromStr,
+	sync::mpsc::{channel, Receiver, Sender},
+	thread,
+};
+
+use tracing::{info, warn};
+
+use crate::{errors::DjError, plan::PlanMode, stinger::Quantize};
+
+/// A command sent to the player over the control interface.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+	/// Play a stinger over the current music.
+	Stinger { name: String, quantize: Quantize },
+	/// Change how many layers of layered segments are audible, from 0 to 1.
+	Intensity(f32),
+	/// Pick songs from this playlist once the current song is over, or from all songs if `None`.
+	Playlist(Option<String>),
+	/// Play the next song in this mode, eg. as a short bumper.
+	PlanMode(PlanMode),
+	/// Make the endless song that is playing head for an ending.
+	WrapUp,
+}
+
+impl FromStr for ControlCommand {
+	type Err = DjError;
+
+	fn from_str(line: &str) -> Result<Self, Self::Err> {
+		let mut words = line.split_whitespace();
+		let command = words
+			.next()
+			.ok_or_else(|| DjError::InvalidControlCommand("empty command".to_string()))?;
+		let parsed = match command {
+			"stinger" => {
+				let name = words
+					.next()
+					.ok_or_else(|| DjError::InvalidControlCommand("usage: stinger NAME [now|beat|bar]".to_string()))?;
+				let quantize = match words.next() {
+					Some(quantize) => quantize.parse()?,
+					None => Quantize::Immediate,
+				};
+				ControlCommand::Stinger {
+					name: name.to_string(),
+					quantize,
+				}
+			}
+			"intensity" => {
+				let intensity = words
+					.next()
+					.and_then(|value| value.parse::<f32>().ok())
+					.filter(|value| (0.0..=1.0).contains(value))
+					.ok_or_else(|| DjError::InvalidControlCommand("usage: intensity 0..1".to_string()))?;
+				ControlCommand::Intensity(intensity)
+			}
+			"playlist" => ControlCommand::Playlist(words.next().map(String::from)),
+			"wrap-up" => ControlCommand::WrapUp,
+			"plan-mode" => {
+				let mode = words.next().ok_or_else(|| {
+					DjError::InvalidControlCommand("usage: plan-mode full|bumper|endless".to_string())
+				})?;
+				ControlCommand::PlanMode(mode.parse()?)
+			}
+			_ => {
+				return Err(DjError::InvalidControlCommand(format!("unknown command '{}'", command)));
+			}
+		};
+		if let Some(extra) = words.next() {
+			return Err(DjError::InvalidControlCommand(format!(
+				"unexpected argument '{}'",
+				extra
+			)));
+		}
+		Ok(parsed)
+	}
+}
+
+/// A command along with a way to tell the client whether it succeeded.
+pub struct ControlRequest {
+	pub command: ControlCommand,
+	reply: Sender<Result<(), DjError>>,
+}
+
+impl ControlRequest {
+	pub fn respond(self, result: Result<(), DjError>) {
+		// The client may have hung up already, which is fine.
+		let _ = self.reply.send(result);
+	}
+}
+
+/// Starts listening for control connections on `addr` in the background.
+///
+/// The protocol is line based: each line is one command, and the server answers every
+/// command with either `ok` or `error: <reason>`.
+///
+/// ```text
+/// $ echo "stinger raid bar" | nc localhost 7878
+/// ok
+/// ```
+pub fn listen(addr: &str) -> Result<Receiver<ControlRequest>, DjError> {
+	let listener = TcpListener::bind(addr).map_err(|e| DjError::ControlServerError(e.to_string()))?;
+	info!(addr, "Listening for control commands");
+	let (tx, rx) = channel();
+	thread::Builder::new()
+		.name("control server".to_string())
+		.spawn(move || {
+			for stream in listener.incoming() {
+				match stream {
+					Ok(stream) => {
+						let tx = tx.clone();
+						thread::spawn(move || handle_client(stream, tx));
+					}
+					Err(e) => warn!("Failed to accept control connection: {}", e),
+				}
+			}
+		})
+		.map_err(|e| DjError::ControlServerError(e.to_string()))?;
+	Ok(rx)
+}
+
+fn handle_client(stream: TcpStream, requests: Sender<ControlRequest>) {
+	let mut writer = match stream.try_clone() {
+		Ok(writer) => writer,
+		Err(_) => return,
+	};
+	for line in BufReader::new(stream).lines() {
+		let line = match line {
+			Ok(line) => line,
+			Err(_) => return,
+		};
+		if line.trim().is_empty() {
+			continue;
+		}
+		let result = line.parse::<ControlCommand>().and_then(|command| {
+			let (reply, response) = channel();
+			requests
+				.send(ControlRequest { command, reply })
+				.map_err(|_| DjError::ControlServerError("player is not running".to_string()))?;
+			response
+				.recv()
+				.map_err(|_| DjError::ControlServerError("player did not respond".to_string()))?
+		});
+		let written = match result {
+			Ok(()) => writeln!(writer, "ok"),
+			Err(e) => writeln!(writer, "error: {}", e),
+		};
+		if written.is_err() {
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_control_commands {
+	use super::*;
+
+	#[test]
+	fn test_parse_stinger() {
+		assert_eq!(
+			"stinger raid".parse::<ControlCommand>(),
+			Ok(ControlCommand::Stinger {
+				name: "raid".to_string(),
+				quantize: Quantize::Immediate,
+			})
+		);
+		assert_eq!(
+			"stinger follow bar".parse::<ControlCommand>(),
+			Ok(ControlCommand::Stinger {
+				name: "follow".to_string(),
+				quantize: Quantize::Bar,
+			})
+		);
+		assert!("stinger".parse::<ControlCommand>().is_err());
+		assert!("stinger follow bar now".parse::<ControlCommand>().is_err());
+	}
+
+	#[test]
+	fn test_parse_intensity() {
+		assert_eq!(
+			"intensity 0.5".parse::<ControlCommand>(),
+			Ok(ControlCommand::Intensity(0.5))
+		);
+		assert!("intensity".parse::<ControlCommand>().is_err());
+		assert!("intensity loud".parse::<ControlCommand>().is_err());
+		assert!("intensity 1.5".parse::<ControlCommand>().is_err());
+	}
+
+	#[test]
+	fn test_parse_playlist() {
+		assert_eq!(
+			"playlist calm".parse::<ControlCommand>(),
+			Ok(ControlCommand::Playlist(Some("calm".to_string())))
+		);
+		assert_eq!("playlist".parse::<ControlCommand>(), Ok(ControlCommand::Playlist(None)));
+	}
+
+	#[test]
+	fn test_parse_plan_mode() {
+		assert_eq!(
+			"plan-mode bumper".parse::<ControlCommand>(),
+			Ok(ControlCommand::PlanMode(PlanMode::Bumper))
+		);
+		assert!("plan-mode".parse::<ControlCommand>().is_err());
+		assert_eq!("wrap-up".parse::<ControlCommand>(), Ok(ControlCommand::WrapUp));
+		assert!("wrap-up now".parse::<ControlCommand>().is_err());
+		assert_eq!(
+			"plan-mode short".parse::<ControlCommand>(),
+			Err(DjError::PlanModeInvalidValue)
+		);
+	}
+
+	#[test]
+	fn test_parse_unknown_command() {
+		assert!("".parse::<ControlCommand>().is_err());
+		assert!("dance".parse::<ControlCommand>().is_err());
+	}
+}
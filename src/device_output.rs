@@ -0,0 +1,359 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
+	thread,
+	time::{Duration, Instant},
+};
+
+use cpal::{
+	traits::{DeviceTrait, EventLoopTrait, HostTrait},
+	Device, EventLoop, Format, Sample, SampleRate, StreamData, StreamDataResult, StreamId, UnknownTypeOutputBuffer,
+};
+use rodio::{dynamic_mixer::DynamicMixer, source::UniformSourceIterator, Source};
+use tracing::{info, warn};
+
+use crate::{errors::DjError, position_source::PlaybackPosition, resample::OutputFormat};
+
+/// How long a device can go without asking for audio before it's considered lost.
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often to check that the device is still playing.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+/// How often to look for a device to play on while there is none.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The names of the available output devices. Devices can be chosen by name or by their position
+/// in this list.
+pub fn list_devices() -> Result<Vec<String>, DjError> {
+	Ok(output_devices()?.into_iter().map(|(name, _)| name).collect())
+}
+
+fn output_devices() -> Result<Vec<(String, Device)>, DjError> {
+	let devices = cpal::default_host()
+		.output_devices()
+		.map_err(|e| DjError::OutputError(e.to_string()))?;
+	Ok(devices.map(|device| (device_name(&device), device)).collect())
+}
+
+fn device_name(device: &Device) -> String {
+	device.name().unwrap_or_else(|_| "<unnamed>".to_string())
+}
+
+/// Finds a device by name or index, or the default device if `selector` is `None`.
+fn find_device(selector: Option<&str>) -> Result<(String, Device), DjError> {
+	let selector = match selector {
+		Some(selector) => selector,
+		None => {
+			let device = cpal::default_host()
+				.default_output_device()
+				.ok_or(DjError::NoOutputDeviceAvailable)?;
+			return Ok((device_name(&device), device));
+		}
+	};
+	let mut devices = output_devices()?;
+	let names = devices.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>();
+	match select_device(&names, selector) {
+		Some(index) => Ok(devices.swap_remove(index)),
+		None => Err(DjError::OutputDeviceNotFound(selector.to_string(), names)),
+	}
+}
+
+/// Finds the device that `selector` refers to. Exact names win over names that only differ in
+/// case, which win over indexes.
+fn select_device(names: &[String], selector: &str) -> Option<usize> {
+	names
+		.iter()
+		.position(|name| name == selector)
+		.or_else(|| names.iter().position(|name| name.eq_ignore_ascii_case(selector)))
+		.or_else(|| selector.parse().ok().filter(|index| *index < names.len()))
+}
+
+/// Plays the mix on an audio device.
+///
+/// If the device goes away, or stops asking for audio, the music waits where it is while the
+/// device is looked for again. Fallback devices are tried too. Once something is playing again,
+/// the segment that was cut off is ended, so the music picks up at the start of the next one.
+pub struct DeviceOutput {
+	shared: Arc<Shared>,
+}
+
+struct Shared {
+	event_loop: EventLoop,
+	mix: SharedMix,
+	/// The devices to play on, in order of preference. `None` is the default device.
+	devices: Vec<Option<String>>,
+	state: Mutex<DeviceState>,
+	/// The position of the music, interrupted after reconnecting.
+	position: Mutex<Option<Arc<PlaybackPosition>>>,
+}
+
+#[derive(Default)]
+struct DeviceState {
+	/// The stream that is playing, and the name of its device.
+	current: Option<(StreamId, String)>,
+	/// Converts the mix to the format of each stream's device.
+	converters: HashMap<StreamId, UniformSourceIterator<SharedMix, f32>>,
+	last_callback: Option<Instant>,
+	/// Set when the current stream reports an error.
+	failed: bool,
+}
+
+impl DeviceOutput {
+	/// Starts playing `mixer` on the device chosen by `device`, or the default device.
+	/// `fallback_devices` are only used if that device goes away.
+	pub fn open(
+		mixer: DynamicMixer<f32>, format: OutputFormat, device: Option<&str>, fallback_devices: &[String],
+	) -> Result<DeviceOutput, DjError> {
+		let mut devices = vec![device.map(String::from)];
+		devices.extend(fallback_devices.iter().cloned().map(Some));
+		let shared = Arc::new(Shared {
+			event_loop: cpal::default_host().event_loop(),
+			mix: SharedMix {
+				mixer: Arc::new(Mutex::new(mixer)),
+				format,
+				chunk: Arc::new(AtomicUsize::new(format.channels as usize)),
+				taken: VecDeque::new(),
+			},
+			devices,
+			state: Mutex::new(DeviceState::default()),
+			position: Mutex::new(None),
+		});
+		// The chosen device has to be there to start with, so that typos are caught.
+		let (name, device) = find_device(device)?;
+		shared.start_stream(&name, &device)?;
+		info!(device = %name, "Playing on audio device");
+
+		let event_loop = shared.clone();
+		thread::spawn(move || {
+			event_loop
+				.event_loop
+				.run(|stream_id, result| event_loop.callback(stream_id, result))
+		});
+		let monitor = shared.clone();
+		thread::spawn(move || monitor.monitor());
+		Ok(DeviceOutput { shared })
+	}
+
+	/// After reconnecting, ends the source that `position` tracks: the current play of a segment,
+	/// so that a loop carries on from the start of its next repeat.
+	pub fn interrupt_on_reconnect(&self, position: Arc<PlaybackPosition>) {
+		*self.shared.position.lock().unwrap() = Some(position);
+	}
+}
+
+impl Shared {
+	fn start_stream(&self, name: &str, device: &Device) -> Result<(), DjError> {
+		let unplayable = |e: String| DjError::OutputError(format!("unable to play on {}: {}", name, e));
+		let format = stream_format(device, self.mix.format).map_err(unplayable)?;
+		let stream_id = self
+			.event_loop
+			.build_output_stream(device, &format)
+			.map_err(|e| unplayable(e.to_string()))?;
+		{
+			let mut state = self.state.lock().unwrap();
+			state.converters.insert(
+				stream_id.clone(),
+				UniformSourceIterator::new(self.mix.clone(), format.channels, format.sample_rate.0),
+			);
+			state.current = Some((stream_id.clone(), name.to_string()));
+			state.last_callback = Some(Instant::now());
+			state.failed = false;
+		}
+		self.event_loop
+			.play_stream(stream_id)
+			.map_err(|e| unplayable(e.to_string()))
+	}
+
+	fn callback(&self, stream_id: StreamId, result: StreamDataResult) {
+		let mut state = self.state.lock().unwrap();
+		let is_current = state.current.as_ref().is_some_and(|(id, _)| id == &stream_id);
+		let buffer = match result {
+			Ok(StreamData::Output { buffer }) => buffer,
+			Ok(StreamData::Input { .. }) => return,
+			Err(e) => {
+				if is_current {
+					warn!(device = %state.current.as_ref().unwrap().1, "Lost the audio device: {}", e);
+					state.failed = true;
+				}
+				state.converters.remove(&stream_id);
+				return;
+			}
+		};
+		if is_current {
+			state.last_callback = Some(Instant::now());
+		}
+		let mut converter = state.converters.get_mut(&stream_id).filter(|_| is_current);
+		if let Some(converter) = &converter {
+			// Take as much of the mix as the buffer needs in one go, so that the mixer is only locked
+			// once for it.
+			let frames = (buffer.len() / converter.channels() as usize) as u64;
+			let mix_frames = (frames * self.mix.format.sample_rate as u64).div_ceil(converter.sample_rate() as u64);
+			self.mix.chunk.store(
+				mix_frames as usize * self.mix.format.channels as usize,
+				Ordering::Relaxed,
+			);
+		}
+		let mut next = || converter.as_mut().and_then(|c| c.next()).unwrap_or(0.0);
+		match buffer {
+			UnknownTypeOutputBuffer::U16(mut buffer) => buffer.iter_mut().for_each(|d| *d = next().to_u16()),
+			UnknownTypeOutputBuffer::I16(mut buffer) => buffer.iter_mut().for_each(|d| *d = next().to_i16()),
+			UnknownTypeOutputBuffer::F32(mut buffer) => buffer.iter_mut().for_each(|d| *d = next()),
+		}
+	}
+
+	/// Watches the current stream, and replaces it when its device is lost.
+	fn monitor(&self) {
+		loop {
+			thread::sleep(CHECK_INTERVAL);
+			let lost = {
+				let mut state = self.state.lock().unwrap();
+				let stalled = state.last_callback.is_some_and(|at| at.elapsed() > STALL_TIMEOUT);
+				match state.current.take() {
+					Some((stream_id, name)) if state.failed || stalled => {
+						if !state.failed {
+							warn!(device = %name, "The audio device stopped playing");
+						}
+						state.converters.remove(&stream_id);
+						Some(stream_id)
+					}
+					current => {
+						state.current = current;
+						None
+					}
+				}
+			};
+			let stream_id = match lost {
+				Some(stream_id) => stream_id,
+				None => continue,
+			};
+			self.event_loop.destroy_stream(stream_id);
+
+			info!("Waiting for an audio device to play on");
+			while !self.reconnect() {
+				thread::sleep(RECONNECT_INTERVAL);
+			}
+			if let Some(position) = self.position.lock().unwrap().as_ref() {
+				position.interrupt();
+			}
+		}
+	}
+
+	/// Starts playing on the first of the devices that is available.
+	fn reconnect(&self) -> bool {
+		for selector in &self.devices {
+			if let Ok((name, device)) = find_device(selector.as_deref()) {
+				match self.start_stream(&name, &device) {
+					Ok(()) => {
+						info!(device = %name, "Reconnected, playing on the audio device from the next repeat or segment");
+						return true;
+					}
+					Err(e) => warn!("{}", e),
+				}
+			}
+		}
+		false
+	}
+}
+
+/// The format to play on a device with. Uses the mix's format if the device supports it, so that
+/// it doesn't need converting.
+fn stream_format(device: &Device, mix: OutputFormat) -> Result<Format, String> {
+	let supported = device
+		.supported_output_formats()
+		.map_err(|e| e.to_string())?
+		.find(|format| {
+			format.channels == mix.channels
+				&& format.min_sample_rate.0 <= mix.sample_rate
+				&& mix.sample_rate <= format.max_sample_rate.0
+		});
+	match supported {
+		Some(format) => Ok(Format {
+			channels: mix.channels,
+			sample_rate: SampleRate(mix.sample_rate),
+			data_type: format.data_type,
+		}),
+		None => device.default_output_format().map_err(|e| e.to_string()),
+	}
+}
+
+/// The mix, shared between the streams of every device it has been played on.
+#[derive(Clone)]
+struct SharedMix {
+	mixer: Arc<Mutex<DynamicMixer<f32>>>,
+	format: OutputFormat,
+	/// How many samples to take from the mixer at a time, set to what the device's next buffer
+	/// needs.
+	chunk: Arc<AtomicUsize>,
+	/// Samples taken from the mixer that haven't been played yet.
+	taken: VecDeque<f32>,
+}
+
+impl Iterator for SharedMix {
+	type Item = f32;
+
+	fn next(&mut self) -> Option<f32> {
+		if self.taken.is_empty() {
+			let mut mixer = self.mixer.lock().unwrap();
+			let chunk = self.chunk.load(Ordering::Relaxed).max(1);
+			// The mixer is empty before anything has been played.
+			self.taken.extend((0..chunk).map(|_| mixer.next().unwrap_or(0.0)));
+		}
+		self.taken.pop_front()
+	}
+}
+
+impl Source for SharedMix {
+	fn current_frame_len(&self) -> Option<usize> {
+		None
+	}
+
+	fn channels(&self) -> u16 {
+		self.format.channels
+	}
+
+	fn sample_rate(&self) -> u32 {
+		self.format.sample_rate
+	}
+
+	fn total_duration(&self) -> Option<Duration> {
+		None
+	}
+}
+
+#[cfg(test)]
+mod test_device_output {
+	use super::*;
+
+	#[test]
+	fn test_select_device() {
+		let names = vec!["Speakers".to_string(), "CABLE Input".to_string(), "1".to_string()];
+		assert_eq!(select_device(&names, "CABLE Input"), Some(1));
+		assert_eq!(select_device(&names, "speakers"), Some(0));
+		assert_eq!(select_device(&names, "1"), Some(2));
+		assert_eq!(select_device(&names, "0"), Some(0));
+		assert_eq!(select_device(&names, "3"), None);
+		assert_eq!(select_device(&names, "Headphones"), None);
+	}
+
+	#[test]
+	fn test_shared_mix_takes_chunks() {
+		let (controller, mixer) = rodio::dynamic_mixer::mixer::<f32>(1, 10);
+		let mut mix = SharedMix {
+			mixer: Arc::new(Mutex::new(mixer)),
+			format: OutputFormat {
+				channels: 1,
+				sample_rate: 10,
+			},
+			chunk: Arc::new(AtomicUsize::new(4)),
+			taken: VecDeque::new(),
+		};
+		// Silence until something is played.
+		assert_eq!(mix.next(), Some(0.0));
+		assert_eq!(mix.taken.len(), 3);
+		controller.add(rodio::buffer::SamplesBuffer::new(1, 10, vec![0.5f32; 10]));
+		assert_eq!(mix.by_ref().take(4).collect::<Vec<_>>(), vec![0.0, 0.0, 0.0, 0.5]);
+		assert_eq!(mix.taken.len(), 3);
+	}
+}
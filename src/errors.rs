@@ -3,18 +3,96 @@ use std::{error::Error, fmt};
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DjError {
 	NoOutputDeviceAvailable,
+	OutputDeviceNotFound(String, Vec<String>),
 	MaxRepeatsInvalidValue,
+	PlanCountInvalidValue,
+	SeedInvalidValue,
+	GraphFormatInvalidValue,
+	PlanModeInvalidValue,
+	SelectionInvalidValue,
+	KeyInvalidValue(String),
+	CacheSizeInvalidValue,
+	SampleRateInvalidValue,
+	ChannelsInvalidValue,
+	UnsupportedSampleRate(u32, u32, String),
+	InvalidOutput(String),
+	UnsupportedOutput(String),
+	WavRollInvalidValue,
+	OutputError(String),
+	LogLevelInvalidValue,
+	UnwritableLogFile(String, String),
+	UnwritableHistory(String, String),
+	HistoryRequired,
 	UnrecognizedSongFormat(String),
 	PathNotValidUnicode,
 	InvalidFileName(String),
 	MultipleSegmentsWithSameId(String, String),
+	InvalidSongMetadata(String, String),
+	UnreadableDirectory(String),
+	UnreadableFile(String, String),
+	UnknownStinger(String),
+	DuckVolumeInvalidValue,
+	InvalidControlCommand(String),
+	ControlServerError(String),
+	InvalidConfig(String, String),
+	InvalidTagQuery(String, String),
+	UnknownPlaylist(String),
+	EmptyPlaylist(String),
+	NoSongs,
+	ConflictingSongs(String, String, String),
+	UnknownSong(String),
+	UnplannableSong(String),
+	NotEndless(String),
+	InvalidPlan(String, String),
+	Stopped,
 }
 
 impl fmt::Display for DjError {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			DjError::NoOutputDeviceAvailable => write!(f, "no output device is available"),
+			DjError::OutputDeviceNotFound(device, available) if available.is_empty() => {
+				write!(
+					f,
+					"output device '{}' not found, no output devices are available",
+					device
+				)
+			}
+			DjError::OutputDeviceNotFound(device, available) => write!(
+				f,
+				"output device '{}' not found, available devices: {}",
+				device,
+				available.join(", ")
+			),
 			DjError::MaxRepeatsInvalidValue => write!(f, "invalid value for max-repeats"),
+			DjError::PlanCountInvalidValue => write!(f, "invalid value for count, must be at least 1"),
+			DjError::SeedInvalidValue => write!(f, "invalid value for seed, must be a whole number"),
+			DjError::GraphFormatInvalidValue => write!(f, "invalid value for format, must be dot or mermaid"),
+			DjError::PlanModeInvalidValue => write!(f, "invalid value for plan-mode, must be full, bumper or endless"),
+			DjError::SelectionInvalidValue => write!(f, "invalid value for selection, must be random or harmonic"),
+			DjError::KeyInvalidValue(key) => write!(
+				f,
+				"invalid value for key: '{}', must be a Camelot code like 8A or a key like A minor",
+				key
+			),
+			DjError::CacheSizeInvalidValue => write!(f, "invalid value for cache-size"),
+			DjError::SampleRateInvalidValue => write!(f, "invalid value for sample-rate"),
+			DjError::ChannelsInvalidValue => write!(f, "invalid value for channels"),
+			DjError::UnsupportedSampleRate(from, to, reason) => {
+				write!(f, "unable to convert audio from {} Hz to {} Hz: {}", from, to, reason)
+			}
+			DjError::InvalidOutput(output) => write!(
+				f,
+				"invalid output '{}', expected device, stdout, fifo:PATH, wav:PATH or http:ADDR[/MOUNT]",
+				output
+			),
+			DjError::UnsupportedOutput(output) => write!(f, "output '{}' is only supported on Unix", output),
+			DjError::WavRollInvalidValue => write!(f, "invalid value for wav-roll"),
+			DjError::LogLevelInvalidValue => write!(f, "invalid value for log-level"),
+			DjError::UnwritableLogFile(path, e) => write!(f, "unable to write to log file '{}': {}", path, e),
+			DjError::UnwritableHistory(path, e) => write!(f, "unable to write to history file '{}': {}", path, e),
+			DjError::HistoryRequired => write!(f, "a history file must be given with --history"),
+			DjError::OutputError(reason) => write!(f, "unable to write audio: {}", reason),
 			DjError::UnrecognizedSongFormat(file_name) => write!(
 				f,
 				"'{}' - unrecognized song format. Only wav, flac, ogg, mp3 and zip are supported",
@@ -34,6 +112,36 @@ impl fmt::Display for DjError {
 				"found multiple segments with same ID: Song: {} Segment: {}",
 				song_id, segment_id
 			),
+			DjError::InvalidSongMetadata(song_id, reason) => {
+				write!(f, "invalid metadata for song '{}': {}", song_id, reason)
+			}
+			DjError::UnreadableDirectory(dir) => write!(f, "unable to list files in '{}'", dir),
+			DjError::UnreadableFile(path, reason) => write!(f, "unable to read '{}': {}", path, reason),
+			DjError::UnknownStinger(name) => write!(f, "no stinger named '{}'", name),
+			DjError::DuckVolumeInvalidValue => write!(f, "invalid value for duck-volume, must be between 0 and 1"),
+			DjError::InvalidControlCommand(reason) => write!(f, "invalid control command: {}", reason),
+			DjError::ControlServerError(reason) => write!(f, "control server error: {}", reason),
+			DjError::InvalidConfig(path, reason) => write!(f, "invalid config '{}': {}", path, reason),
+			DjError::InvalidTagQuery(playlist, reason) => {
+				write!(f, "invalid tag query for playlist '{}': {}", playlist, reason)
+			}
+			DjError::UnknownPlaylist(name) => write!(f, "no playlist named '{}'", name),
+			DjError::EmptyPlaylist(name) => write!(f, "no songs match playlist '{}'", name),
+			DjError::NoSongs => write!(f, "no songs were found"),
+			DjError::ConflictingSongs(song_id, first, second) => write!(
+				f,
+				"found multiple songs with same ID: Song: {} in '{}' and '{}'",
+				song_id, first, second
+			),
+			DjError::UnknownSong(song_id) => write!(f, "no song with ID '{}' was found", song_id),
+			DjError::UnplannableSong(song_id) => {
+				write!(f, "song '{}' has no way to get from its start to an ending", song_id)
+			}
+			DjError::NotEndless(song_id) => {
+				write!(f, "song '{}' has no loop it can keep playing endlessly", song_id)
+			}
+			DjError::InvalidPlan(song_id, reason) => write!(f, "invalid plan for song '{}': {}", song_id, reason),
+			DjError::Stopped => write!(f, "stopped"),
 		}
 	}
 }
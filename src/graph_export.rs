@@ -0,0 +1,394 @@
+use std::{collections::HashMap, fmt::Write, str::FromStr};
+
+use crate::{
+	errors::DjError,
+	planner::{can_finish_on, distances_to_finish},
+	Song,
+};
+
+/// The diagram languages a song's transitions can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+	/// Graphviz, eg. `dot -Tsvg`.
+	Dot,
+	/// Mermaid flowcharts, which render on GitHub and in many wikis.
+	Mermaid,
+}
+
+impl FromStr for GraphFormat {
+	type Err = DjError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"dot" => Ok(GraphFormat::Dot),
+			"mermaid" => Ok(GraphFormat::Mermaid),
+			_ => Err(DjError::GraphFormatInvalidValue),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+	Start,
+	/// A segment the song begins on when it follows a certain other song.
+	Bridge,
+	Loop,
+	DedicatedTransition,
+	End,
+	/// A transition leads to a segment the song doesn't have.
+	Missing,
+}
+
+/// A segment, and what there is to know about it for finding problems in the song.
+#[derive(Debug)]
+struct Node {
+	id: String,
+	kind: NodeKind,
+	/// Whether the segment can be reached from a start or a bridge.
+	reachable: bool,
+	/// Whether there is no way to finish the song after the segment.
+	dead_end: bool,
+}
+
+/// Exports the transitions between a song's segments as a diagram. Segments that can't be reached
+/// from the start, and dead ends that can't lead to a segment the song can finish on, are styled
+/// differently, as are transitions to segments that don't exist.
+pub fn export(song: &Song, format: GraphFormat) -> String {
+	let (nodes, edges) = nodes_and_edges(song);
+	match format {
+		GraphFormat::Dot => to_dot(&song.id, &nodes, &edges),
+		GraphFormat::Mermaid => to_mermaid(&nodes, &edges),
+	}
+}
+
+fn nodes_and_edges(song: &Song) -> (Vec<Node>, Vec<(usize, usize)>) {
+	let mut segments = song.segments.values().collect::<Vec<_>>();
+	segments.sort_by(|a, b| a.id.cmp(&b.id));
+	let mut nodes = segments
+		.iter()
+		.map(|segment| Node {
+			id: segment.id.to_string(),
+			kind: if segment.is_start() {
+				NodeKind::Start
+			}
+			else if segment.is_bridge() {
+				NodeKind::Bridge
+			}
+			else if segment.is_dedicated_transition() {
+				NodeKind::DedicatedTransition
+			}
+			else if segment.is_end() {
+				NodeKind::End
+			}
+			else {
+				NodeKind::Loop
+			},
+			reachable: false,
+			dead_end: false,
+		})
+		.collect::<Vec<_>>();
+	let mut indexes = nodes
+		.iter()
+		.enumerate()
+		.map(|(i, node)| (node.id.to_string(), i))
+		.collect::<HashMap<_, _>>();
+
+	let mut edges = Vec::new();
+	for (from, segment) in segments.iter().enumerate() {
+		let mut next = segment.allowed_transitions.iter().collect::<Vec<_>>();
+		next.sort();
+		for id in next {
+			let to = *indexes.entry(id.to_string()).or_insert_with(|| {
+				nodes.push(Node {
+					id: id.to_string(),
+					kind: NodeKind::Missing,
+					reachable: false,
+					dead_end: false,
+				});
+				nodes.len() - 1
+			});
+			edges.push((from, to));
+		}
+	}
+
+	let mut transitions = vec![Vec::new(); nodes.len()];
+	for (from, to) in &edges {
+		transitions[*from].push(*to);
+	}
+	let finishes = nodes
+		.iter()
+		.map(|node| node.kind != NodeKind::Missing && can_finish_on(song, &song.segments[&node.id]))
+		.collect::<Vec<_>>();
+	for (node, distance) in nodes.iter_mut().zip(distances_to_finish(&transitions, &finishes)) {
+		node.dead_end = distance.is_none();
+	}
+	let mut stack = (0..nodes.len())
+		.filter(|i| matches!(nodes[*i].kind, NodeKind::Start | NodeKind::Bridge))
+		.collect::<Vec<_>>();
+	for start in &stack {
+		nodes[*start].reachable = true;
+	}
+	while let Some(from) = stack.pop() {
+		for to in &transitions[from] {
+			if !nodes[*to].reachable {
+				nodes[*to].reachable = true;
+				stack.push(*to);
+			}
+		}
+	}
+	(nodes, edges)
+}
+
+/// Escapes a name so it can go between double quotes in DOT.
+fn escape_dot(name: &str) -> String {
+	name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escapes a name so it can go between double quotes in a Mermaid label.
+fn escape_mermaid(name: &str) -> String {
+	name.replace('"', "#quot;")
+}
+
+fn to_dot(song_id: &str, nodes: &[Node], edges: &[(usize, usize)]) -> String {
+	let mut dot = String::new();
+	writeln!(dot, "digraph \"{}\" {{", escape_dot(song_id)).unwrap();
+	writeln!(dot, "\trankdir=LR;").unwrap();
+	for node in nodes {
+		let shape = match node.kind {
+			NodeKind::Start => "box",
+			NodeKind::Bridge => "cds",
+			NodeKind::Loop => "ellipse",
+			NodeKind::DedicatedTransition => "diamond",
+			NodeKind::End => "doublecircle",
+			NodeKind::Missing => "plaintext",
+		};
+		let mut styles = Vec::new();
+		if node.kind == NodeKind::Start {
+			styles.push("bold");
+		}
+		if !node.reachable {
+			styles.push("dashed");
+		}
+		let mut label = escape_dot(&node.id);
+		if node.kind == NodeKind::Missing {
+			label.push_str("\\n(missing)");
+		}
+		else if !node.reachable {
+			label.push_str("\\n(unreachable)");
+		}
+		else if node.dead_end {
+			label.push_str("\\n(dead end)");
+		}
+		write!(
+			dot,
+			"\t\"{}\" [label=\"{}\", shape={}",
+			escape_dot(&node.id),
+			label,
+			shape
+		)
+		.unwrap();
+		if !styles.is_empty() {
+			write!(dot, ", style=\"{}\"", styles.join(",")).unwrap();
+		}
+		if node.kind == NodeKind::Missing || node.dead_end {
+			write!(dot, ", color=red, fontcolor=red").unwrap();
+		}
+		else if !node.reachable {
+			write!(dot, ", color=gray, fontcolor=gray").unwrap();
+		}
+		writeln!(dot, "];").unwrap();
+	}
+	for (from, to) in edges {
+		writeln!(
+			dot,
+			"\t\"{}\" -> \"{}\";",
+			escape_dot(&nodes[*from].id),
+			escape_dot(&nodes[*to].id)
+		)
+		.unwrap();
+	}
+	writeln!(dot, "}}").unwrap();
+	dot
+}
+
+fn to_mermaid(nodes: &[Node], edges: &[(usize, usize)]) -> String {
+	let mut mermaid = String::new();
+	writeln!(mermaid, "flowchart LR").unwrap();
+	for (i, node) in nodes.iter().enumerate() {
+		let (open, close) = match node.kind {
+			NodeKind::Start => ("([", "])"),
+			NodeKind::Bridge => (">", "]"),
+			NodeKind::Loop => ("(", ")"),
+			NodeKind::DedicatedTransition => ("{{", "}}"),
+			NodeKind::End => ("(((", ")))"),
+			NodeKind::Missing => ("[/", "/]"),
+		};
+		let note = if node.kind == NodeKind::Missing {
+			"<br/>(missing)"
+		}
+		else if !node.reachable {
+			"<br/>(unreachable)"
+		}
+		else if node.dead_end {
+			"<br/>(dead end)"
+		}
+		else {
+			""
+		};
+		writeln!(
+			mermaid,
+			"\tn{}{}\"{}{}\"{}",
+			i,
+			open,
+			escape_mermaid(&node.id),
+			note,
+			close
+		)
+		.unwrap();
+	}
+	for (from, to) in edges {
+		writeln!(mermaid, "\tn{} --> n{}", from, to).unwrap();
+	}
+	writeln!(mermaid, "\tclassDef unreachable stroke-dasharray:5 5,color:#888").unwrap();
+	writeln!(mermaid, "\tclassDef deadEnd stroke:#d00,color:#d00").unwrap();
+	let class = |name: &str, filter: &dyn Fn(&Node) -> bool| {
+		let members = (0..nodes.len())
+			.filter(|i| filter(&nodes[*i]))
+			.map(|i| format!("n{}", i))
+			.collect::<Vec<_>>();
+		if members.is_empty() {
+			String::new()
+		}
+		else {
+			format!("\tclass {} {}\n", members.join(","), name)
+		}
+	};
+	mermaid.push_str(&class("unreachable", &|node| !node.reachable));
+	mermaid.push_str(&class("deadEnd", &|node| node.dead_end));
+	mermaid
+}
+
+#[cfg(test)]
+mod test_graph_export {
+	use super::*;
+	use crate::{metadata::SongMetadata, song_source::LooseFiles, SongSegment};
+	use std::sync::Arc;
+
+	fn song() -> Song {
+		let segment = |id: &str, transitions: &[&str]| SongSegment {
+			id: id.to_string(),
+			format: "ogg".to_string(),
+			allowed_transitions: transitions.iter().map(|s| s.to_string()).collect(),
+			layers: vec![],
+		};
+		Song {
+			id: "song".to_string(),
+			segments: vec![
+				segment("start", &["loop0"]),
+				segment("loop0", &["loop0-to-1", "loop0-end"]),
+				segment("loop0-to-1", &["loop1"]),
+				segment("loop1", &["loop1-to-3"]),
+				segment("loop1-to-3", &["loop3"]),
+				segment("loop0-end", &[]),
+				segment("loop2", &["loop0"]),
+			]
+			.into_iter()
+			.map(|segment| (segment.id.to_string(), segment))
+			.collect(),
+			stingers: HashMap::new(),
+			metadata: SongMetadata::default(),
+			has_end: true,
+			has_global_ending: false,
+			has_multiple_loops: true,
+			has_dedicated_transitions: true,
+			source: Arc::new(LooseFiles::new("", "")),
+		}
+	}
+
+	#[test]
+	fn test_nodes() {
+		let (nodes, edges) = nodes_and_edges(&song());
+		let summary = nodes
+			.iter()
+			.map(|node| (node.id.as_str(), node.kind, node.reachable, node.dead_end))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			summary,
+			vec![
+				("loop0", NodeKind::Loop, true, false),
+				("loop0-end", NodeKind::End, true, false),
+				("loop0-to-1", NodeKind::DedicatedTransition, true, true),
+				("loop1", NodeKind::Loop, true, true),
+				("loop1-to-3", NodeKind::DedicatedTransition, true, true),
+				("loop2", NodeKind::Loop, false, false),
+				("start", NodeKind::Start, true, false),
+				("loop3", NodeKind::Missing, true, true),
+			]
+		);
+		assert_eq!(edges.len(), 7);
+	}
+
+	#[test]
+	fn test_bridge() {
+		let mut song = song();
+		song.segments.insert(
+			"from-other".to_string(),
+			SongSegment {
+				id: "from-other".to_string(),
+				format: "ogg".to_string(),
+				allowed_transitions: vec!["loop2".to_string()].into_iter().collect(),
+				layers: vec![],
+			},
+		);
+		let (nodes, _) = nodes_and_edges(&song);
+		let kind_and_reachable = |id: &str| {
+			let node = nodes.iter().find(|node| node.id == id).unwrap();
+			(node.kind, node.reachable)
+		};
+		assert_eq!(kind_and_reachable("from-other"), (NodeKind::Bridge, true));
+		assert_eq!(kind_and_reachable("loop2"), (NodeKind::Loop, true));
+		assert!(export(&song, GraphFormat::Dot).contains("\t\"from-other\" [label=\"from-other\", shape=cds];\n"));
+		assert!(export(&song, GraphFormat::Mermaid).contains(">\"from-other\"]"));
+	}
+
+	#[test]
+	fn test_dot() {
+		let dot = export(&song(), GraphFormat::Dot);
+		assert!(dot.starts_with("digraph \"song\" {\n\trankdir=LR;\n"));
+		assert!(dot.contains("\t\"start\" [label=\"start\", shape=box, style=\"bold\"];\n"));
+		assert!(dot.contains(
+			"\t\"loop2\" [label=\"loop2\\n(unreachable)\", shape=ellipse, style=\"dashed\", color=gray, fontcolor=gray];\n"
+		));
+		assert!(dot.contains("\t\"loop1\" [label=\"loop1\\n(dead end)\", shape=ellipse, color=red, fontcolor=red];\n"));
+		assert!(dot.contains("\t\"loop1-to-3\" -> \"loop3\";\n"));
+		assert!(dot.ends_with("}\n"));
+	}
+
+	#[test]
+	fn test_quoted_names() {
+		let mut song = song();
+		song.id = "say \"hi\" - live".to_string();
+		song.segments
+			.get_mut("loop0")
+			.unwrap()
+			.allowed_transitions
+			.insert("loop\\\"2\"".to_string());
+		let dot = export(&song, GraphFormat::Dot);
+		assert!(dot.starts_with("digraph \"say \\\"hi\\\" - live\" {\n"));
+		assert!(dot.contains("\t\"loop0\" -> \"loop\\\\\\\"2\\\"\";\n"));
+		assert!(dot.contains("\t\"loop\\\\\\\"2\\\"\" [label=\"loop\\\\\\\"2\\\"\\n(missing)\", shape=plaintext"));
+		let mermaid = export(&song, GraphFormat::Mermaid);
+		assert!(mermaid.contains("[/\"loop\\#quot;2#quot;<br/>(missing)\"/]\n"));
+	}
+
+	#[test]
+	fn test_mermaid() {
+		let mermaid = export(&song(), GraphFormat::Mermaid);
+		assert!(mermaid.starts_with("flowchart LR\n\tn0(\"loop0\")\n\tn1(((\"loop0-end\")))\n"));
+		assert!(mermaid.contains("\tn2{{\"loop0-to-1<br/>(dead end)\"}}\n"));
+		assert!(mermaid.contains("\tn7[/\"loop3<br/>(missing)\"/]\n"));
+		assert!(mermaid.contains("\tn6 --> n0\n"));
+		assert!(mermaid.ends_with("\tclass n5 unreachable\n\tclass n2,n3,n4,n7 deadEnd\n"));
+		assert_eq!("svg".parse::<GraphFormat>(), Err(DjError::GraphFormatInvalidValue));
+	}
+}
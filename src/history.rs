@@ -0,0 +1,348 @@
+use std::{
+	collections::{BTreeMap, HashSet},
+	fmt,
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::errors::DjError;
+
+/// A song that has been played, as stored in the history file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Play {
+	/// When the song started playing, in RFC 3339 format.
+	pub started: String,
+	pub song: String,
+	pub plan: Vec<PlannedSegment>,
+	/// How long the song actually played for, in seconds.
+	pub played: f64,
+	pub end: PlayEnd,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedSegment {
+	pub id: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub repeat_count: Option<u32>,
+}
+
+/// Why a song stopped playing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlayEnd {
+	/// The song played until the next one started.
+	Finished,
+	/// Part of the song was skipped, because the audio device was lost.
+	Interrupted,
+	/// The song was endless, and was asked to head for an ending.
+	WrappedUp,
+	/// The DJ stopped while the song was playing.
+	Stopped,
+}
+
+impl Play {
+	pub fn new(song: &str, plan: Vec<PlannedSegment>, started: SystemTime, played: Duration, end: PlayEnd) -> Play {
+		Play {
+			started: humantime::format_rfc3339_seconds(started).to_string(),
+			song: song.to_string(),
+			plan,
+			played: played.as_secs_f64(),
+			end,
+		}
+	}
+}
+
+/// Every song played so far, kept in a file with one JSON object per line so that it lasts
+/// between runs.
+#[derive(Debug)]
+pub struct History {
+	path: String,
+	file: File,
+	plays: Vec<Play>,
+}
+
+impl History {
+	/// Reads the history in `path`, creating the file if it doesn't exist. Lines that can't be read
+	/// are skipped with a warning.
+	pub fn open(path: &str) -> Result<History, DjError> {
+		let plays = read_plays(path)?;
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.map_err(|e| DjError::UnwritableHistory(path.to_string(), e.to_string()))?;
+		Ok(History {
+			path: path.to_string(),
+			file,
+			plays,
+		})
+	}
+
+	pub fn plays(&self) -> &[Play] {
+		&self.plays
+	}
+
+	/// Adds a play to the history, and writes it to the file straight away.
+	pub fn record(&mut self, play: Play) -> Result<(), DjError> {
+		let line = serde_json::to_string(&play).unwrap();
+		writeln!(self.file, "{}", line)
+			.map_err(|e| DjError::UnwritableHistory(self.path.to_string(), e.to_string()))?;
+		self.plays.push(play);
+		Ok(())
+	}
+}
+
+/// Reads the plays in a history file. A file that doesn't exist has no plays.
+pub fn read_plays(path: &str) -> Result<Vec<Play>, DjError> {
+	let text = match fs::read_to_string(path) {
+		Ok(text) => text,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(DjError::UnreadableFile(path.to_string(), e.to_string())),
+	};
+	Ok(parse_plays(path, &text))
+}
+
+fn parse_plays(path: &str, text: &str) -> Vec<Play> {
+	text.lines()
+		.enumerate()
+		.filter(|(_, line)| !line.trim().is_empty())
+		.filter_map(|(i, line)| match serde_json::from_str(line) {
+			Ok(play) => Some(play),
+			Err(e) => {
+				warn!(path, line = i + 1, "Skipping unreadable play in history: {}", e);
+				None
+			}
+		})
+		.collect()
+}
+
+/// Removes songs that were picked recently from `candidates`, so that a song doesn't come up again
+/// until at least half of the other candidates have played. `recent` is ordered oldest first.
+pub fn exclude_recent<'a>(candidates: &[&'a String], recent: &[String]) -> Vec<&'a String> {
+	let mut excluded = HashSet::new();
+	for song in recent.iter().rev() {
+		if excluded.len() >= candidates.len() / 2 {
+			break;
+		}
+		if candidates.contains(&song) {
+			excluded.insert(song);
+		}
+	}
+	candidates
+		.iter()
+		.filter(|song| !excluded.contains(*song))
+		.copied()
+		.collect()
+}
+
+/// How often each song and segment has been played.
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+	pub plays: usize,
+	pub played: Duration,
+	pub first_play: Option<String>,
+	pub songs: BTreeMap<String, SongStats>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SongStats {
+	pub plays: usize,
+	pub interrupted: usize,
+	pub wrapped_up: usize,
+	pub played: Duration,
+	pub last_played: String,
+	pub segments: BTreeMap<String, SegmentStats>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SegmentStats {
+	pub plays: usize,
+	/// Times the segment looped, over all of its plays.
+	pub repeats: u64,
+}
+
+impl Stats {
+	pub fn new(plays: &[Play]) -> Stats {
+		let mut stats = Stats {
+			first_play: plays.first().map(|play| play.started.to_string()),
+			..Stats::default()
+		};
+		for play in plays {
+			let played = Duration::from_secs_f64(play.played.max(0.0));
+			stats.plays += 1;
+			stats.played += played;
+			let song = stats.songs.entry(play.song.to_string()).or_default();
+			song.plays += 1;
+			song.played += played;
+			match play.end {
+				PlayEnd::Interrupted => song.interrupted += 1,
+				PlayEnd::WrappedUp => song.wrapped_up += 1,
+				PlayEnd::Finished | PlayEnd::Stopped => {}
+			}
+			song.last_played = play.started.to_string();
+			for segment in &play.plan {
+				let segment_stats = song.segments.entry(segment.id.to_string()).or_default();
+				segment_stats.plays += 1;
+				segment_stats.repeats += segment.repeat_count.unwrap_or(0) as u64;
+			}
+		}
+		stats
+	}
+}
+
+impl fmt::Display for Stats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let first_play = match &self.first_play {
+			Some(first_play) => first_play,
+			None => return writeln!(f, "Nothing has been played yet."),
+		};
+		writeln!(
+			f,
+			"{} plays since {}, {} of music.",
+			self.plays,
+			first_play,
+			format_duration(self.played)
+		)?;
+		let mut songs = self.songs.iter().collect::<Vec<_>>();
+		songs.sort_by(|a, b| b.1.plays.cmp(&a.1.plays).then(a.0.cmp(b.0)));
+		for (song_id, song) in songs {
+			writeln!(f)?;
+			write!(
+				f,
+				"{}: {} plays, {}, last played {}",
+				song_id,
+				song.plays,
+				format_duration(song.played),
+				song.last_played
+			)?;
+			if song.interrupted > 0 {
+				write!(f, ", {} interrupted", song.interrupted)?;
+			}
+			if song.wrapped_up > 0 {
+				write!(f, ", {} wrapped up", song.wrapped_up)?;
+			}
+			writeln!(f)?;
+			for (segment_id, segment) in &song.segments {
+				write!(f, "  {}: {} plays", segment_id, segment.plays)?;
+				if segment.repeats > 0 {
+					write!(
+						f,
+						", {:.1} repeats on average",
+						segment.repeats as f64 / segment.plays as f64
+					)?;
+				}
+				writeln!(f)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Formats a duration to the second, eg. `1h 2m 3s`.
+fn format_duration(duration: Duration) -> String {
+	humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string()
+}
+
+#[cfg(test)]
+mod test_history {
+	use super::*;
+
+	fn play(song: &str, loop_repeats: u32, played: f64, end: PlayEnd) -> Play {
+		Play {
+			started: "2026-01-01T00:00:00Z".to_string(),
+			song: song.to_string(),
+			plan: vec![
+				PlannedSegment {
+					id: "start".to_string(),
+					repeat_count: None,
+				},
+				PlannedSegment {
+					id: "loop".to_string(),
+					repeat_count: Some(loop_repeats),
+				},
+			],
+			played,
+			end,
+		}
+	}
+
+	#[test]
+	fn test_history_file() {
+		let path = std::env::temp_dir().join(format!("autodj-test-history-{}.jsonl", std::process::id()));
+		let path = path.to_str().unwrap();
+		let _ = fs::remove_file(path);
+		let mut history = History::open(path).unwrap();
+		assert!(history.plays().is_empty());
+		history.record(play("a", 5, 30.0, PlayEnd::Finished)).unwrap();
+		history.record(play("b", 6, 12.5, PlayEnd::Interrupted)).unwrap();
+		drop(history);
+
+		let history = History::open(path).unwrap();
+		assert_eq!(
+			history.plays(),
+			&[
+				play("a", 5, 30.0, PlayEnd::Finished),
+				play("b", 6, 12.5, PlayEnd::Interrupted)
+			]
+		);
+		fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn test_parse_plays_skips_bad_lines() {
+		let line = serde_json::to_string(&play("a", 5, 30.0, PlayEnd::Finished)).unwrap();
+		assert!(line.contains(r#""end":"finished""#));
+		let plays = parse_plays("history.jsonl", &format!("{}\nnot json\n\n{}\n", line, line));
+		assert_eq!(plays.len(), 2);
+	}
+
+	#[test]
+	fn test_exclude_recent() {
+		let songs = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+		let candidates = songs.iter().collect::<Vec<_>>();
+		let recent = ["b", "x", "a", "c"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+		assert_eq!(exclude_recent(&candidates, &recent), vec!["b", "d"]);
+		assert_eq!(exclude_recent(&candidates[..1], &recent), vec!["a"]);
+		assert_eq!(exclude_recent(&candidates, &[]).len(), 4);
+	}
+
+	#[test]
+	fn test_stats() {
+		let stats = Stats::new(&[
+			play("a", 5, 30.0, PlayEnd::Finished),
+			play("b", 6, 12.5, PlayEnd::Interrupted),
+			play("a", 7, 40.0, PlayEnd::Finished),
+		]);
+		assert_eq!(stats.plays, 3);
+		assert_eq!(stats.played, Duration::from_secs_f64(82.5));
+		assert_eq!(stats.songs["a"].plays, 2);
+		assert_eq!(stats.songs["b"].interrupted, 1);
+		assert_eq!(
+			stats.songs["a"].segments["loop"],
+			SegmentStats { plays: 2, repeats: 12 }
+		);
+		let report = stats.to_string();
+		assert!(report.starts_with("3 plays since 2026-01-01T00:00:00Z, 1m 22s of music.\n"));
+		assert!(report.contains("\na: 2 plays, 1m 10s, last played 2026-01-01T00:00:00Z\n  loop: 2 plays, 6.0 repeats on average\n  start: 2 plays\n"));
+		assert!(report.contains("\nb: 1 plays, 12s, last played 2026-01-01T00:00:00Z, 1 interrupted\n"));
+	}
+
+	#[test]
+	fn test_stats_wrap_ups() {
+		let stats = Stats::new(&[
+			play("a", 5, 30.0, PlayEnd::WrappedUp),
+			play("a", 7, 40.0, PlayEnd::Finished),
+		]);
+		assert_eq!(stats.songs["a"].wrapped_up, 1);
+		assert_eq!(stats.songs["a"].interrupted, 0);
+		assert!(stats
+			.to_string()
+			.contains("\na: 2 plays, 1m 10s, last played 2026-01-01T00:00:00Z, 1 wrapped up\n"));
+		let line = serde_json::to_string(&play("a", 5, 30.0, PlayEnd::WrappedUp)).unwrap();
+		assert!(line.ends_with(r#""end":"wrapped-up"}"#), "{}", line);
+	}
+}
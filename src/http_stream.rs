@@ -0,0 +1,406 @@
+use std::{
+	io::{self, BufRead, BufReader, Write},
+	net::{TcpListener, TcpStream},
+	num::{NonZeroU32, NonZeroU8},
+	sync::{
+		mpsc::{self, Receiver, SyncSender},
+		Arc, Mutex,
+	},
+	thread,
+};
+
+use tracing::{error, info};
+use vorbis_rs::VorbisEncoderBuilder;
+
+use crate::{errors::DjError, output::PcmWriter, resample::OutputFormat};
+
+/// The mount point that the stream is served on if none is given.
+pub const DEFAULT_MOUNT: &str = "/stream.ogg";
+/// Bytes of audio between ICY metadata blocks.
+const ICY_METAINT: usize = 16000;
+/// Pages that can be waiting for a client before it's considered too slow and dropped.
+const CLIENT_BACKLOG: usize = 256;
+
+/// The title sent to listeners as ICY metadata.
+#[derive(Debug, Default)]
+pub struct StreamTitle {
+	title: Mutex<String>,
+}
+
+impl StreamTitle {
+	pub fn new() -> Arc<StreamTitle> {
+		Arc::new(StreamTitle::default())
+	}
+
+	pub fn set(&self, title: &str) {
+		*self.title.lock().unwrap() = title.to_string();
+	}
+
+	pub fn get(&self) -> String {
+		self.title.lock().unwrap().clone()
+	}
+}
+
+/// Encodes everything played to Ogg/Vorbis, and serves it over HTTP to any number of listeners.
+pub struct HttpStream {
+	samples: SyncSender<Vec<i16>>,
+}
+
+impl HttpStream {
+	pub fn start(
+		addr: &str, mount: &str, format: OutputFormat, title: Arc<StreamTitle>,
+	) -> Result<HttpStream, DjError> {
+		let listener = TcpListener::bind(addr)
+			.map_err(|e| DjError::OutputError(format!("unable to listen on {}: {}", addr, e)))?;
+		let local_addr = listener.local_addr().map_err(|e| DjError::OutputError(e.to_string()))?;
+		let broadcast = Arc::new(Broadcast::default());
+
+		let served_mount = mount.to_string();
+		let clients = broadcast.clone();
+		thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				let mount = served_mount.clone();
+				let clients = clients.clone();
+				let title = title.clone();
+				thread::spawn(move || {
+					let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+					match serve_client(stream, &mount, &clients, &title) {
+						Ok(()) => info!(listener = %peer, "Listener disconnected"),
+						Err(e) => info!(listener = %peer, "Listener disconnected: {}", e),
+					}
+				});
+			}
+		});
+
+		let (samples, sample_rx) = mpsc::sync_channel(16);
+		thread::spawn(move || {
+			if let Err(e) = encode(sample_rx, format, broadcast) {
+				// Writing fails once the encoder is gone, which stops the player.
+				error!("{}", DjError::OutputError(e));
+			}
+		});
+		info!(url = %format!("http://{}{}", local_addr, mount), "Streaming");
+		Ok(HttpStream { samples })
+	}
+}
+
+impl PcmWriter for HttpStream {
+	fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+		self.samples
+			.send(samples.to_vec())
+			.map_err(|_| io::Error::other("the encoder stopped"))
+	}
+}
+
+/// Encodes interleaved samples until the sending side goes away.
+fn encode(samples: Receiver<Vec<i16>>, format: OutputFormat, broadcast: Arc<Broadcast>) -> Result<(), String> {
+	let channels = format.channels as usize;
+	let mut encoder = VorbisEncoderBuilder::new(
+		NonZeroU32::new(format.sample_rate).unwrap(),
+		NonZeroU8::new(format.channels as u8).ok_or("too many channels to encode")?,
+		PageSplitter::new(broadcast),
+	)
+	.and_then(|mut builder| builder.build())
+	.map_err(|e| e.to_string())?;
+	for chunk in samples {
+		let mut block = vec![Vec::with_capacity(chunk.len() / channels); channels];
+		for frame in chunk.chunks(channels) {
+			for (channel, sample) in block.iter_mut().zip(frame) {
+				channel.push(*sample as f32 / 32768.0);
+			}
+		}
+		encoder.encode_audio_block(&block).map_err(|e| e.to_string())?;
+	}
+	Ok(())
+}
+
+/// Hands out the encoded stream to listeners.
+#[derive(Debug, Default)]
+struct Broadcast {
+	/// The pages holding the stream's headers, which every listener needs before any audio.
+	headers: Mutex<Vec<u8>>,
+	clients: Mutex<Vec<SyncSender<Arc<[u8]>>>>,
+}
+
+impl Broadcast {
+	fn publish(&self, page: Vec<u8>, is_header: bool) {
+		let mut clients = self.clients.lock().unwrap();
+		if is_header {
+			self.headers.lock().unwrap().extend_from_slice(&page);
+		}
+		let page: Arc<[u8]> = page.into();
+		// Listeners that have gone away, or can't keep up, are dropped.
+		clients.retain(|client| client.try_send(page.clone()).is_ok());
+	}
+
+	fn subscribe(&self) -> (Vec<u8>, Receiver<Arc<[u8]>>) {
+		let (tx, rx) = mpsc::sync_channel(CLIENT_BACKLOG);
+		let mut clients = self.clients.lock().unwrap();
+		clients.push(tx);
+		(self.headers.lock().unwrap().clone(), rx)
+	}
+}
+
+/// Collects the encoder's output and publishes it one Ogg page at a time, so listeners always
+/// start at the beginning of a page.
+struct PageSplitter {
+	buffer: Vec<u8>,
+	broadcast: Arc<Broadcast>,
+}
+
+impl PageSplitter {
+	fn new(broadcast: Arc<Broadcast>) -> PageSplitter {
+		PageSplitter {
+			buffer: Vec::new(),
+			broadcast,
+		}
+	}
+}
+
+impl Write for PageSplitter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.buffer.extend_from_slice(buf);
+		while let Some(len) = ogg_page_len(&self.buffer)? {
+			let page = self.buffer.drain(..len).collect::<Vec<_>>();
+			// Header packets are the only ones without a granule position.
+			let granule_position = u64::from_le_bytes(std::convert::TryInto::try_into(&page[6..14]).unwrap());
+			self.broadcast.publish(page, granule_position == 0);
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// The length of the Ogg page at the start of `data`, if all of it is there.
+fn ogg_page_len(data: &[u8]) -> io::Result<Option<usize>> {
+	const HEADER_LEN: usize = 27;
+	if data.len() < HEADER_LEN {
+		return Ok(None);
+	}
+	if &data[..4] != b"OggS" {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"encoder wrote an invalid ogg page",
+		));
+	}
+	let segments = data[26] as usize;
+	if data.len() < HEADER_LEN + segments {
+		return Ok(None);
+	}
+	let body_len = data[HEADER_LEN..HEADER_LEN + segments]
+		.iter()
+		.map(|len| *len as usize)
+		.sum::<usize>();
+	let len = HEADER_LEN + segments + body_len;
+	Ok(if data.len() >= len { Some(len) } else { None })
+}
+
+/// Answers one HTTP request, streaming audio until the listener goes away.
+fn serve_client(mut stream: TcpStream, mount: &str, broadcast: &Broadcast, title: &StreamTitle) -> io::Result<()> {
+	let mut reader = BufReader::new(stream.try_clone()?);
+	let mut request_line = String::new();
+	reader.read_line(&mut request_line)?;
+	let mut wants_icy = false;
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+			break;
+		}
+		if let Some((name, value)) = line.split_once(':') {
+			if name.trim().eq_ignore_ascii_case("icy-metadata") && value.trim() == "1" {
+				wants_icy = true;
+			}
+		}
+	}
+
+	let mut parts = request_line.split_whitespace();
+	if parts.next() != Some("GET") || parts.next().map(|path| path.split('?').next().unwrap()) != Some(mount) {
+		stream.write_all(b"HTTP/1.0 404 Not Found\r\nContent-Type: text/plain\r\n\r\nnot found\n")?;
+		return Ok(());
+	}
+	info!(listener = %stream.peer_addr()?, "Listener connected");
+
+	let mut response = String::from(
+		"HTTP/1.0 200 OK\r\nContent-Type: audio/ogg\r\nCache-Control: no-cache, no-store\r\nicy-name: stream-autodj\r\n",
+	);
+	if wants_icy {
+		response.push_str(&format!("icy-metaint: {}\r\n", ICY_METAINT));
+	}
+	response.push_str("\r\n");
+	stream.write_all(response.as_bytes())?;
+
+	let (headers, pages) = broadcast.subscribe();
+	let mut icy = IcyInterleaver::new(ICY_METAINT);
+	let mut out = Vec::new();
+	for data in std::iter::once(headers.into()).chain(pages) {
+		if wants_icy {
+			out.clear();
+			icy.interleave(&data, &title.get(), &mut out);
+			stream.write_all(&out)?;
+		}
+		else {
+			stream.write_all(&data)?;
+		}
+	}
+	Ok(())
+}
+
+/// Inserts ICY metadata blocks into a stream every `metaint` bytes.
+struct IcyInterleaver {
+	metaint: usize,
+	until_metadata: usize,
+	last_title: Option<String>,
+}
+
+impl IcyInterleaver {
+	fn new(metaint: usize) -> IcyInterleaver {
+		IcyInterleaver {
+			metaint,
+			until_metadata: metaint,
+			last_title: None,
+		}
+	}
+
+	fn interleave(&mut self, mut data: &[u8], title: &str, out: &mut Vec<u8>) {
+		while !data.is_empty() {
+			let len = self.until_metadata.min(data.len());
+			out.extend_from_slice(&data[..len]);
+			data = &data[len..];
+			self.until_metadata -= len;
+			if self.until_metadata == 0 {
+				self.metadata_block(title, out);
+				self.until_metadata = self.metaint;
+			}
+		}
+	}
+
+	/// Writes a metadata block, which is empty unless the title has changed since the last one.
+	fn metadata_block(&mut self, title: &str, out: &mut Vec<u8>) {
+		if self.last_title.as_deref() == Some(title) {
+			out.push(0);
+			return;
+		}
+		self.last_title = Some(title.to_string());
+		// Clients read the title up to the first quote and semicolon, and don't unescape anything.
+		let mut title = title.replace(['\'', ';'], "");
+		// The block's length is given in units of 16 bytes, in a single byte.
+		let max_len = 255 * 16 - "StreamTitle='';".len();
+		if title.len() > max_len {
+			let end = (0..=max_len).rev().find(|i| title.is_char_boundary(*i)).unwrap();
+			title.truncate(end);
+		}
+		let mut text = format!("StreamTitle='{}';", title).into_bytes();
+		let blocks = text.len().div_ceil(16);
+		text.resize(blocks * 16, 0);
+		out.push(blocks as u8);
+		out.extend(text);
+	}
+}
+
+#[cfg(test)]
+mod test_http_stream {
+	use super::*;
+	use std::io::Read;
+
+	#[test]
+	fn test_icy_interleave() {
+		let mut icy = IcyInterleaver::new(4);
+		let mut out = Vec::new();
+		icy.interleave(b"abcdef", "Song", &mut out);
+		let mut expected = b"abcd".to_vec();
+		expected.push(2);
+		expected.extend(b"StreamTitle='Song';");
+		expected.resize(4 + 1 + 32, 0);
+		expected.extend(b"ef");
+		assert_eq!(out, expected);
+
+		out.clear();
+		icy.interleave(b"ghij", "Song", &mut out);
+		assert_eq!(out, b"gh\0ij");
+
+		out.clear();
+		IcyInterleaver::new(4).interleave(b"abcd", "Rock 'n' Roll; Live", &mut out);
+		assert_eq!(&out[5..], b"StreamTitle='Rock n Roll Live';\0");
+
+		out.clear();
+		IcyInterleaver::new(4).interleave(b"abcd", &"é".repeat(3000), &mut out);
+		assert_eq!(out[4], 255);
+		assert_eq!(out.len(), 5 + 255 * 16);
+		assert!(String::from_utf8(out[5..].to_vec())
+			.unwrap()
+			.trim_end_matches('\0')
+			.ends_with("é';"));
+	}
+
+	#[test]
+	fn test_ogg_page_len() {
+		let mut page = b"OggS".to_vec();
+		page.resize(26, 0);
+		page.extend([2, 3, 4]);
+		assert_eq!(ogg_page_len(&page).unwrap(), None);
+		page.extend([0; 7]);
+		assert_eq!(ogg_page_len(&page).unwrap(), Some(36));
+		assert!(ogg_page_len(&[0; 30]).is_err());
+	}
+
+	#[test]
+	fn test_serve_stream() {
+		let title = StreamTitle::new();
+		title.set("Test Song");
+		let format = OutputFormat {
+			channels: 2,
+			sample_rate: 48000,
+		};
+		// Finds a free port to serve on.
+		let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let mut stream = HttpStream::start(&addr.to_string(), DEFAULT_MOUNT, format, title).unwrap();
+
+		let mut client = TcpStream::connect(addr).unwrap();
+		client
+			.set_read_timeout(Some(std::time::Duration::from_secs(30)))
+			.unwrap();
+		client
+			.write_all(b"GET /stream.ogg HTTP/1.1\r\nIcy-MetaData: 1\r\n\r\n")
+			.unwrap();
+		// Wait for the listener to be subscribed before audio is sent.
+		let mut response = Vec::new();
+		let mut buf = [0; 4096];
+		while !response.windows(4).any(|w| w == b"OggS") {
+			let len = client.read(&mut buf).unwrap();
+			assert!(len > 0);
+			response.extend_from_slice(&buf[..len]);
+		}
+		// Noise, so that the encoder has plenty to say and metadata is sent soon.
+		thread::spawn(move || {
+			let mut seed = 1u32;
+			for _ in 0..100 {
+				let samples = (0..4800 * 2)
+					.map(|_| {
+						seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+						(seed >> 16) as i16
+					})
+					.collect::<Vec<_>>();
+				stream.write(&samples).unwrap();
+			}
+		});
+		while !response.windows(11).any(|w| w == b"StreamTitle") {
+			let len = client.read(&mut buf).unwrap();
+			assert!(len > 0);
+			response.extend_from_slice(&buf[..len]);
+		}
+		let response = String::from_utf8_lossy(&response);
+		assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+		assert!(response.contains("Content-Type: audio/ogg\r\n"));
+		assert!(response.contains("icy-metaint: 16000\r\n"));
+		assert!(response.contains("StreamTitle='Test Song';"));
+
+		let mut client = TcpStream::connect(addr).unwrap();
+		client.write_all(b"GET /other HTTP/1.1\r\n\r\n").unwrap();
+		let mut response = String::new();
+		client.read_to_string(&mut response).unwrap();
+		assert!(response.starts_with("HTTP/1.0 404 Not Found"));
+	}
+}
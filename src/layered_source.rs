@@ -0,0 +1,205 @@
+use std::{
+	sync::{
+		atomic::{AtomicU32, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use rodio::{Sample, Source};
+
+/// How long it takes for a layer to fully fade in or out after the intensity changes.
+const FADE_DURATION: Duration = Duration::from_secs(2);
+
+/// How much of a layered segment should be audible, from 0 (only the base) to 1 (every layer).
+#[derive(Debug)]
+pub struct Intensity(AtomicU32);
+
+impl Intensity {
+	pub fn new(value: f32) -> Arc<Intensity> {
+		Arc::new(Intensity(AtomicU32::new(value.to_bits())))
+	}
+
+	pub fn get(&self) -> f32 {
+		f32::from_bits(self.0.load(Ordering::Relaxed))
+	}
+
+	pub fn set(&self, value: f32) {
+		self.0.store(value.to_bits(), Ordering::Relaxed)
+	}
+}
+
+/// How loud the `index`th of `count` optional layers should be at the given intensity.
+///
+/// Layers fade in one after another as the intensity rises, so that at an intensity of 0.5
+/// the first half of the layers are audible.
+pub fn layer_gain(intensity: f32, index: usize, count: usize) -> f32 {
+	(intensity * count as f32 - index as f32).clamp(0.0, 1.0)
+}
+
+/// Internal function that builds a `LayeredSource` object.
+///
+/// All sources must have the same channel count and sample rate.
+pub fn mix_layers<I>(always_on: Vec<I>, layers: Vec<I>, intensity: Arc<Intensity>) -> LayeredSource<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	let first = always_on.first().or_else(|| layers.first()).expect("no layers to mix");
+	let channels = first.channels();
+	let sample_rate = first.sample_rate();
+	let current = intensity.get();
+	let count = layers.len();
+	LayeredSource {
+		always_on,
+		layers: layers
+			.into_iter()
+			.enumerate()
+			.map(|(i, source)| (source, layer_gain(current, i, count)))
+			.collect(),
+		intensity,
+		channels,
+		sample_rate,
+		fade_step: 1.0 / (FADE_DURATION.as_secs_f32() * sample_rate as f32),
+		channel_index: 0,
+	}
+}
+
+/// A source that plays several synchronized layers at once, fading optional layers in and
+/// out depending on the current intensity.
+pub struct LayeredSource<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	always_on: Vec<I>,
+	/// Optional layers, and their current gain.
+	layers: Vec<(I, f32)>,
+	intensity: Arc<Intensity>,
+	channels: u16,
+	sample_rate: u32,
+	/// How much a layer's gain can change per frame.
+	fade_step: f32,
+	channel_index: u16,
+}
+
+impl<I> Iterator for LayeredSource<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	type Item = I::Item;
+
+	#[inline]
+	fn next(&mut self) -> Option<I::Item> {
+		if self.channel_index == 0 {
+			// Only change gains between frames so all channels stay in sync.
+			let intensity = self.intensity.get();
+			let count = self.layers.len();
+			for (i, (_, gain)) in self.layers.iter_mut().enumerate() {
+				let target = layer_gain(intensity, i, count);
+				if *gain < target {
+					*gain = (*gain + self.fade_step).min(target);
+				}
+				else if *gain > target {
+					*gain = (*gain - self.fade_step).max(target);
+				}
+			}
+		}
+		self.channel_index = (self.channel_index + 1) % self.channels;
+
+		let mut playing = false;
+		let mut mixed = I::Item::zero_value();
+		for source in self.always_on.iter_mut() {
+			if let Some(value) = source.next() {
+				playing = true;
+				mixed = mixed.saturating_add(value);
+			}
+		}
+		for (source, gain) in self.layers.iter_mut() {
+			if let Some(value) = source.next() {
+				playing = true;
+				mixed = mixed.saturating_add(value.amplify(*gain));
+			}
+		}
+		if playing {
+			Some(mixed)
+		}
+		else {
+			None
+		}
+	}
+}
+
+impl<I> Source for LayeredSource<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	#[inline]
+	fn current_frame_len(&self) -> Option<usize> {
+		None
+	}
+
+	#[inline]
+	fn channels(&self) -> u16 {
+		self.channels
+	}
+
+	#[inline]
+	fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+
+	#[inline]
+	fn total_duration(&self) -> Option<Duration> {
+		self.always_on
+			.iter()
+			.chain(self.layers.iter().map(|(source, _)| source))
+			.filter_map(|source| source.total_duration())
+			.max()
+	}
+}
+
+#[cfg(test)]
+mod test_layered_source {
+	use super::*;
+	use rodio::buffer::SamplesBuffer;
+
+	#[test]
+	fn test_layer_gain() {
+		assert_eq!(layer_gain(0.0, 0, 2), 0.0);
+		assert_eq!(layer_gain(0.5, 0, 2), 1.0);
+		assert_eq!(layer_gain(0.5, 1, 2), 0.0);
+		assert_eq!(layer_gain(0.75, 1, 2), 0.5);
+		assert_eq!(layer_gain(1.0, 1, 2), 1.0);
+	}
+
+	#[test]
+	fn test_mix_layers() {
+		let intensity = Intensity::new(0.0);
+		let mut mixed = mix_layers(
+			vec![SamplesBuffer::new(1, 10, vec![0.25f32; 100])],
+			vec![SamplesBuffer::new(1, 10, vec![0.5f32; 100])],
+			intensity.clone(),
+		);
+		assert_eq!(mixed.next(), Some(0.25));
+
+		// fading in takes FADE_DURATION
+		intensity.set(1.0);
+		let faded = mixed.by_ref().take(20).collect::<Vec<_>>();
+		assert!(faded.windows(2).all(|w| w[0] < w[1]));
+		assert!((faded.last().unwrap() - 0.75).abs() < 1e-6);
+		assert!((mixed.next().unwrap() - 0.75).abs() < 1e-6);
+	}
+
+	#[test]
+	fn test_mix_layers_ends_with_longest_layer() {
+		let mixed = mix_layers(
+			vec![SamplesBuffer::new(2, 10, vec![0.0f32; 10])],
+			vec![SamplesBuffer::new(2, 10, vec![0.0f32; 20])],
+			Intensity::new(1.0),
+		);
+		assert_eq!(mixed.count(), 20);
+	}
+}
@@ -0,0 +1,54 @@
+use std::{
+	fs::OpenOptions,
+	io::{self, IsTerminal},
+	sync::Mutex,
+};
+
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*};
+
+use crate::errors::DjError;
+
+/// Sets up logging. Messages at `level` and above are printed, and also written to `json_file` as
+/// one JSON object per line, so that they can be read by other programs.
+pub fn init(level: &str, json_file: Option<&str>) -> Result<(), DjError> {
+	let level = level
+		.parse::<LevelFilter>()
+		.map_err(|_| DjError::LogLevelInvalidValue)?;
+	let console = fmt::layer()
+		.with_target(false)
+		.without_time()
+		.with_ansi(io::stdout().is_terminal());
+	let json = match json_file {
+		Some(path) => {
+			let file = OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(path)
+				.map_err(|e| DjError::UnwritableLogFile(path.to_string(), e.to_string()))?;
+			Some(
+				fmt::layer()
+					.json()
+					.flatten_event(true)
+					.with_current_span(false)
+					.with_writer(Mutex::new(file)),
+			)
+		}
+		None => None,
+	};
+	tracing_subscriber::registry()
+		.with(level)
+		.with(console)
+		.with(json)
+		.init();
+	Ok(())
+}
+
+#[cfg(test)]
+mod test_logging {
+	use super::*;
+
+	#[test]
+	fn test_invalid_log_level() {
+		assert_eq!(init("loud", None), Err(DjError::LogLevelInvalidValue));
+	}
+}
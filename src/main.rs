@@ -1,174 +1,338 @@
+mod config;
+mod control;
+mod device_output;
 mod errors;
+mod graph_export;
+mod history;
+mod http_stream;
+mod layered_source;
+mod logging;
 mod macros;
-mod repeating_source;
-
-use clap::{App, Arg, ArgMatches};
+mod metadata;
+mod output;
+mod plan;
+mod planner;
+mod player;
+mod playlist;
+mod position_source;
+mod prefetch;
+mod resample;
+mod segment_cache;
+mod selection;
+mod shutdown;
+mod song_source;
+mod stinger;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use config::Config;
+use device_output::list_devices;
 use errors::DjError;
+use graph_export::GraphFormat;
+use history::{History, PlannedSegment, Stats};
 use lazy_static::lazy_static;
+use metadata::{SongMetadata, TransitionMode};
+use output::Output;
+use plan::{Plan, PlanMode, PlanStats, SegmentDurations};
+use planner::SegmentGraph;
+use player::{Player, QueuedSegment};
+use playlist::Playlists;
+use prefetch::{Prefetcher, PrepareOptions, QueuedSource};
 use proptest::{collection::hash_map, prelude::*};
-use rand::{seq::SliceRandom, Rng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use regex::Regex;
-use rodio::{decoder::Decoder, source::Zero, Sink, Source};
+use resample::OutputFormat;
+use rodio::{decoder::Decoder, Source};
+use segment_cache::{CachedSource, SegmentCache, SegmentData, MIB};
+use selection::Selection;
+use song_source::{ArchiveFormat, LooseFiles, SongSource};
 use std::{
-	collections::{HashMap, HashSet, VecDeque},
+	collections::{HashMap, HashSet},
+	convert::TryInto,
 	error::Error,
 	fs,
-	fs::File,
-	io::{BufReader, Cursor, Read},
-	path::Path,
+	io::{BufReader, Cursor},
+	path::{Path, PathBuf},
+	sync::Arc,
 	time::Duration,
 };
-use zip::ZipArchive;
+use stinger::StingerLayer;
+use tracing::{debug, error, info, warn};
 
 lazy_static! {
 	static ref REGEX_IS_LOOP: Regex = Regex::new(r"loop(\d+)?$").unwrap();
 	static ref REGEX_IS_DEDICATED_TRANSITION: Regex = Regex::new(r"loop(\d+)-to-(\d+)").unwrap();
+	static ref REGEX_IS_START: Regex = Regex::new(r"^start(\d+)?(-to-(\d+))?$").unwrap();
+	/// Any segment that isn't a bridge, by its whole id.
+	static ref REGEX_IS_REGULAR_SEGMENT: Regex =
+		Regex::new(r"^(start\d*(-to-\d+)?|loop\d*|loop\d+-to-\d+|(loop\d*-)?end|stinger-.+)$").unwrap();
 }
 
+/// Segments named `from-SONGNAME` are bridges, played instead of a start when the song follows
+/// `SONGNAME`.
+const BRIDGE_PREFIX: &str = "from-";
+
 // Do NOT use mp3.
 
+/// A song file that has been read into memory and is ready to be played.
+pub type SegmentDecoder = Decoder<BufReader<Cursor<SegmentData>>>;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SongSegment {
 	id: String,
+	/// Format of the segment's main file. Empty if the segment only consists of layers.
 	format: String,
 	allowed_transitions: HashSet<String>,
+	/// Additional files that are played in sync with the main file, faded in and out by the
+	/// player's intensity.
+	layers: Vec<SegmentLayer>,
 }
 
+/// One stem of a layered segment, eg. `SONGNAME_loop0.drums.ogg`.
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentLayer {
+	name: String,
+	format: String,
+}
+
+#[derive(Debug, Clone)]
 pub struct Song {
 	id: String,
 	segments: HashMap<String, SongSegment>,
+	/// One-shot segments that can be played over this song, by stinger name.
+	stingers: HashMap<String, SongSegment>,
+	metadata: SongMetadata,
 	has_end: bool,
 	has_global_ending: bool,
 	has_multiple_loops: bool,
 	has_dedicated_transitions: bool,
-	is_archive: bool,
+	/// Where the song's files are read from.
+	source: Arc<dyn SongSource>,
+}
+
+// Written out by hand, because deriving it doesn't work for trait objects.
+impl PartialEq for Song {
+	fn eq(&self, other: &Song) -> bool {
+		self.id == other.id
+			&& self.segments == other.segments
+			&& self.stingers == other.stingers
+			&& self.metadata == other.metadata
+			&& self.has_end == other.has_end
+			&& self.has_global_ending == other.has_global_ending
+			&& self.has_multiple_loops == other.has_multiple_loops
+			&& self.has_dedicated_transitions == other.has_dedicated_transitions
+			&& *self.source == *other.source
+	}
 }
 
 impl Song {
-	/// Reads the song segment from disk for playback.
-	fn read_segment(
-		&self, segment: &SongSegment, songs_dir: &str,
-	) -> Result<Decoder<BufReader<Cursor<Vec<u8>>>>, DjError> {
-		let mut data = Vec::new();
-		let file_name: String;
-		if self.is_archive {
-			file_name = format!("{}/{}.zip", songs_dir, self.id);
-			let f = File::open(&file_name).unwrap();
-			let mut arch = ZipArchive::new(f).unwrap();
-			arch.by_name(&format!("{}.{}", segment.id, segment.format))
-				.unwrap()
-				.read_to_end(&mut data)
-				.unwrap();
+	fn new(id: String, source: Arc<dyn SongSource>) -> Song {
+		Song {
+			id,
+			segments: HashMap::new(),
+			stingers: HashMap::new(),
+			metadata: SongMetadata::default(),
+			has_end: false,
+			has_global_ending: false,
+			has_multiple_loops: false,
+			has_dedicated_transitions: false,
+			source,
 		}
-		else {
-			file_name = format!("{}/{}_{}.{}", songs_dir, self.id, segment.id, segment.format);
-			File::open(&file_name).unwrap().read_to_end(&mut data).unwrap();
-		};
-		Decoder::new(BufReader::new(Cursor::new(data))).map_err(|_| DjError::UnrecognizedSongFormat(file_name))
 	}
 
-	/// Makes a randomized plan for playing the song.
-	fn make_plan<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<SongSegment> {
-		let mut work_queue = VecDeque::new();
-
-		work_queue.push_back((
-			vec![self.segments["start"].clone()],
-			self.segments[&self.segments["start"]
-				.allowed_transitions
-				.clone()
-				.into_iter()
-				.collect::<Vec<_>>()[0]]
-				.clone(),
-		));
-
-		while let Some((mut plan, next_seg)) = work_queue.pop_front() {
-			assert!(plan.len() <= 100, "plan too long");
-
-			plan.push(next_seg.clone());
+	/// The bridge that leads into this song from another one, if the song has one.
+	fn bridge_from(&self, song_id: &str) -> Option<&SongSegment> {
+		self.segments.get(&format!("{}{}", BRIDGE_PREFIX, song_id))
+	}
 
-			if next_seg.is_end() {
-				return plan;
+	/// Loads a song's segments and metadata from its source.
+	fn load(id: String, source: Arc<dyn SongSource>) -> Result<Song, DjError> {
+		let mut song = Song::new(id, source.clone());
+		for file in source.list_files()? {
+			if file == song_source::METADATA_FILE {
+				let text = String::from_utf8(source.read_file(&file)?)
+					.map_err(|e| DjError::InvalidSongMetadata(song.id.to_string(), e.to_string()))?;
+				song.metadata = SongMetadata::parse(&song.id, &text)?;
+				continue;
+			}
+			match detect_file_type(&file) {
+				Ok(FileType::SegmentFormat) => song.add_segment(parse_segment(&file)?)?,
+				_ => warn!(
+					song = %song.id,
+					file = %file,
+					path = %source.path().display(),
+					"Not a song segment, dropping"
+				),
 			}
+		}
+		Ok(song)
+	}
 
-			if plan.len() < 6 || next_seg.is_dedicated_transition() {
-				// Randomly pick the next segment.
-				let mut transitions = next_seg
-					.allowed_transitions
-					.clone()
-					.into_iter()
-					.filter(|s| !self.segments[s].is_end()) // don't end the song too early
-					.collect::<Vec<String>>();
-				if transitions.is_empty() {
-					transitions = next_seg
-						.allowed_transitions
-						.clone()
-						.into_iter()
-						.filter(|s| self.segments[s].is_end()) // but, if there aren't any more valid transitions, we need to end the song
-						.collect::<Vec<String>>();
+	/// Adds a segment to the song, keeping track of what kinds of segments the song has.
+	fn add_segment(&mut self, segment: SongSegment) -> Result<(), DjError> {
+		if let Some(name) = segment.stinger_name() {
+			if self.stingers.contains_key(name) {
+				return Err(DjError::MultipleSegmentsWithSameId(self.id.to_string(), segment.id));
+			}
+			self.stingers.insert(name.to_string(), segment);
+			return Ok(());
+		}
+		if !self.has_end && segment.is_end() {
+			self.has_end = true;
+			self.has_global_ending = segment.id == "end";
+		}
+		if segment.id != "loop" && segment.is_loop() {
+			self.has_multiple_loops = true;
+		}
+		if segment.is_dedicated_transition() {
+			self.has_dedicated_transitions = true;
+		}
+		match self.segments.get_mut(&segment.id) {
+			Some(existing) => {
+				// Layers of the same segment are separate files, so they get merged together.
+				if !existing.format.is_empty() && !segment.format.is_empty() {
+					// Having multiple files with the same ID is ambiguous.
+					return Err(DjError::MultipleSegmentsWithSameId(self.id.to_string(), segment.id));
 				}
-				if self.has_global_ending {
-					// only add one item to the work queue at a time
-					match transitions.choose(rng) {
-						Some(next) => {
-							work_queue.push_back((plan, self.segments[next].clone()));
-						}
-						None => {
-							return plan;
-						}
+				for layer in &segment.layers {
+					if existing.layers.iter().any(|l| l.name == layer.name) {
+						return Err(DjError::MultipleSegmentsWithSameId(
+							self.id.to_string(),
+							format!("{}.{}", segment.id, layer.name),
+						));
 					}
 				}
-				else {
-					if !self.has_end && transitions.is_empty() {
-						return plan;
-					}
-					// shuffle is technically not required, but helps to plan more variations in general.
-					transitions.shuffle(rng);
-					for seg in transitions {
-						work_queue.push_back((plan.clone(), self.segments[&seg].clone()));
-					}
+				if existing.format.is_empty() {
+					existing.format = segment.format;
 				}
+				existing.layers.extend(segment.layers);
 			}
-			else if self.has_end {
-				// The plan is getting too long, but we need a way to end it on an end segment.
-				let available_ends = next_seg
-					.allowed_transitions
-					.clone()
-					.into_iter()
-					.filter(|s| self.segments[s].is_end())
-					.collect::<Vec<String>>();
-				// early exit if there is an end segment we can transition to
-				if let Some(end_seg) = available_ends.get(0) {
-					plan.push(self.segments[end_seg].clone());
-					return plan;
-				}
-				else {
-					// we need to search all possible plans for an end.
-					for seg_id in next_seg.allowed_transitions {
-						work_queue.push_back((plan.clone(), self.segments[&seg_id].clone()));
-					}
-				}
+			None => {
+				self.segments.insert(segment.id.to_string(), segment);
 			}
-			else {
-				return plan;
+		}
+		Ok(())
+	}
+
+	/// Reads the song segment from disk for playback.
+	fn read_segment(&self, segment: &SongSegment) -> Result<SegmentDecoder, DjError> {
+		self.read_file(&format!("{}.{}", segment.id, segment.format))
+	}
+
+	/// Reads all the files of a layered segment from disk for playback.
+	///
+	/// Returns the files that should always be audible, and the optional layers in the order
+	/// they should fade in as the intensity rises.
+	fn read_layers(&self, segment: &SongSegment) -> Result<(Vec<SegmentDecoder>, Vec<SegmentDecoder>), DjError> {
+		let mut layers = self
+			.layer_files(segment)
+			.iter()
+			.map(|file| self.read_file(file))
+			.collect::<Result<Vec<_>, _>>()?;
+		let always_on = if segment.format.is_empty() {
+			// Without a main file, the first layer takes its place.
+			vec![layers.remove(0)]
+		}
+		else {
+			vec![self.read_segment(segment)?]
+		};
+		Ok((always_on, layers))
+	}
+
+	/// The files of a layered segment's layers, in the order they should fade in.
+	fn layer_files(&self, segment: &SongSegment) -> Vec<String> {
+		let mut layers = segment.layers.iter().collect::<Vec<_>>();
+		layers.sort_by_key(|layer| {
+			let position = self.metadata.layer_order.iter().position(|name| name == &layer.name);
+			(position.unwrap_or(usize::MAX), layer.name.to_string())
+		});
+		layers
+			.into_iter()
+			.map(|layer| format!("{}.{}.{}", segment.id, layer.name, layer.format))
+			.collect()
+	}
+
+	/// How long one play of a segment lasts. OGG files say so in their last page, but finding out
+	/// for other formats can mean decoding the whole file.
+	fn segment_duration(&self, segment: &SongSegment) -> Result<Duration, DjError> {
+		let file = if segment.format.is_empty() {
+			self.layer_files(segment).remove(0)
+		}
+		else {
+			format!("{}.{}", segment.id, segment.format)
+		};
+		if file.ends_with(".ogg") {
+			if let Some(duration) = ogg_duration(&self.source.read_shared(&file)?) {
+				return Ok(duration);
 			}
 		}
+		let decoder = self.read_file(&file)?;
+		if let Some(duration) = decoder.total_duration() {
+			return Ok(duration);
+		}
+		let samples_per_second = decoder.channels() as f64 * decoder.sample_rate() as f64;
+		Ok(Duration::from_secs_f64(decoder.count() as f64 / samples_per_second))
+	}
 
-		panic!("Failed to make plan for song: {}", self.id);
+	/// Reads one of the song's files from its source, eg. `loop0.ogg`.
+	fn read_file(&self, file: &str) -> Result<SegmentDecoder, DjError> {
+		let data = self.source.read_shared(file)?;
+		Decoder::new(BufReader::new(Cursor::new(data)))
+			.map_err(|_| DjError::UnrecognizedSongFormat(format!("{}/{}", self.source.path().display(), file)))
 	}
 }
 
+/// How long an OGG Vorbis file lasts, from the sample rate in its first page and the position of the
+/// last sample in its last page, or `None` if they can't be found.
+fn ogg_duration(data: &[u8]) -> Option<Duration> {
+	// Every page begins with a 27 byte header, then a table of its segment sizes.
+	let identification = data.get(27 + *data.get(26)? as usize..)?;
+	if !identification.starts_with(b"\x01vorbis") {
+		return None;
+	}
+	let sample_rate = u32::from_le_bytes(identification.get(12..16)?.try_into().ok()?);
+	if sample_rate == 0 {
+		return None;
+	}
+	// Pages that no packet finishes on have a granule position of -1.
+	let granule_position = (0..data.len().saturating_sub(27))
+		.rev()
+		.filter(|i| data[*i..].starts_with(b"OggS"))
+		.map(|i| i64::from_le_bytes(data[i + 6..i + 14].try_into().unwrap()))
+		.find(|position| *position >= 0)?;
+	Some(Duration::from_secs_f64(granule_position as f64 / sample_rate as f64))
+}
+
 impl SongSegment {
 	fn is_loop(&self) -> bool {
-		REGEX_IS_LOOP.is_match(&self.id) && !self.is_dedicated_transition()
+		REGEX_IS_LOOP.is_match(&self.id) && !self.is_dedicated_transition() && !self.is_bridge()
 	}
 
 	fn is_dedicated_transition(&self) -> bool {
-		REGEX_IS_DEDICATED_TRANSITION.is_match(&self.id)
+		REGEX_IS_DEDICATED_TRANSITION.is_match(&self.id) && !self.is_bridge()
+	}
+
+	/// Whether the song can begin with this segment, eg. `start`, `start1` or `start-to-2`.
+	fn is_start(&self) -> bool {
+		REGEX_IS_START.is_match(&self.id)
 	}
 
 	fn is_end(&self) -> bool {
-		(&self.id).ends_with("end")
+		self.id.ends_with("end") && !self.is_bridge()
+	}
+
+	/// Whether the song can begin with this segment when it follows a certain song.
+	fn is_bridge(&self) -> bool {
+		self.bridge_from().is_some()
+	}
+
+	/// The song that this segment bridges over from, if it is a bridge.
+	fn bridge_from(&self) -> Option<&str> {
+		self.id.strip_prefix(BRIDGE_PREFIX)
+	}
+
+	/// The name of the stinger, if this segment is a one-shot stinger rather than part of the song.
+	fn stinger_name(&self) -> Option<&str> {
+		self.id.strip_prefix("stinger-")
 	}
 }
 
@@ -182,6 +346,7 @@ mod test_song_segments {
 			id: "loop".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_loop());
 
@@ -189,6 +354,7 @@ mod test_song_segments {
 			id: "loop0".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_loop());
 
@@ -196,6 +362,7 @@ mod test_song_segments {
 			id: "loop1".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_loop());
 
@@ -203,6 +370,7 @@ mod test_song_segments {
 			id: "loop0-to-1".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_loop());
 
@@ -210,6 +378,7 @@ mod test_song_segments {
 			id: "start".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_loop());
 
@@ -217,6 +386,7 @@ mod test_song_segments {
 			id: "end".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_loop());
 	}
@@ -227,6 +397,7 @@ mod test_song_segments {
 			id: "loop".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_dedicated_transition());
 
@@ -234,6 +405,7 @@ mod test_song_segments {
 			id: "loop0".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_dedicated_transition());
 
@@ -241,16 +413,51 @@ mod test_song_segments {
 			id: "loop0-to-1".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_dedicated_transition());
 	}
 
+	#[test]
+	fn test_is_start() {
+		let segment = |id: &str| SongSegment {
+			id: id.to_string(),
+			format: "wav".to_string(),
+			allowed_transitions: set!(),
+			layers: vec![],
+		};
+		assert!(segment("start").is_start());
+		assert!(segment("start1").is_start());
+		assert!(segment("start-to-2").is_start());
+		assert!(segment("start0-to-2").is_start());
+		assert!(!segment("loop0").is_start());
+		assert!(!segment("restart").is_start());
+		assert!(!segment("start-to-2").is_loop());
+	}
+
+	#[test]
+	fn test_is_bridge() {
+		let segment = |id: &str| SongSegment {
+			id: id.to_string(),
+			format: "wav".to_string(),
+			allowed_transitions: set!(),
+			layers: vec![],
+		};
+		assert!(segment("from-other").is_bridge());
+		assert_eq!(segment("from-other_song").bridge_from(), Some("other_song"));
+		assert!(!segment("from-loop").is_loop());
+		assert!(!segment("from-x-to-1").is_dedicated_transition());
+		assert!(!segment("from-x-end").is_end());
+		assert!(!segment("loop0").is_bridge());
+	}
+
 	#[test]
 	fn test_is_end() {
 		assert!(SongSegment {
 			id: "end".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_end());
 
@@ -258,6 +465,7 @@ mod test_song_segments {
 			id: "loop0-end".to_string(),
 			format: "wav".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 		.is_end());
 	}
@@ -265,14 +473,18 @@ mod test_song_segments {
 
 pub enum FileType {
 	SegmentFormat,
-	SongArchiveFormat,
+	SongArchiveFormat(ArchiveFormat),
+	SongMetadataFormat,
 }
 
 pub fn detect_file_type(file_name: &str) -> Result<FileType, DjError> {
-	let extension = file_name.split('.').last().unwrap();
+	let extension = file_name.split('.').next_back().unwrap();
 	match extension {
 		"wav" | "ogg" | "mp3" | "flac" => Ok(FileType::SegmentFormat),
-		"zip" => Ok(FileType::SongArchiveFormat),
+		"zip" => Ok(FileType::SongArchiveFormat(ArchiveFormat::Zip)),
+		"tar" => Ok(FileType::SongArchiveFormat(ArchiveFormat::Tar)),
+		"zst" if file_name.ends_with(".tar.zst") => Ok(FileType::SongArchiveFormat(ArchiveFormat::TarZst)),
+		"toml" => Ok(FileType::SongMetadataFormat),
 		_ => Err(DjError::UnrecognizedSongFormat(file_name.to_string())),
 	}
 }
@@ -283,117 +495,109 @@ pub fn detect_file_type(file_name: &str) -> Result<FileType, DjError> {
 /// assert!(get_song_name("ram_ranch_start.ogg"), Ok("ram_ranch"))
 /// ```
 pub fn get_song_name(file_name: &str) -> Result<String, DjError> {
-	let segments = file_name.split('_').collect::<Vec<_>>();
-	let name = segments[..(segments.len()) - 1].join("_");
-	if name == "" {
+	let name = match segment_start(file_name) {
+		0 => "",
+		start => &file_name[..start - 1],
+	};
+	if name.is_empty() {
 		return Err(DjError::InvalidFileName(file_name.to_string()));
 	}
-	Ok(name)
+	Ok(name.to_string())
+}
+
+/// Where the segment's id begins in a file name, after the song's name. The song a bridge comes
+/// from can have underscores in its name, so a bridge begins at the last `_from-`, unless what
+/// follows the last underscore is a regular segment of a song whose own name has `_from-` in it.
+fn segment_start(file_name: &str) -> usize {
+	let last = file_name.rfind('_').map_or(0, |i| i + 1);
+	let last_id = file_name[last..].split('.').next().unwrap();
+	if REGEX_IS_REGULAR_SEGMENT.is_match(last_id) {
+		return last;
+	}
+	match file_name.rfind(&format!("_{}", BRIDGE_PREFIX)) {
+		Some(i) => i + 1,
+		None if file_name.starts_with(BRIDGE_PREFIX) => 0,
+		None => last,
+	}
 }
 
 pub fn parse_segment(file_name: &str) -> Result<SongSegment, DjError> {
-	let name_split = file_name.split('_');
-	let mut song_segment_split = name_split.last().unwrap().split('.');
-	let song_segment_id = song_segment_split
-		.next()
-		.ok_or_else(|| DjError::InvalidFileName(file_name.to_string()))?;
-	let song_segment_format = song_segment_split
-		.next()
-		.ok_or_else(|| DjError::UnrecognizedSongFormat(file_name.to_string()))?;
-	let segment = SongSegment {
-		id: song_segment_id.to_string(),
-		format: song_segment_format.to_string(),
-		allowed_transitions: HashSet::new(),
+	let segment_name = &file_name[segment_start(file_name)..];
+	let song_segment_split = segment_name.split('.').collect::<Vec<_>>();
+	let (song_segment_id, layer, song_segment_format) = match song_segment_split.as_slice() {
+		[id, format] => (id, None, format),
+		[id, layer, format] => (id, Some(layer), format),
+		[_] => return Err(DjError::UnrecognizedSongFormat(file_name.to_string())),
+		_ => return Err(DjError::InvalidFileName(file_name.to_string())),
+	};
+	let segment = match layer {
+		Some(layer) => SongSegment {
+			id: song_segment_id.to_string(),
+			format: String::new(),
+			allowed_transitions: HashSet::new(),
+			layers: vec![SegmentLayer {
+				name: layer.to_string(),
+				format: song_segment_format.to_string(),
+			}],
+		},
+		None => SongSegment {
+			id: song_segment_id.to_string(),
+			format: song_segment_format.to_string(),
+			allowed_transitions: HashSet::new(),
+			layers: vec![],
+		},
 	};
 
 	Ok(segment)
 }
 
-pub fn initialize_songs<P: AsRef<Path>>(paths: &[P]) -> Result<HashMap<String, Song>, DjError> {
-	let mut songs = HashMap::new();
-	for path in paths {
-		let path = path.as_ref();
-		let file_name = match path.file_name().unwrap().to_str().ok_or(DjError::PathNotValidUnicode) {
-			Ok(val) => val,
-			Err(e) => {
-				println!("Warning: {}. Dropping.", e);
-				continue;
-			}
-		};
-		let file_type = match detect_file_type(file_name) {
-			Ok(val) => val,
-			Err(e) => {
-				println!("Warning: {}. Dropping.", e);
-				continue;
-			}
-		};
+/// Lists the files in `dir` that songs can be loaded from, including those in subdirectories.
+///
+/// If `dir_per_song` is set, subdirectories with a `start` segment are listed as songs instead
+/// of being scanned.
+pub fn list_song_paths<P: AsRef<Path>>(dir: P, dir_per_song: bool) -> Result<Vec<PathBuf>, DjError> {
+	let dir = dir.as_ref();
+	let unreadable = || DjError::UnreadableDirectory(dir.display().to_string());
+	let mut paths = Vec::new();
+	for entry in fs::read_dir(dir).map_err(|_| unreadable())? {
+		let path = entry.map_err(|_| unreadable())?.path();
+		if path.is_dir() && !(dir_per_song && is_song_directory(&path)) {
+			paths.extend(list_song_paths(&path, dir_per_song)?);
+		}
+		else {
+			paths.push(path);
+		}
+	}
+	paths.sort();
+	Ok(paths)
+}
 
-		match file_type {
-			FileType::SegmentFormat => {
-				let song_id = get_song_name(file_name)?;
-				let segment = parse_segment(file_name)?;
-				let song = songs.entry(song_id.to_string()).or_insert(Song {
-					id: song_id,
-					segments: HashMap::new(),
-					has_end: false,
-					has_global_ending: false,
-					has_multiple_loops: false,
-					has_dedicated_transitions: false,
-					is_archive: false,
-				});
-				if !song.has_end && segment.id.ends_with("end") {
-					song.has_end = true;
-					song.has_global_ending = segment.id == "end";
-				}
-				if segment.id != "loop" && REGEX_IS_LOOP.is_match(&segment.id) {
-					song.has_multiple_loops = true;
-				}
-				if REGEX_IS_DEDICATED_TRANSITION.is_match(&segment.id) {
-					song.has_dedicated_transitions = true;
-				}
-				if song.segments.contains_key(&segment.id) {
-					// Having multiple files with the same ID is ambiguous.
-					return Err(DjError::MultipleSegmentsWithSameId(song.id.to_string(), segment.id));
-				}
-				song.segments.entry(segment.id.to_string()).or_insert(segment);
-			}
-			FileType::SongArchiveFormat => {
-				let archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
-				let song_id = file_name
-					.split('.')
-					.next()
-					.ok_or(DjError::InvalidFileName(file_name.to_string()))?
-					.to_string();
-				println!("Encountered Archive {}.", song_id);
-				let song = songs.entry(song_id.to_string()).or_insert(Song {
-					id: song_id,
-					segments: HashMap::new(),
-					has_end: false,
-					has_global_ending: false,
-					has_multiple_loops: false,
-					has_dedicated_transitions: false,
-					is_archive: true,
-				});
-				for segment_path in archive.file_names() {
-					let segment = parse_segment(&segment_path)?;
-					if !song.has_end && segment.id.ends_with("end") {
-						song.has_end = true;
-						song.has_global_ending = segment.id == "end";
-					}
-					if segment.id != "loop" && REGEX_IS_LOOP.is_match(&segment.id) {
-						song.has_multiple_loops = true;
-					}
-					if REGEX_IS_DEDICATED_TRANSITION.is_match(&segment.id) {
-						song.has_dedicated_transitions = true;
-					}
-					if song.segments.contains_key(&segment.id) {
-						// Having multiple files with the same ID is ambiguous.
-						return Err(DjError::MultipleSegmentsWithSameId(song.id.to_string(), segment.id));
-					}
-					song.segments.entry(segment.id.to_string()).or_insert(segment);
-				}
-			}
+fn is_song_directory(dir: &Path) -> bool {
+	match fs::read_dir(dir) {
+		Ok(entries) => entries.filter_map(Result::ok).any(|entry| {
+			let name = entry.file_name().to_string_lossy().to_string();
+			REGEX_IS_START.is_match(name.split('.').next().unwrap())
+		}),
+		Err(_) => false,
+	}
+}
+
+/// Loads every song found in `paths`.
+///
+/// Songs with the same id from different sources are reported as a conflict instead of being
+/// merged into one song.
+pub fn initialize_songs<P: AsRef<Path>>(paths: &[P]) -> Result<HashMap<String, Song>, DjError> {
+	let mut songs: HashMap<String, Song> = HashMap::new();
+	for (song_id, source) in song_source::find_sources(paths)? {
+		if let Some(song) = songs.get(&song_id) {
+			return Err(DjError::ConflictingSongs(
+				song_id,
+				song.source.path().display().to_string(),
+				source.path().display().to_string(),
+			));
 		}
+		debug!(song = %song_id, kind = source.kind(), path = %source.path().display(), "Found song");
+		songs.insert(song_id.to_string(), Song::load(song_id, source)?);
 	}
 
 	Ok(songs)
@@ -402,6 +606,7 @@ pub fn initialize_songs<P: AsRef<Path>>(paths: &[P]) -> Result<HashMap<String, S
 pub fn initialize_transitions(songs: &mut HashMap<String, Song>) {
 	for song in songs.values_mut() {
 		let clone_segments = &song.segments.clone();
+		let mixed = song.metadata.transitions == TransitionMode::Mixed;
 
 		for song_segment in song.segments.values_mut() {
 			if song_segment.is_dedicated_transition() {
@@ -435,22 +640,36 @@ pub fn initialize_transitions(songs: &mut HashMap<String, Song>) {
 						}
 						song_segment.allowed_transitions.insert(seg.id.clone());
 					}
+					else if mixed && seg.is_loop() && seg.id != song_segment.id {
+						// Cut straight to loops there's no dedicated transition to.
+						let dedicated = format!("{}-to-{}", song_segment.id, seg.id.trim_start_matches("loop"));
+						if !clone_segments.contains_key(&dedicated) {
+							song_segment.allowed_transitions.insert(seg.id.clone());
+						}
+					}
 				}
 			}
+			else if song_segment.is_start() {
+				let start = REGEX_IS_START.captures(&song_segment.id).unwrap();
+				song_segment.allowed_transitions.insert(match start.get(3) {
+					Some(loop_to) => format!("loop{}", loop_to.as_str()),
+					None if song.has_multiple_loops => "loop0".to_string(),
+					None => "loop".to_string(),
+				});
+			}
+			else if song_segment.is_bridge() {
+				// Bridges take the place of the song's start.
+				song_segment.allowed_transitions.insert(if song.has_multiple_loops {
+					"loop0".to_string()
+				}
+				else {
+					"loop".to_string()
+				});
+			}
 			else {
 				match song_segment.id.as_str() {
-					"start" => {
-						song_segment.allowed_transitions.insert(if song.has_multiple_loops {
-							"loop0".to_string()
-						}
-						else {
-							"loop".to_string()
-						});
-					}
-					"loop" => {
-						if song.has_end && song.has_global_ending {
-							song_segment.allowed_transitions.insert("end".to_string());
-						}
+					"loop" if song.has_end && song.has_global_ending => {
+						song_segment.allowed_transitions.insert("end".to_string());
 					}
 					_ => {}
 				}
@@ -467,6 +686,7 @@ prop_compose! {
 			id,
 			format: segment_format,
 			allowed_transitions: set!(),
+			layers: vec![],
 		}
 	}
 }
@@ -480,6 +700,7 @@ prop_compose! {
 			id: "start".to_string(),
 			format:"ogg".to_string(),
 			allowed_transitions: set!(),
+			layers: vec![],
 
 		});
 
@@ -489,6 +710,7 @@ prop_compose! {
 					id: "loop".to_string(),
 					format:"ogg".to_string(),
 					allowed_transitions: set!(),
+					layers: vec![],
 				});
 			},
 			_ => {
@@ -497,6 +719,7 @@ prop_compose! {
 						id: format!("loop{}", i),
 						format:"ogg".to_string(),
 						allowed_transitions: set!(),
+						layers: vec![],
 					});
 				}
 			}
@@ -513,6 +736,7 @@ prop_compose! {
 						id: format!("loop{}-to-{}", from, to),
 						format:"ogg".to_string(),
 						allowed_transitions: set!(),
+						layers: vec![],
 					});
 					transition_count += 1;
 					if transition_count >= loop_transitions {
@@ -528,7 +752,8 @@ prop_compose! {
 					segment_vec.push(SongSegment {
 						id: format!("loop{}-end", i),
 						format:"ogg".to_string(),
-						allowed_transitions: set!()
+						allowed_transitions: set!(),
+						layers: vec![],
 					});
 				}
 			}
@@ -536,7 +761,8 @@ prop_compose! {
 				segment_vec.push(SongSegment {
 					id: "end".to_string(),
 					format:"ogg".to_string(),
-					allowed_transitions: set!()
+					allowed_transitions: set!(),
+					layers: vec![],
 				});
 			}
 		}
@@ -548,11 +774,13 @@ prop_compose! {
 		Song {
 			id,
 			segments,
+			stingers: HashMap::new(),
+			metadata: SongMetadata::default(),
 			has_end,
 			has_global_ending: has_end && (has_global_ending || loop_count == 1),
 			has_multiple_loops: loop_count > 1,
 			has_dedicated_transitions: loop_transitions > 0,
-			is_archive: false,
+			source: Arc::new(LooseFiles::new("", "")),
 		}
 	}
 }
@@ -572,6 +800,7 @@ prop_compose! {
 				id: "start".to_string(),
 				format:"ogg".to_string(),
 				allowed_transitions: set!(),
+				layers: vec![],
 			});
 
 			match loop_count {
@@ -580,6 +809,7 @@ prop_compose! {
 						id: "loop".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: set!(),
+						layers: vec![],
 					});
 				},
 				_ => {
@@ -588,6 +818,7 @@ prop_compose! {
 							id: format!("loop{}", i),
 							format:"ogg".to_string(),
 							allowed_transitions: set!(),
+							layers: vec![],
 						});
 					}
 				}
@@ -601,6 +832,7 @@ prop_compose! {
 					id: format!("loop{}-to-{}", from, to),
 					format:"ogg".to_string(),
 					allowed_transitions: set!(),
+					layers: vec![],
 				});
 			}
 
@@ -609,6 +841,7 @@ prop_compose! {
 					id: "end".to_string(),
 					format:"ogg".to_string(),
 					allowed_transitions: set!(),
+					layers: vec![],
 				});
 			}
 
@@ -619,11 +852,13 @@ prop_compose! {
 			Song {
 				id,
 				segments,
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end,
 				has_global_ending: has_end,
 				has_multiple_loops: loop_count > 1,
 				has_dedicated_transitions: true,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("", "")),
 			}
 	}
 }
@@ -631,6 +866,7 @@ prop_compose! {
 #[cfg(test)]
 mod test_song_parsing {
 	use super::*;
+	use song_source::ZipArchiveSource;
 
 	#[test]
 	fn test_song_archive() {
@@ -648,23 +884,93 @@ mod test_song_parsing {
 					id: "start".to_string(),
 					format:"wav".to_string(),
 					allowed_transitions: HashSet::new(),
+					layers: vec![],
 
 				},
 				"loop".to_string() => SongSegment {
 					id: "loop".to_string(),
 					format:"wav".to_string(),
 					allowed_transitions: HashSet::new(),
+					layers: vec![],
 
 				}),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: false,
 				has_global_ending: false,
 				has_multiple_loops: false,
 				has_dedicated_transitions: false,
-				is_archive: true,
+				source: Arc::new(ZipArchiveSource::new("test-data/test_song_archive/song_archive.zip")),
 			}
 		)
 	}
 
+	#[test]
+	fn test_song_directory() {
+		let songs = initialize_songs(&["test-data/test_song_directory/directory_song"]).unwrap();
+		let source = &songs["directory_song"].source;
+		assert_eq!(source.kind(), "song directory");
+		assert_eq!(source.path(), Path::new("test-data/test_song_directory/directory_song"));
+		let mut segment_ids = songs["directory_song"].segments.keys().collect::<Vec<_>>();
+		segment_ids.sort();
+		assert_eq!(segment_ids, vec!["loop", "start"]);
+	}
+
+	#[test]
+	fn test_list_song_paths() {
+		let paths = list_song_paths("test-data", false).unwrap();
+		assert!(paths.contains(&PathBuf::from("test-data/test_song_archive/song_archive.zip")));
+		assert!(paths.contains(&PathBuf::from("test-data/test_song_directory/directory_song/start.wav")));
+
+		let paths = list_song_paths("test-data", true).unwrap();
+		assert!(paths.contains(&PathBuf::from("test-data/test_song_archive/song_archive.zip")));
+		assert!(paths.contains(&PathBuf::from("test-data/test_song_directory/directory_song")));
+		assert!(!paths.contains(&PathBuf::from("test-data/test_song_directory/directory_song/start.wav")));
+	}
+
+	#[test]
+	fn test_skip_stray_files() {
+		// Without --dir-per-song, the files in a song directory aren't named after a song.
+		let paths = list_song_paths("test-data", false).unwrap();
+		let songs = initialize_songs(&paths).unwrap();
+		assert!(songs.contains_key("song_archive"));
+		assert!(!songs.contains_key("start"));
+	}
+
+	#[test]
+	fn test_ogg_duration() {
+		let page = |granule_position: i64, packet: &[u8]| {
+			let mut page = b"OggS\0\0".to_vec();
+			page.extend_from_slice(&granule_position.to_le_bytes());
+			page.extend_from_slice(&[0; 12]);
+			page.push(1);
+			page.push(packet.len() as u8);
+			page.extend_from_slice(packet);
+			page
+		};
+		let mut identification = b"\x01vorbis\0\0\0\0\x02".to_vec();
+		identification.extend_from_slice(&8000u32.to_le_bytes());
+		let mut data = page(0, &identification);
+		data.extend(page(12000, b"audio"));
+		data.extend(page(-1, b"more audio"));
+		assert_eq!(ogg_duration(&data), Some(Duration::from_millis(1500)));
+		assert_eq!(ogg_duration(&page(0, b"\x01vorbit")), None);
+		assert_eq!(ogg_duration(b"RIFF"), None);
+	}
+
+	#[test]
+	fn test_detect_conflicting_songs() {
+		let paths = ["library/song_start.ogg", "library/song_loop.ogg", "other/song_end.ogg"];
+		assert_eq!(
+			initialize_songs(&paths),
+			Err(DjError::ConflictingSongs(
+				"song".to_string(),
+				"library".to_string(),
+				"other".to_string()
+			))
+		);
+	}
+
 	#[test]
 	fn test_initialize_songs() {
 		let paths = [
@@ -694,23 +1000,28 @@ mod test_song_parsing {
 						id: "start".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop".to_string() => SongSegment {
 						id: "loop".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"end".to_string() => SongSegment {
 						id: "end".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					}
 				),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: true,
 				has_global_ending: true,
 				has_multiple_loops: false,
 				has_dedicated_transitions: false,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("songs", "song_1")),
 			}
 		);
 		assert_eq!(
@@ -722,28 +1033,34 @@ mod test_song_parsing {
 						id: "start".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop0".to_string() => SongSegment {
 						id: "loop0".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop1".to_string() => SongSegment {
 						id: "loop1".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"end".to_string() => SongSegment {
 						id: "end".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					}
 				),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: true,
 				has_global_ending: true,
 				has_multiple_loops: true,
 				has_dedicated_transitions: false,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("songs", "song_2")),
 			}
 		);
 		assert_eq!(
@@ -755,33 +1072,40 @@ mod test_song_parsing {
 						id: "start".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop0".to_string() => SongSegment {
 						id: "loop0".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop0-to-1".to_string() => SongSegment {
 						id: "loop0-to-1".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop1".to_string() => SongSegment {
 						id: "loop1".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"end".to_string() => SongSegment {
 						id: "end".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					}
 				),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: true,
 				has_global_ending: true,
 				has_multiple_loops: true,
 				has_dedicated_transitions: true,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("songs", "y3")),
 			}
 		);
 		assert_eq!(
@@ -793,27 +1117,110 @@ mod test_song_parsing {
 						id: "start".to_string(),
 						format:"wav".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop".to_string() => SongSegment {
 						id: "loop".to_string(),
 						format:"wav".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"end".to_string() => SongSegment {
 						id: "end".to_string(),
 						format:"wav".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					}
 				),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: true,
 				has_global_ending: true,
 				has_multiple_loops: false,
 				has_dedicated_transitions: false,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("songs", "song_wav")),
 			}
 		);
 	}
 
+	#[test]
+	fn test_layered_segments() {
+		let paths = [
+			"songs/song_1_start.ogg",
+			"songs/song_1_loop.drums.ogg",
+			"songs/song_1_loop.ogg",
+			"songs/song_1_loop.pads.wav",
+			"songs/song_2_start.ogg",
+			"songs/song_2_loop.drums.ogg",
+		];
+		let songs = initialize_songs(&paths).unwrap();
+		assert_eq!(
+			songs["song_1"].segments["loop"],
+			SongSegment {
+				id: "loop".to_string(),
+				format: "ogg".to_string(),
+				allowed_transitions: HashSet::new(),
+				layers: vec![
+					SegmentLayer {
+						name: "drums".to_string(),
+						format: "ogg".to_string(),
+					},
+					SegmentLayer {
+						name: "pads".to_string(),
+						format: "wav".to_string(),
+					},
+				],
+			}
+		);
+		assert_eq!(
+			songs["song_2"].segments["loop"],
+			SongSegment {
+				id: "loop".to_string(),
+				format: "".to_string(),
+				allowed_transitions: HashSet::new(),
+				layers: vec![SegmentLayer {
+					name: "drums".to_string(),
+					format: "ogg".to_string(),
+				}],
+			}
+		);
+	}
+
+	#[test]
+	fn test_detect_duplicate_layer() {
+		let paths = ["song_start.wav", "song_loop.drums.wav", "song_loop.drums.ogg"];
+		assert_eq!(
+			initialize_songs(&paths),
+			Err(DjError::MultipleSegmentsWithSameId(
+				"song".to_string(),
+				"loop.drums".to_string()
+			))
+		);
+	}
+
+	#[test]
+	fn test_stinger_segments() {
+		let paths = [
+			"songs/song_1_start.ogg",
+			"songs/song_1_loop.ogg",
+			"songs/song_1_stinger-weekend.ogg",
+		];
+		let songs = initialize_songs(&paths).unwrap();
+		assert_eq!(songs["song_1"].segments.len(), 2);
+		assert!(!songs["song_1"].has_end);
+		assert_eq!(
+			songs["song_1"].stingers,
+			map!(
+				"weekend".to_string() => SongSegment {
+					id: "stinger-weekend".to_string(),
+					format: "ogg".to_string(),
+					allowed_transitions: HashSet::new(),
+					layers: vec![],
+				}
+			)
+		);
+	}
+
 	#[test]
 	#[should_panic(
 		expected = "called `Result::unwrap()` on an `Err` value: MultipleSegmentsWithSameId(\"song_format\", \"loop\")"
@@ -838,23 +1245,28 @@ mod test_song_parsing {
 						id: "start".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop".to_string() => SongSegment {
 						id: "loop".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"end".to_string() => SongSegment {
 						id: "end".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					}
 				),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: true,
 				has_global_ending: true,
 				has_multiple_loops: false,
 				has_dedicated_transitions: false,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("songs", "1")),
 			},
 			"2".to_string() => Song {
 				id: "2".to_string(),
@@ -863,28 +1275,34 @@ mod test_song_parsing {
 						id: "start".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop0".to_string() => SongSegment {
 						id: "loop0".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop1".to_string() => SongSegment {
 						id: "loop1".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"end".to_string() => SongSegment {
 						id: "end".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					}
 				),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: true,
 				has_global_ending: true,
 				has_multiple_loops: true,
 				has_dedicated_transitions: false,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("songs", "2")),
 			},
 			"3".to_string() => Song {
 				id: "3".to_string(),
@@ -893,33 +1311,40 @@ mod test_song_parsing {
 						id: "start".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop0".to_string() => SongSegment {
 						id: "loop0".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop0-to-1".to_string() => SongSegment {
 						id: "loop0-to-1".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"loop1".to_string() => SongSegment {
 						id: "loop1".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					},
 					"end".to_string() => SongSegment {
 						id: "end".to_string(),
 						format:"ogg".to_string(),
 						allowed_transitions: HashSet::new(),
+						layers: vec![],
 					}
 				),
+				stingers: HashMap::new(),
+				metadata: SongMetadata::default(),
 				has_end: true,
 				has_global_ending: true,
 				has_multiple_loops: true,
 				has_dedicated_transitions: true,
-				is_archive: false,
+				source: Arc::new(LooseFiles::new("songs", "3")),
 			}
 		};
 
@@ -965,6 +1390,78 @@ mod test_song_parsing {
 		assert_eq!(songs["3"].segments["end"].allowed_transitions, HashSet::new());
 	}
 
+	#[test]
+	fn test_start_variant_transitions() {
+		let paths = [
+			"songs/song_start.ogg",
+			"songs/song_start1.ogg",
+			"songs/song_start-to-2.ogg",
+			"songs/song_loop0.ogg",
+			"songs/song_loop1.ogg",
+			"songs/song_loop2.ogg",
+			"songs/song_end.ogg",
+		];
+		let mut songs = initialize_songs(&paths).unwrap();
+		initialize_transitions(&mut songs);
+		let song = &songs["song"];
+		assert!(!song.has_dedicated_transitions);
+		assert_eq!(song.segments["start"].allowed_transitions, set!["loop0".to_string()]);
+		assert_eq!(song.segments["start1"].allowed_transitions, set!["loop0".to_string()]);
+		assert_eq!(
+			song.segments["start-to-2"].allowed_transitions,
+			set!["loop2".to_string()]
+		);
+		assert!(!song.segments["loop2"].allowed_transitions.contains("start-to-2"));
+	}
+
+	#[test]
+	fn test_bridge_transitions() {
+		let paths = [
+			"songs/song_start.ogg",
+			"songs/song_from-other_song.ogg",
+			"songs/song_loop0.ogg",
+			"songs/song_loop1.ogg",
+			"songs/other_song_start.ogg",
+			"songs/other_song_loop.ogg",
+			"songs/other_song_from-song.ogg",
+		];
+		let mut songs = initialize_songs(&paths).unwrap();
+		initialize_transitions(&mut songs);
+		let song = &songs["song"];
+		assert_eq!(
+			song.segments["from-other_song"].allowed_transitions,
+			set!["loop0".to_string()]
+		);
+		assert!(!song.segments["loop0"].allowed_transitions.contains("from-other_song"));
+		assert!(song.bridge_from("other_song").is_some());
+		assert!(song.bridge_from("song").is_none());
+		assert_eq!(
+			songs["other_song"].segments["from-song"].allowed_transitions,
+			set!["loop".to_string()]
+		);
+	}
+
+	#[test]
+	fn test_bridge_file_names() {
+		assert_eq!(get_song_name("song_from-other_song.ogg").unwrap(), "song");
+		assert_eq!(parse_segment("song_from-other_song.ogg").unwrap().id, "from-other_song");
+		assert_eq!(get_song_name("rock_from-space_start.ogg").unwrap(), "rock_from-space");
+		assert_eq!(parse_segment("rock_from-space_start.ogg").unwrap().id, "start");
+		assert_eq!(
+			get_song_name("rock_from-space_loop1.drums.ogg").unwrap(),
+			"rock_from-space"
+		);
+		assert_eq!(parse_segment("rock_from-space_loop1.drums.ogg").unwrap().id, "loop1");
+		assert_eq!(
+			get_song_name("rock_from-space_from-jazz.ogg").unwrap(),
+			"rock_from-space"
+		);
+		assert_eq!(parse_segment("rock_from-space_from-jazz.ogg").unwrap().id, "from-jazz");
+		assert_eq!(get_song_name("song_from-the_weekend.ogg").unwrap(), "song");
+		assert_eq!(parse_segment("from-other_song.ogg").unwrap().id, "from-other_song");
+		assert!(get_song_name("from-other.ogg").is_err());
+	}
+
 	proptest! {
 		#[test]
 		fn prop_multiloop_song_should_not_contain_references_to_loop(song_id in "[a-z0-9]+", loop_count in 2..10) {
@@ -985,6 +1482,28 @@ mod test_song_parsing {
 			}
 		}
 
+		#[test]
+		fn prop_mixed_transitions_reach_every_loop(song in song_strategy(12, true)) {
+			let song_id = song.id.to_string();
+			let mut song = song;
+			song.metadata.transitions = TransitionMode::Mixed;
+			let mut songs: HashMap<String, Song> = map!(song_id.clone() => song);
+			initialize_transitions(&mut songs);
+			let song = &songs[&song_id];
+			for from in song.segments.values().filter(|s| s.is_loop()) {
+				for to in song.segments.values().filter(|s| s.is_loop() && s.id != from.id) {
+					let dedicated = format!("{}-to-{}", from.id, to.id.trim_start_matches("loop"));
+					if song.segments.contains_key(&dedicated) {
+						prop_assert!(from.allowed_transitions.contains(&dedicated));
+						prop_assert!(!from.allowed_transitions.contains(&to.id));
+					}
+					else {
+						prop_assert!(from.allowed_transitions.contains(&to.id));
+					}
+				}
+			}
+		}
+
 		#[test]
 		fn prop_should_generate_transitions(song in song_strategy(12, true)) {
 			let song_id = song.id.to_string();
@@ -1101,18 +1620,18 @@ mod test_song_planning {
 		let mut songs = initialize_songs(&paths).unwrap();
 		initialize_transitions(&mut songs);
 		let big_ass_song = songs["big"].clone();
-		assert_eq!(big_ass_song.has_end, true);
-		assert_eq!(big_ass_song.has_global_ending, false);
-		assert_eq!(big_ass_song.has_multiple_loops, true);
-		assert_eq!(big_ass_song.has_dedicated_transitions, true);
-		let plan = big_ass_song.make_plan(&mut rng);
+		assert!(big_ass_song.has_end);
+		assert!(!big_ass_song.has_global_ending);
+		assert!(big_ass_song.has_multiple_loops);
+		assert!(big_ass_song.has_dedicated_transitions);
+		let plan = SegmentGraph::new(&big_ass_song).unwrap().plan(None, &mut rng);
 		assert!(plan.len() >= 3);
 		assert_eq!(&plan.first().unwrap().id, &"start".to_string());
 		assert!(&plan.last().unwrap().id.ends_with("end"));
 		println!("{:?}", plan.iter().map(|x| x.id.clone()).collect::<Vec<_>>());
 
 		let big_ass_song = songs["big_no_trans"].clone();
-		let plan = big_ass_song.make_plan(&mut rng);
+		let plan = SegmentGraph::new(&big_ass_song).unwrap().plan(None, &mut rng);
 		assert!(plan.len() >= 3);
 		assert_eq!(&plan.first().unwrap().id, &"start".to_string());
 		assert!(&plan.last().unwrap().id.ends_with("end"));
@@ -1126,7 +1645,7 @@ mod test_song_planning {
 			let song_id = song.id.to_string();
 			let mut songs: HashMap<String, Song> = map!(song_id.clone() => song);
 			initialize_transitions(&mut songs);
-			let plan = songs[&song_id].make_plan(&mut rng);
+			let plan = SegmentGraph::new(&songs[&song_id]).unwrap().plan(None, &mut rng);
 			prop_assert!(&plan.last().unwrap().id.ends_with("end"));
 		}
 
@@ -1136,7 +1655,7 @@ mod test_song_planning {
 			let song_id = song.id.to_string();
 			let mut songs: HashMap<String, Song> = map!(song_id.clone() => song);
 			initialize_transitions(&mut songs);
-			let plan = songs[&song_id].make_plan(&mut rng);
+			let plan = SegmentGraph::new(&songs[&song_id]).unwrap().plan(None, &mut rng);
 			prop_assert_ne!(&plan.last().unwrap().id, &"end".to_string())
 		}
 
@@ -1146,7 +1665,7 @@ mod test_song_planning {
 			let song_id = song.id.to_string();
 			let mut songs: HashMap<String, Song> = map!(song_id.clone() => song);
 			initialize_transitions(&mut songs);
-			let plan = songs[&song_id].make_plan(&mut rng);
+			let plan = SegmentGraph::new(&songs[&song_id]).unwrap().plan(None, &mut rng);
 			prop_assert_eq!(&plan.first().unwrap().id, &"start".to_string())
 		}
 
@@ -1156,7 +1675,7 @@ mod test_song_planning {
 			let song_id = song.id.to_string();
 			let mut songs: HashMap<String, Song> = map!(song_id.clone() => song);
 			initialize_transitions(&mut songs);
-			let plan = songs[&song_id].make_plan(&mut rng);
+			let plan = SegmentGraph::new(&songs[&song_id]).unwrap().plan(None, &mut rng);
 			prop_assert!(plan.len() >= 3)
 		}
 
@@ -1166,7 +1685,7 @@ mod test_song_planning {
 			let song_id = song.id.to_string();
 			let mut songs: HashMap<String, Song> = map!(song_id.clone() => song);
 			initialize_transitions(&mut songs);
-			let plan = songs[&song_id].make_plan(&mut rng);
+			let plan = SegmentGraph::new(&songs[&song_id]).unwrap().plan(None, &mut rng);
 			prop_assert!(plan.len() >= 2);
 		}
 
@@ -1176,10 +1695,80 @@ mod test_song_planning {
 			let song_id = song.id.to_string();
 			let mut songs: HashMap<String, Song> = map!(song_id.clone() => song);
 			initialize_transitions(&mut songs);
-			let plan = songs[&song_id].make_plan(&mut rng);
+			let plan = SegmentGraph::new(&songs[&song_id]).unwrap().plan(None, &mut rng);
 			prop_assert!(!plan.last().unwrap().is_dedicated_transition())
 		}
 	}
+
+	#[test]
+	fn test_bridged_songs() {
+		let paths = [
+			"songs/a_start.ogg",
+			"songs/a_loop.ogg",
+			"songs/b_start.ogg",
+			"songs/b_from-a.ogg",
+			"songs/b_loop.ogg",
+			"songs/c_start.ogg",
+			"songs/c_loop.ogg",
+		];
+		let songs = initialize_songs(&paths).unwrap();
+		let mut candidates = songs.keys().collect::<Vec<_>>();
+		candidates.sort();
+		assert_eq!(bridged_songs(&candidates, &songs, Some("a")), vec!["b"]);
+		assert!(bridged_songs(&candidates, &songs, Some("b")).is_empty());
+		assert!(bridged_songs(&candidates, &songs, None).is_empty());
+	}
+
+	#[test]
+	fn test_pick_song_avoids_recent_with_history() {
+		let songs = initialize_songs(&["songs/a_loop.ogg", "songs/b_loop.ogg"]).unwrap();
+		let args = App::new("test")
+			.arg(Arg::with_name("OVERRIDE").index(1))
+			.get_matches_from(vec!["test"]);
+		let playlists = Playlists::new(HashMap::new(), None, vec![]).unwrap();
+		let mut rng = StdRng::seed_from_u64(1);
+		let mut pick = |recent: Option<&mut Vec<String>>| {
+			pick_song(&args, &playlists, &songs, Selection::Random, None, recent, &mut rng)
+				.unwrap()
+				.to_string()
+		};
+
+		let mut recent = vec!["a".to_string()];
+		for _ in 0..10 {
+			let song_id = pick(Some(&mut recent));
+			assert_ne!(song_id, recent[recent.len() - 2]);
+		}
+		assert_eq!(recent.len(), 2);
+		// Without a history, songs can come up again straight away.
+		let picks = (0..20).map(|_| pick(None)).collect::<Vec<_>>();
+		assert!(picks.windows(2).any(|pair| pair[0] == pair[1]));
+	}
+
+	#[test]
+	fn test_pick_song_errors() {
+		let songs = initialize_songs(&["songs/a_loop.ogg"]).unwrap();
+		let app = App::new("test").arg(Arg::with_name("OVERRIDE").index(1));
+		let playlists = Playlists::new(HashMap::new(), None, vec![]).unwrap();
+		let mut rng = StdRng::seed_from_u64(1);
+		let args = app.clone().get_matches_from(vec!["test", "b"]);
+		assert_eq!(
+			pick_song(&args, &playlists, &songs, Selection::Random, None, None, &mut rng),
+			Err(DjError::UnknownSong("b".to_string()))
+		);
+		let args = app.get_matches_from(vec!["test"]);
+		assert_eq!(
+			pick_song(
+				&args,
+				&playlists,
+				&HashMap::new(),
+				Selection::Random,
+				None,
+				None,
+				&mut rng
+			),
+			Err(DjError::NoSongs)
+		);
+	}
 }
 
 fn main() {
@@ -1191,87 +1780,668 @@ fn main() {
 			.short("s")
 			.long("songs-dir")
 			.value_name("SONGS_DIR")
-			.help("Sets a custom config file")
+			.help("Sets the directory to load songs from. Can be given multiple times. Subdirectories are scanned too.")
 			.default_value("./songs")
-			.takes_value(true))
+			.takes_value(true)
+			.multiple(true)
+			.number_of_values(1))
+		.arg(Arg::with_name("dir-per-song")
+			.long("dir-per-song")
+			.help("Treat subdirectories of songs-dir that contain a start segment as one song each, with files named like the ones in song archives (start.ogg, loop.ogg, ...)"))
+		.arg(Arg::with_name("config")
+			.short("c")
+			.long("config")
+			.value_name("FILE")
+			.takes_value(true)
+			.help("Loads playlists and other settings from this TOML file"))
 		.arg(Arg::with_name("OVERRIDE")
 			.help("Overrides song selection with this song.")
 			.required(false)
 			.index(1))
+		.arg(Arg::with_name("playlist")
+			.short("p")
+			.long("playlist")
+			.value_name("PLAYLIST")
+			.takes_value(true)
+			.help("Starts by picking songs from this playlist, defined in the config file"))
 		.arg(Arg::with_name("max-repeats")
 			.long("max-repeats")
 			.default_value("13")
 			.takes_value(true)
 			.help("Sets the max number of loop repeats"))
+		.arg(Arg::with_name("plan-mode")
+			.long("plan-mode")
+			.value_name("MODE")
+			.default_value("full")
+			.takes_value(true)
+			.help("How songs are planned: full, bumper to take the shortest way from a start to an ending, playing each loop once, or endless to keep playing the song's loops until the wrap-up control command"))
+		.arg(Arg::with_name("selection")
+			.long("selection")
+			.value_name("STRATEGY")
+			.default_value("random")
+			.takes_value(true)
+			.help("How the next song is picked: random, or harmonic to prefer songs in a key next to the current song's on the Camelot wheel and with a similar tempo"))
+		.arg(Arg::with_name("cache-size")
+			.long("cache-size")
+			.value_name("MIB")
+			.default_value("256")
+			.takes_value(true)
+			.help("Keeps up to this many MiB of recently played song files in memory. 0 disables the cache."))
+		.arg(Arg::with_name("sample-rate")
+			.long("sample-rate")
+			.value_name("HZ")
+			.default_value("48000")
+			.takes_value(true)
+			.help("Sample rate that every segment is converted to before playing"))
+		.arg(Arg::with_name("channels")
+			.long("channels")
+			.default_value("2")
+			.takes_value(true)
+			.help("Number of channels that every segment is converted to before playing"))
+		.arg(Arg::with_name("output")
+			.short("o")
+			.long("output")
+			.value_name("OUTPUT")
+			.default_value("device")
+			.takes_value(true)
+			.help("Where to play the music: device, stdout, fifo:PATH, wav:PATH or http:ADDR[/MOUNT]. Everything but device writes 16-bit PCM in the format given by --sample-rate and --channels. http serves an Ogg/Vorbis stream, on /stream.ogg by default."))
+		.arg(Arg::with_name("device")
+			.long("device")
+			.value_name("DEVICE")
+			.takes_value(true)
+			.help("Plays on this audio device instead of the default one, by name or by its number in --list-devices. Overrides the device in the config file."))
+		.arg(Arg::with_name("list-devices")
+			.long("list-devices")
+			.help("Lists the available audio devices and exits"))
+		.arg(Arg::with_name("wav-roll")
+			.long("wav-roll")
+			.value_name("DURATION")
+			.default_value("1h")
+			.takes_value(true)
+			.help("How much audio to write to each file with --output wav:PATH, e.g. 30m"))
 		.arg(Arg::with_name("debug-wait-each-segment")
 			.long("debug-wait-each-segment")
 			.help("Force the program to wait for the sink to empty after each source is added to the sink, and print the name of the segments as they get queued up. Will cause small pauses between song segments as a result."))
+		.arg(Arg::with_name("stingers-dir")
+			.long("stingers-dir")
+			.value_name("STINGERS_DIR")
+			.takes_value(true)
+			.help("Directory of global stingers (one-shot sounds that can be played over any song), named STINGER.ogg"))
+		.arg(Arg::with_name("duck-volume")
+			.long("duck-volume")
+			.default_value("0.35")
+			.takes_value(true)
+			.help("Volume of the music while a stinger is playing"))
+		.arg(Arg::with_name("control-addr")
+			.long("control-addr")
+			.value_name("ADDR")
+			.takes_value(true)
+			.help("Listen for control commands on this address, e.g. 127.0.0.1:7878"))
+		.arg(Arg::with_name("log-level")
+			.long("log-level")
+			.value_name("LEVEL")
+			.default_value("info")
+			.takes_value(true)
+			.help("Only logs messages at this level and above: error, warn, info, debug or trace"))
+		.arg(Arg::with_name("log-file")
+			.long("log-file")
+			.value_name("FILE")
+			.takes_value(true)
+			.help("Also writes log messages to this file, as one JSON object per line"))
+		.arg(Arg::with_name("plan-file")
+			.long("plan")
+			.value_name("FILE")
+			.takes_value(true)
+			.help("Plays the plan saved as JSON in this file first, eg. one printed by `plan --json`"))
+		.arg(Arg::with_name("history")
+			.long("history")
+			.value_name("FILE")
+			.takes_value(true)
+			.global(true)
+			.help("Records every song played in this file, and avoids songs that played recently when starting again"))
+		.subcommand(SubCommand::with_name("stats")
+			.about("Summarizes the plays in the history file, per song and per segment"))
+		.subcommand(SubCommand::with_name("plan")
+			.about("Prints plans for a song without playing it, to see how its segments get used")
+			.arg(Arg::with_name("SONG")
+				.help("The ID of the song to plan")
+				.required(true)
+				.index(1))
+			.arg(Arg::with_name("count")
+				.short("n")
+				.long("count")
+				.value_name("N")
+				.default_value("10")
+				.takes_value(true)
+				.help("How many plans to make"))
+			.arg(Arg::with_name("seed")
+				.long("seed")
+				.value_name("SEED")
+				.takes_value(true)
+				.help("Makes the same plans every time for the same number"))
+			.arg(Arg::with_name("json")
+				.long("json")
+				.help("Prints each plan as a line of JSON that --plan can play, instead of a summary")))
+		.subcommand(SubCommand::with_name("graph")
+			.about("Prints the transitions between a song's segments as a diagram, marking segments that can't be reached or can't lead to an ending")
+			.arg(Arg::with_name("SONG")
+				.help("The ID of the song to draw")
+				.required(true)
+				.index(1))
+			.arg(Arg::with_name("format")
+				.short("f")
+				.long("format")
+				.value_name("FORMAT")
+				.default_value("dot")
+				.takes_value(true)
+				.help("The diagram language: dot (Graphviz) or mermaid")))
 		.get_matches();
 
-	if let Err(e) = run(args) {
+	if let Err(e) = logging::init(args.value_of("log-level").unwrap(), args.value_of("log-file")) {
 		eprintln!("Error: {}.", e);
 		std::process::exit(1);
 	}
+	if let Err(e) = run(args) {
+		if let Some(DjError::Stopped) = e.downcast_ref::<DjError>() {
+			info!("Stopped");
+			return;
+		}
+		error!("{}", e);
+		std::process::exit(1);
+	}
 }
 
 fn run(args: ArgMatches) -> Result<(), Box<dyn Error>> {
-	let songs_dir = args.value_of("songs-dir").unwrap();
-	let paths = fs::read_dir(songs_dir)?;
-	let path_strings = paths
-		.map(|p| p.unwrap().path().display().to_string())
-		.collect::<Vec<_>>();
+	if args.is_present("list-devices") {
+		let devices = list_devices()?;
+		if devices.is_empty() {
+			println!("No output devices are available.");
+		}
+		for (index, name) in devices.iter().enumerate() {
+			println!("{}: {}", index, name);
+		}
+		return Ok(());
+	}
+	if let Some(stats_args) = args.subcommand_matches("stats") {
+		let path = stats_args
+			.value_of("history")
+			.or_else(|| args.value_of("history"))
+			.ok_or(DjError::HistoryRequired)?;
+		print!("{}", Stats::new(&history::read_plays(path)?));
+		return Ok(());
+	}
+	if let Some(plan_args) = args.subcommand_matches("plan") {
+		return preview_plans(&args, plan_args);
+	}
+	if let Some(graph_args) = args.subcommand_matches("graph") {
+		let format: GraphFormat = graph_args.value_of("format").unwrap().parse()?;
+		let songs = load_songs(&args)?;
+		print!("{}", graph_export::export(find_song(&songs, graph_args)?, format));
+		return Ok(());
+	}
 
-	let mut songs = initialize_songs(&path_strings)?;
-	initialize_transitions(&mut songs);
-	println!("Found {} songs.", songs.len());
+	let format = OutputFormat {
+		sample_rate: args
+			.value_of("sample-rate")
+			.unwrap()
+			.parse()
+			.ok()
+			.filter(|v| *v > 0)
+			.ok_or(DjError::SampleRateInvalidValue)?,
+		channels: args
+			.value_of("channels")
+			.unwrap()
+			.parse()
+			.ok()
+			.filter(|v| *v > 0)
+			.ok_or(DjError::ChannelsInvalidValue)?,
+	};
+
+	let wav_roll =
+		humantime::parse_duration(args.value_of("wav-roll").unwrap()).map_err(|_| DjError::WavRollInvalidValue)?;
+	let config = match args.value_of("config") {
+		Some(path) => Config::load(path)?,
+		None => Config::default(),
+	};
+	let device = args.value_of("device").or(config.device.as_deref());
+	// Opened first, because writing audio to stdout moves all messages over to stderr.
+	let output = Output::open(
+		&args.value_of("output").unwrap().parse()?,
+		device,
+		&config.fallback_devices,
+		format,
+		wav_roll,
+	)?;
+
+	let mut songs = load_songs(&args)?;
+	let graphs = planner::build_graphs(&mut songs);
+	info!(count = songs.len(), "Found songs");
+
+	let cache_size: usize = args
+		.value_of("cache-size")
+		.unwrap()
+		.parse()
+		.map_err(|_| DjError::CacheSizeInvalidValue)?;
+	let cache = SegmentCache::new(cache_size * MIB);
+	for song in songs.values_mut() {
+		song.source = Arc::new(CachedSource::new(song.source.clone(), cache.clone()));
+	}
 
 	let mut rng = rand::thread_rng();
-	let device = rodio::default_output_device().ok_or(DjError::NoOutputDeviceAvailable)?;
-	let sink = Sink::new(&device);
 
-	let max_repeats: u32 = args
-		.value_of("max-repeats")
+	let max_repeats = parse_max_repeats(&args)?;
+	let duck_volume: f32 = args
+		.value_of("duck-volume")
 		.unwrap()
 		.parse()
-		.map_err(|_| DjError::MaxRepeatsInvalidValue)?;
+		.ok()
+		.filter(|v| (0.0..=1.0).contains(v))
+		.ok_or(DjError::DuckVolumeInvalidValue)?;
+
+	let stingers = StingerLayer::new(output.new_sink(), args.value_of("stingers-dir"), duck_volume)?;
+	let control = match args.value_of("control-addr") {
+		Some(addr) => Some(control::listen(addr)?),
+		None => None,
+	};
+	let playlists = config.playlists(args.value_of("playlist"))?;
+	let mut default_mode = parse_plan_mode(&args)?;
+	let selection = parse_selection(&args)?;
+	let history = match args.value_of("history") {
+		Some(path) => Some(History::open(path)?),
+		None => None,
+	};
+	// Songs picked so far, oldest first, so that they don't come up again too soon. Only kept with a
+	// history, which carries them over to the next run.
+	let mut recent = history.as_ref().map(|history| {
+		history
+			.plays()
+			.iter()
+			.map(|play| play.song.to_string())
+			.collect::<Vec<_>>()
+	});
+	let songs = Arc::new(songs);
+	let mut player = Player::new(
+		output.new_sink(),
+		songs.clone(),
+		stingers,
+		control,
+		playlists,
+		format,
+		history,
+	);
+	output.interrupt_on_reconnect(player.position());
+	shutdown::listen();
+	player.watch_output(output.failure());
+	player.show_titles(output.stream_title());
+
+	let replay = match args.value_of("plan-file") {
+		Some(path) => Some(Plan::load(path, &songs, &graphs)?),
+		None => None,
+	};
+	let prefetcher = Prefetcher::spawn(
+		songs.clone(),
+		graphs,
+		PrepareOptions {
+			max_repeats,
+			intensity: player.intensity(),
+			format,
+		},
+	);
+	// The plan mode of the song being prepared.
+	let mut requested_mode = default_mode;
+	// When more of an endless song is being prepared, the segment it carries on after, and whether
+	// it is wrapping up.
+	let mut carrying_on: Option<(String, bool)> = None;
+	// The song played before the one being prepared, which it can bridge over from.
+	let mut previous_song: Option<String> = None;
+	// The fade out of the song before, queued once it's known whether the next song bridges over
+	// from it instead.
+	let mut fade_out: Vec<QueuedSource> = Vec::new();
+	// Whether the song being prepared is the plan from `--plan`, which is played as it is.
+	let mut replaying = replay.is_some();
+	match replay {
+		Some(plan) => {
+			if plan.endless {
+				requested_mode = PlanMode::Endless;
+			}
+			if let Some(recent) = &mut recent {
+				recent.push(plan.song.to_string());
+			}
+			prefetcher.replay(plan);
+		}
+		None => prefetcher.request(
+			pick_song(
+				&args,
+				player.playlists(),
+				&songs,
+				selection,
+				None,
+				recent.as_mut(),
+				&mut rng,
+			)?,
+			requested_mode,
+			None,
+		),
+	}
 
 	loop {
-		let current_song_id = args
-			.value_of("OVERRIDE")
-			.unwrap_or_else(|| songs.keys().collect::<Vec<_>>().choose(&mut rng).unwrap());
-		println!("Now playing: {}.", current_song_id);
-		let current_song = &songs[current_song_id];
-
-		let plan = current_song.make_plan(&mut rng);
-		println!(
-			"Plan: {:?}.",
-			plan.clone().iter().map(|x| x.id.clone()).collect::<Vec<_>>()
-		);
+		let mut prepared = prefetcher.take()?;
+		player.playlists().update_schedule();
+		let mode = match carrying_on.take() {
+			Some((after, wrap_up)) => {
+				if !wrap_up && player.wrap_up_requested() {
+					// The song was asked to wrap up while its next part was being prepared.
+					prefetcher.carry_on(&prepared.song_id, &after, true);
+					prepared = prefetcher.take()?;
+				}
+				PlanMode::Endless
+			}
+			None if replaying => {
+				replaying = false;
+				requested_mode
+			}
+			None => {
+				let mode = player.take_next_plan_mode().unwrap_or(default_mode);
+				let playlist_changed = args.value_of("OVERRIDE").is_none()
+					&& !player.playlists().songs(&songs).contains(&&prepared.song_id);
+				if playlist_changed || mode != requested_mode {
+					// The playlist or plan mode changed while the song was being prepared.
+					let previous = previous_song.as_deref();
+					let song_id = if playlist_changed {
+						pick_song(
+							&args,
+							player.playlists(),
+							&songs,
+							selection,
+							previous,
+							recent.as_mut(),
+							&mut rng,
+						)?
+						.to_string()
+					}
+					else {
+						prepared.song_id.to_string()
+					};
+					prefetcher.request(&song_id, mode, previous);
+					prepared = prefetcher.take()?;
+				}
+				mode
+			}
+		};
+		let carries_on = prepared.plan.after.is_some();
+		player.set_endless(prepared.plan.endless);
+		let current_song = &songs[&prepared.song_id];
+		let bridged = current_song.segments[&prepared.plan.entries[0].id].is_bridge();
+		for source in fade_out.drain(..) {
+			if !bridged {
+				player.append(source, None);
+			}
+		}
+		if carries_on {
+			let wrap_up = !prepared.plan.endless;
+			if wrap_up && default_mode == PlanMode::Endless {
+				// Once wrapped up, songs are played in full instead of endlessly again.
+				default_mode = PlanMode::Full;
+			}
+			info!(
+				song = %current_song.id,
+				plan = ?prepared.plan.entries.iter().map(|x| x.id.as_str()).collect::<Vec<_>>(),
+				duration = prepared.plan.duration(),
+				wrap_up,
+				"Carrying on"
+			);
+		}
+		else {
+			let title = current_song.metadata.title.as_deref().unwrap_or(&current_song.id);
+			info!(
+				song = %current_song.id,
+				title,
+				plan = ?prepared.plan.entries.iter().map(|x| x.id.as_str()).collect::<Vec<_>>(),
+				duration = prepared.plan.duration(),
+				?mode,
+				"Now playing"
+			);
+			if let Some(from) = current_song.segments[&prepared.plan.entries[0].id].bridge_from() {
+				info!(song = %current_song.id, from, "Bridging over");
+			}
+		}
 
-		for segment in &plan {
-			let source = current_song.read_segment(segment, songs_dir)?;
-			if args.is_present("debug-wait-each-segment") {
-				println!("Playing segment: {}.", segment.id);
+		let mut plan = Some(
+			prepared
+				.plan
+				.entries
+				.iter()
+				.map(|entry| PlannedSegment {
+					id: entry.id.to_string(),
+					repeat_count: entry.repeat_count,
+				})
+				.collect(),
+		);
+		// Listeners are shown the title once the song starts.
+		let mut title = (!carries_on).then(|| {
+			current_song
+				.metadata
+				.title
+				.clone()
+				.unwrap_or_else(|| current_song.id.to_string())
+		});
+		let mut sources = prepared.sources.into_iter();
+		// The last segment queued, which the next part of an endless song carries on after.
+		let mut last_queued = &prepared.plan.entries[0].id;
+		for (i, entry) in prepared.plan.entries.iter().enumerate() {
+			if prepared.plan.endless && i > 0 {
+				// Endless songs are queued a segment at a time, so they can wrap up as soon as asked.
+				player.wait_until_queued(1)?;
+				if player.wrap_up_requested() {
+					break;
+				}
 			}
-			if segment.is_loop() && !segment.is_dedicated_transition() {
-				let repeat_counts: u32 = rng.gen_range(5, max_repeats);
-				println!("Repeating {} {} times.", segment.id, repeat_counts);
-				sink.append(repeating_source::repeat_with_count(source, repeat_counts));
+			let segment = &current_song.segments[&entry.id];
+			let repeat_count = entry.repeat_count;
+			if let Some(count) = repeat_count {
+				info!(song = %current_song.id, segment = %segment.id, count, "Repeating segment");
 			}
-			else {
-				sink.append(source);
+			let mut plays = sources.next().unwrap().into_iter();
+			player.append(
+				plays.next().unwrap(),
+				Some(QueuedSegment {
+					song_id: current_song.id.to_string(),
+					segment_id: segment.id.to_string(),
+					repeat_count,
+					plan: plan.take(),
+					carries_on,
+					title: title.take(),
+					endless: prepared.plan.endless,
+				}),
+			);
+			// Each play of a loop is a source of its own, so that an interruption only skips the rest
+			// of the current one.
+			for play in plays {
+				player.append(play, None);
 			}
+			last_queued = &entry.id;
 			if args.is_present("debug-wait-each-segment") {
-				sink.sleep_until_end();
+				player.wait_until_end()?;
 			}
 		}
-		if !current_song.has_end {
-			let segment = plan.last().unwrap();
-			let source_end = current_song.read_segment(segment, songs_dir)?;
-			let empty_source: Zero<f32> = Zero::new(source_end.channels(), source_end.sample_rate());
-			sink.append(source_end.take_crossfade_with(empty_source, Duration::from_secs(8)));
+
+		// Start getting the next song ready while this one plays, and queue it up as soon as this
+		// song's last segment starts, so there is no gap between them.
+		if prepared.plan.endless {
+			let after = last_queued.to_string();
+			let wrap_up = player.wrap_up_requested();
+			prefetcher.carry_on(&current_song.id, &after, wrap_up);
+			carrying_on = Some((after, wrap_up));
+		}
+		else {
+			requested_mode = player.next_plan_mode().unwrap_or(default_mode);
+			let next_song = pick_song(
+				&args,
+				player.playlists(),
+				&songs,
+				selection,
+				Some(&current_song.id),
+				recent.as_mut(),
+				&mut rng,
+			)?;
+			prefetcher.request(next_song, requested_mode, Some(&current_song.id));
+			previous_song = Some(current_song.id.to_string());
+		}
+		player.wait_until_queued(1)?;
+		// The fade out, for songs without an ending. Endless songs that wrapped up early drop the rest
+		// of their part instead.
+		fade_out = if prepared.plan.endless {
+			Vec::new()
 		}
+		else {
+			sources.flatten().collect()
+		};
+		let stats = cache.stats();
+		debug!(
+			hits = stats.hits,
+			misses = stats.misses,
+			hit_rate = stats.hit_rate(),
+			used = stats.used,
+			budget = stats.budget,
+			"Segment cache"
+		);
+	}
+}
 
-		sink.sleep_until_end();
+/// Loads the songs in every songs directory, with their transitions.
+fn load_songs(args: &ArgMatches) -> Result<HashMap<String, Song>, DjError> {
+	let mut paths = Vec::new();
+	for songs_dir in args.values_of("songs-dir").unwrap() {
+		paths.extend(list_song_paths(songs_dir, args.is_present("dir-per-song"))?);
 	}
+
+	let mut songs = initialize_songs(&paths)?;
+	initialize_transitions(&mut songs);
+	Ok(songs)
+}
+
+/// Finds the song with the ID given to a subcommand.
+fn find_song<'a>(songs: &'a HashMap<String, Song>, sub_args: &ArgMatches) -> Result<&'a Song, DjError> {
+	let song_id = sub_args.value_of("SONG").unwrap();
+	songs
+		.get(song_id)
+		.ok_or_else(|| DjError::UnknownSong(song_id.to_string()))
+}
+
+fn parse_max_repeats(args: &ArgMatches) -> Result<u32, DjError> {
+	args.value_of("max-repeats")
+		.unwrap()
+		.parse()
+		.map_err(|_| DjError::MaxRepeatsInvalidValue)
+}
+
+fn parse_plan_mode(args: &ArgMatches) -> Result<PlanMode, DjError> {
+	args.value_of("plan-mode").unwrap().parse()
+}
+
+fn parse_selection(args: &ArgMatches) -> Result<Selection, DjError> {
+	args.value_of("selection").unwrap().parse()
+}
+
+/// Prints plans for one song, and how they turned out on average, without playing anything.
+fn preview_plans(args: &ArgMatches, plan_args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+	let count: usize = plan_args
+		.value_of("count")
+		.unwrap()
+		.parse()
+		.ok()
+		.filter(|count| *count > 0)
+		.ok_or(DjError::PlanCountInvalidValue)?;
+	let mut rng = match plan_args.value_of("seed") {
+		Some(seed) => StdRng::seed_from_u64(seed.parse().map_err(|_| DjError::SeedInvalidValue)?),
+		None => StdRng::from_entropy(),
+	};
+	let max_repeats = parse_max_repeats(args)?;
+	let mode = parse_plan_mode(args)?;
+	let songs = load_songs(args)?;
+	let song = find_song(&songs, plan_args)?;
+	let graph = SegmentGraph::new(song)?;
+
+	let mut durations = SegmentDurations::default();
+	let plans = (0..count)
+		.map(|_| Plan::make(song, &graph, mode, None, max_repeats, &mut durations, &mut rng))
+		.collect::<Result<Vec<_>, _>>()?;
+	if plan_args.is_present("json") {
+		for plan in &plans {
+			println!("{}", serde_json::to_string(plan)?);
+		}
+		return Ok(());
+	}
+	for (i, plan) in plans.iter().enumerate() {
+		println!("Plan {}: {}", i + 1, plan);
+	}
+	print!("{}", PlanStats::new(&plans));
+	Ok(())
+}
+
+/// Picks the song to play next from the active playlist, or the song given on the command line.
+/// With a history, songs in `recent` are avoided, and the picked song is added to it. Songs with a bridge from the
+/// `previous` song are preferred, and then songs that mix well into it if `selection` is harmonic.
+fn pick_song<'a, R: Rng + ?Sized>(
+	args: &'a ArgMatches, playlists: &Playlists, songs: &'a HashMap<String, Song>, selection: Selection,
+	previous: Option<&str>, mut recent: Option<&mut Vec<String>>, rng: &mut R,
+) -> Result<&'a str, DjError> {
+	if let Some(song_id) = args.value_of("OVERRIDE") {
+		if !songs.contains_key(song_id) {
+			return Err(DjError::UnknownSong(song_id.to_string()));
+		}
+		return Ok(song_id);
+	}
+	let mut candidates = playlists.songs(songs);
+	if candidates.is_empty() {
+		let playlist = playlists.active().unwrap_or_default().to_string();
+		warn!("{}, playing any song instead", DjError::EmptyPlaylist(playlist));
+		candidates = songs.keys().collect();
+	}
+	if let Some(recent) = &recent {
+		candidates = history::exclude_recent(&candidates, recent);
+	}
+	let bridged = bridged_songs(&candidates, songs, previous);
+	let (candidates, reason) = match (selection, previous) {
+		_ if !bridged.is_empty() => (bridged, None),
+		(Selection::Harmonic, Some(previous)) => {
+			let (candidates, reason) = selection::harmonic_candidates(&candidates, songs, &songs[previous].metadata);
+			(candidates, Some(reason))
+		}
+		_ => (candidates, None),
+	};
+	let song_id = candidates.choose(rng).copied().ok_or(DjError::NoSongs)?;
+	if let (Some(reason), Some(previous)) = (reason, previous) {
+		let metadata = &songs[song_id.as_str()].metadata;
+		let previous_metadata = &songs[previous].metadata;
+		info!(
+			song = %song_id,
+			key = metadata.key.map(|key| key.to_string()).as_deref(),
+			bpm = metadata.bpm,
+			previous,
+			previous_key = previous_metadata.key.map(|key| key.to_string()).as_deref(),
+			previous_bpm = previous_metadata.bpm,
+			%reason,
+			"Picked next song"
+		);
+	}
+	if let Some(recent) = &mut recent {
+		// Only the last few picks matter.
+		if recent.len() >= songs.len() {
+			recent.drain(..=recent.len() - songs.len());
+		}
+		recent.push(song_id.to_string());
+	}
+	Ok(song_id)
+}
+
+/// The candidates that have a bridge from the `previous` song.
+fn bridged_songs<'a>(
+	candidates: &[&'a String], songs: &HashMap<String, Song>, previous: Option<&str>,
+) -> Vec<&'a String> {
+	candidates
+		.iter()
+		.filter(|song_id| previous.is_some_and(|previous| songs[song_id.as_str()].bridge_from(previous).is_some()))
+		.copied()
+		.collect()
 }
@@ -0,0 +1,235 @@
+use crate::errors::DjError;
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+/// Optional information about a song that can't be derived from its segment files.
+///
+/// Loaded from `SONGNAME.toml` next to the segment files, or `meta.toml` inside a song archive.
+///
+/// ```toml
+/// title = "Artist - Song"
+/// bpm = 128.0
+/// key = "A minor"
+/// beats_per_bar = 4
+/// layer_order = ["drums", "bass", "melody"]
+/// tags = ["calm", "menu"]
+/// transitions = "mixed"
+/// crossfade = 0.05
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SongMetadata {
+	/// The name shown to listeners of a stream. The song's id is used when not specified.
+	pub title: Option<String>,
+	/// Tempo of the song's loops, in beats per minute.
+	pub bpm: Option<f32>,
+	/// The song's key, either a Camelot code like `8A` or a key like `A minor`, `C#m` or `Db`.
+	pub key: Option<Key>,
+	/// Number of beats in one bar. Assumed to be 4 when not specified.
+	pub beats_per_bar: Option<u32>,
+	/// The order in which a layered segment's layers fade in as the intensity rises. Layers that
+	/// aren't listed fade in last, in alphabetical order.
+	pub layer_order: Vec<String>,
+	/// Free-form tags that playlists can select songs by, eg. `calm`, `hype` or `menu`.
+	pub tags: Vec<String>,
+	/// How loops of a song with dedicated transitions get from one to another.
+	pub transitions: TransitionMode,
+	/// Seconds that one loop fades out over the next for, when mixed transitions cut between them
+	/// without a dedicated transition. Other cuts between loops aren't crossfaded.
+	pub crossfade: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransitionMode {
+	/// Loops only go to other loops through dedicated transitions.
+	#[default]
+	Dedicated,
+	/// Dedicated transitions are used where there is one, and loops cut straight to any other loop
+	/// that they don't have a dedicated transition to.
+	Mixed,
+}
+
+/// A musical key, as its position on the Camelot wheel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Key {
+	/// The hour on the wheel, from 1 to 12.
+	number: u8,
+	minor: bool,
+}
+
+impl Key {
+	/// Whether a song in this key mixes well into a song in the other: the same key, a key next to
+	/// it on the wheel, or its relative major or minor.
+	pub fn is_compatible(&self, other: &Key) -> bool {
+		let distance = (i32::from(self.number) - i32::from(other.number)).rem_euclid(12);
+		if self.minor == other.minor {
+			distance == 0 || distance == 1 || distance == 11
+		}
+		else {
+			distance == 0
+		}
+	}
+
+	/// The key with a tonic `pitch` semitones above C.
+	fn from_pitch(pitch: i32, minor: bool) -> Key {
+		// Going up a fifth moves one hour clockwise, and minor keys share an hour with their relative
+		// major, 3 semitones above.
+		let major_pitch = if minor { pitch + 3 } else { pitch };
+		let number = (major_pitch * 7 + 8).rem_euclid(12);
+		Key {
+			number: if number == 0 { 12 } else { number as u8 },
+			minor,
+		}
+	}
+}
+
+impl FromStr for Key {
+	type Err = DjError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || DjError::KeyInvalidValue(s.to_string());
+		let s = s.trim();
+		// Camelot codes, like 8A for A minor.
+		if let Some(number) = s.get(..s.len().saturating_sub(1)).and_then(|n| n.parse::<u8>().ok()) {
+			let minor = match &s[s.len() - 1..] {
+				"A" | "a" => true,
+				"B" | "b" => false,
+				_ => return Err(invalid()),
+			};
+			if !(1..=12).contains(&number) {
+				return Err(invalid());
+			}
+			return Ok(Key { number, minor });
+		}
+		let mut chars = s.chars();
+		let mut pitch = match chars.next().map(|c| c.to_ascii_uppercase()) {
+			Some('C') => 0,
+			Some('D') => 2,
+			Some('E') => 4,
+			Some('F') => 5,
+			Some('G') => 7,
+			Some('A') => 9,
+			Some('B') => 11,
+			_ => return Err(invalid()),
+		};
+		let mut rest = chars.as_str();
+		if let Some(accidental) = rest.chars().next().filter(|c| "#♯b♭".contains(*c)) {
+			pitch += if "#♯".contains(accidental) { 1 } else { -1 };
+			rest = &rest[accidental.len_utf8()..];
+		}
+		let minor = match rest.trim().to_lowercase().as_str() {
+			"" | "maj" | "major" => false,
+			"m" | "min" | "minor" => true,
+			_ => return Err(invalid()),
+		};
+		Ok(Key::from_pitch(pitch, minor))
+	}
+}
+
+impl TryFrom<String> for Key {
+	type Error = DjError;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl fmt::Display for Key {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}{}", self.number, if self.minor { "A" } else { "B" })
+	}
+}
+
+impl SongMetadata {
+	pub fn parse(song_id: &str, text: &str) -> Result<SongMetadata, DjError> {
+		let metadata: SongMetadata =
+			toml::from_str(text).map_err(|e| DjError::InvalidSongMetadata(song_id.to_string(), e.to_string()))?;
+		if metadata.bpm.is_some_and(|bpm| !(bpm > 0.0 && bpm.is_finite())) {
+			return Err(DjError::InvalidSongMetadata(
+				song_id.to_string(),
+				"bpm must be a positive number".to_string(),
+			));
+		}
+		Ok(metadata)
+	}
+
+	pub fn beats_per_bar(&self) -> u32 {
+		self.beats_per_bar.unwrap_or(4)
+	}
+}
+
+#[cfg(test)]
+mod test_song_metadata {
+	use super::*;
+
+	#[test]
+	fn test_parse_metadata() {
+		let meta = SongMetadata::parse(
+			"song",
+			"title = \"Song\"\nbpm = 128.0\nbeats_per_bar = 3\nlayer_order = [\"drums\", \"pads\"]\ntags = [\"calm\"]\ntransitions = \"mixed\"\ncrossfade = 0.05\n",
+		)
+		.unwrap();
+		assert_eq!(meta.title.as_deref(), Some("Song"));
+		assert_eq!(meta.bpm, Some(128.0));
+		assert_eq!(meta.beats_per_bar(), 3);
+		assert_eq!(meta.layer_order, vec!["drums".to_string(), "pads".to_string()]);
+		assert_eq!(meta.tags, vec!["calm".to_string()]);
+		assert_eq!(meta.transitions, TransitionMode::Mixed);
+		assert_eq!(meta.crossfade, Some(0.05));
+
+		let meta = SongMetadata::parse("song", "").unwrap();
+		assert_eq!(meta, SongMetadata::default());
+		assert_eq!(meta.beats_per_bar(), 4);
+		assert_eq!(meta.transitions, TransitionMode::Dedicated);
+	}
+
+	#[test]
+	fn test_parse_key() {
+		let key = |s: &str| s.parse::<Key>().map(|key| key.to_string());
+		assert_eq!(key("8A"), Ok("8A".to_string()));
+		assert_eq!(key("12b"), Ok("12B".to_string()));
+		assert_eq!(key("A minor"), Ok("8A".to_string()));
+		assert_eq!(key("Am"), Ok("8A".to_string()));
+		assert_eq!(key("C"), Ok("8B".to_string()));
+		assert_eq!(key("C major"), Ok("8B".to_string()));
+		assert_eq!(key("G#m"), Ok("1A".to_string()));
+		assert_eq!(key("Abm"), Ok("1A".to_string()));
+		assert_eq!(key("Db"), Ok("3B".to_string()));
+		assert_eq!(key("B"), Ok("1B".to_string()));
+		assert_eq!(key("Bbm"), Ok("3A".to_string()));
+		assert_eq!(key("F# min"), Ok("11A".to_string()));
+		assert_eq!(key("13A"), Err(DjError::KeyInvalidValue("13A".to_string())));
+		assert_eq!(key("H"), Err(DjError::KeyInvalidValue("H".to_string())));
+		assert_eq!(key("Am7"), Err(DjError::KeyInvalidValue("Am7".to_string())));
+
+		let meta = SongMetadata::parse("song", "key = \"Em\"").unwrap();
+		assert_eq!(meta.key, Some("9A".parse().unwrap()));
+		assert!(SongMetadata::parse("song", "key = \"X\"").is_err());
+	}
+
+	#[test]
+	fn test_compatible_keys() {
+		let key = |s: &str| s.parse::<Key>().unwrap();
+		assert!(key("8A").is_compatible(&key("8A")));
+		assert!(key("8A").is_compatible(&key("7A")));
+		assert!(key("8A").is_compatible(&key("9A")));
+		assert!(key("8A").is_compatible(&key("8B")));
+		assert!(key("12B").is_compatible(&key("1B")));
+		assert!(!key("8A").is_compatible(&key("9B")));
+		assert!(!key("8A").is_compatible(&key("10A")));
+	}
+
+	#[test]
+	fn test_parse_metadata_rejects_unknown_keys() {
+		assert!(SongMetadata::parse("song", "bmp = 120").is_err());
+	}
+
+	#[test]
+	fn test_parse_metadata_rejects_bad_bpm() {
+		assert!(SongMetadata::parse("song", "bpm = 0.0").is_err());
+		assert!(SongMetadata::parse("song", "bpm = -120.0").is_err());
+		assert!(SongMetadata::parse("song", "bpm = nan").is_err());
+	}
+}
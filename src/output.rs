@@ -0,0 +1,490 @@
+#[cfg(unix)]
+use std::{
+	ffi::CString,
+	os::unix::{ffi::OsStrExt, io::FromRawFd},
+};
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, BufWriter, Write},
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{Arc, Mutex},
+	thread,
+	time::{Duration, Instant},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rodio::{
+	dynamic_mixer::{self, DynamicMixer, DynamicMixerController},
+	Sink,
+};
+use tracing::{info, warn};
+
+use crate::{
+	device_output::DeviceOutput,
+	errors::DjError,
+	http_stream::{self, HttpStream, StreamTitle},
+	position_source::PlaybackPosition,
+	resample::OutputFormat,
+};
+
+/// How much audio is written to PCM outputs at a time.
+const CHUNK_DURATION: Duration = Duration::from_millis(20);
+/// How far ahead of real time PCM outputs are allowed to get, so readers don't run dry.
+const MAX_LEAD: Duration = Duration::from_millis(100);
+
+/// Where the music is played, as given with `--output`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputTarget {
+	/// An audio device, the default one unless another is chosen with `--device`.
+	Device,
+	/// Raw interleaved PCM written to stdout. Messages are printed to stderr instead.
+	#[cfg(unix)]
+	Stdout,
+	/// Raw interleaved PCM written to a named pipe, which is created if it doesn't exist.
+	#[cfg(unix)]
+	Fifo(PathBuf),
+	/// WAV files, starting a new file every `--wav-roll`.
+	Wav(PathBuf),
+	/// An Ogg/Vorbis stream served over HTTP, like an Icecast mount point.
+	Http { addr: String, mount: String },
+}
+
+impl FromStr for OutputTarget {
+	type Err = DjError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.split_once(':') {
+			None if s == "device" => Ok(OutputTarget::Device),
+			#[cfg(unix)]
+			None if s == "stdout" => Ok(OutputTarget::Stdout),
+			#[cfg(unix)]
+			Some(("fifo", path)) if !path.is_empty() => Ok(OutputTarget::Fifo(PathBuf::from(path))),
+			// Swapping out stdout and creating named pipes need Unix.
+			#[cfg(not(unix))]
+			None if s == "stdout" => Err(DjError::UnsupportedOutput(s.to_string())),
+			#[cfg(not(unix))]
+			Some(("fifo", path)) if !path.is_empty() => Err(DjError::UnsupportedOutput(s.to_string())),
+			Some(("wav", path)) if !path.is_empty() => Ok(OutputTarget::Wav(PathBuf::from(path))),
+			Some(("http", location)) => {
+				let (addr, mount) = match location.find('/') {
+					Some(i) => location.split_at(i),
+					None => (location, http_stream::DEFAULT_MOUNT),
+				};
+				if addr.is_empty() || mount.len() < 2 {
+					return Err(DjError::InvalidOutput(s.to_string()));
+				}
+				Ok(OutputTarget::Http {
+					addr: addr.to_string(),
+					mount: mount.to_string(),
+				})
+			}
+			_ => Err(DjError::InvalidOutput(s.to_string())),
+		}
+	}
+}
+
+/// Creates the sinks that the music and stingers are played through. Everything played through
+/// them is mixed together, and played on a device or written out by a background thread.
+pub struct Output {
+	mixer: Arc<DynamicMixerController<f32>>,
+	device: Option<DeviceOutput>,
+	/// Tells stream listeners what is playing.
+	title: Option<Arc<StreamTitle>>,
+	failure: WriterFailure,
+}
+
+/// Where the background writer of a PCM output leaves the error that stopped it.
+#[derive(Debug, Clone, Default)]
+pub struct WriterFailure(Arc<Mutex<Option<DjError>>>);
+
+impl WriterFailure {
+	fn set(&self, e: DjError) {
+		*self.0.lock().unwrap() = Some(e);
+	}
+
+	/// Returns the error that stopped the writer, if it has stopped.
+	pub fn check(&self) -> Result<(), DjError> {
+		match self.0.lock().unwrap().take() {
+			Some(e) => Err(e),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Output {
+	/// Opens the output. `device` picks the audio device to play on, by name or by its number in
+	/// [`list_devices`](crate::device_output::list_devices), and `fallback_devices` are played on if
+	/// it goes away.
+	pub fn open(
+		target: &OutputTarget, device: Option<&str>, fallback_devices: &[String], format: OutputFormat,
+		wav_roll: Duration,
+	) -> Result<Output, DjError> {
+		let (controller, mixer) = dynamic_mixer::mixer(format.channels, format.sample_rate);
+		let mut title = None;
+		let failure = WriterFailure::default();
+		let writer: Box<dyn PcmWriter> = match target {
+			OutputTarget::Device => {
+				return Ok(Output {
+					mixer: controller,
+					device: Some(DeviceOutput::open(mixer, format, device, fallback_devices)?),
+					title,
+					failure,
+				});
+			}
+			#[cfg(unix)]
+			OutputTarget::Stdout => Box::new(take_stdout()?),
+			#[cfg(unix)]
+			OutputTarget::Fifo(path) => Box::new(Fifo::create(path)?),
+			OutputTarget::Wav(path) => Box::new(RollingWav::new(path, format, wav_roll)),
+			OutputTarget::Http { addr, mount } => {
+				let stream_title = StreamTitle::new();
+				title = Some(stream_title.clone());
+				Box::new(HttpStream::start(addr, mount, format, stream_title)?)
+			}
+		};
+		let writer_failure = failure.clone();
+		thread::spawn(move || {
+			if let Err(e) = write_pcm(mixer, writer, format) {
+				writer_failure.set(DjError::OutputError(e.to_string()));
+			}
+		});
+		Ok(Output {
+			mixer: controller,
+			device: None,
+			title,
+			failure,
+		})
+	}
+
+	/// Creates a new sink. Everything played through the output's sinks is heard together.
+	pub fn new_sink(&self) -> Sink {
+		let (sink, queue) = Sink::new_idle();
+		self.mixer.add(queue);
+		sink
+	}
+
+	/// Where to tell listeners what is playing, for outputs that have listeners.
+	pub fn stream_title(&self) -> Option<Arc<StreamTitle>> {
+		self.title.clone()
+	}
+
+	/// Where the error that stopped writing audio is left, for outputs that write it on a
+	/// background thread.
+	pub fn failure(&self) -> WriterFailure {
+		self.failure.clone()
+	}
+
+	/// If the audio device is lost, ends the source that `position` tracks once it's back, so that
+	/// the music doesn't pick up in the middle of a segment.
+	pub fn interrupt_on_reconnect(&self, position: Arc<PlaybackPosition>) {
+		if let Some(device) = &self.device {
+			device.interrupt_on_reconnect(position);
+		}
+	}
+}
+
+/// Somewhere that interleaved 16-bit PCM can be written to.
+pub trait PcmWriter: Send {
+	fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+}
+
+impl PcmWriter for File {
+	fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+		self.write_all(&pcm_bytes(samples))
+	}
+}
+
+fn pcm_bytes(samples: &[i16]) -> Vec<u8> {
+	samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+/// Takes over stdout for audio, and points the old stdout at stderr so that messages don't end up
+/// in the audio.
+#[cfg(unix)]
+fn take_stdout() -> Result<File, DjError> {
+	io::stdout().flush().map_err(|e| DjError::OutputError(e.to_string()))?;
+	// SAFETY: dup and dup2 only operate on file descriptors, and the duplicated descriptor is
+	// owned by the returned file from here on.
+	unsafe {
+		let fd = libc::dup(libc::STDOUT_FILENO);
+		if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+			return Err(DjError::OutputError(io::Error::last_os_error().to_string()));
+		}
+		Ok(File::from_raw_fd(fd))
+	}
+}
+
+/// A named pipe. If the reader goes away, writing pauses until a new reader opens the pipe.
+#[cfg(unix)]
+struct Fifo {
+	path: PathBuf,
+	file: Option<File>,
+}
+
+#[cfg(unix)]
+impl Fifo {
+	fn create(path: &Path) -> Result<Fifo, DjError> {
+		if !path.exists() {
+			let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| DjError::OutputError(e.to_string()))?;
+			// SAFETY: c_path is a valid, nul terminated string.
+			if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+				return Err(DjError::OutputError(format!(
+					"unable to create fifo '{}': {}",
+					path.display(),
+					io::Error::last_os_error()
+				)));
+			}
+		}
+		Ok(Fifo {
+			path: path.to_path_buf(),
+			file: None,
+		})
+	}
+}
+
+#[cfg(unix)]
+impl PcmWriter for Fifo {
+	fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+		loop {
+			if self.file.is_none() {
+				info!(path = %self.path.display(), "Waiting for a reader to open the fifo");
+				// Blocks until there is a reader.
+				self.file = Some(File::create(&self.path)?);
+			}
+			match self.file.as_mut().unwrap().write_all(&pcm_bytes(samples)) {
+				Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+					warn!(path = %self.path.display(), "The reader of the fifo went away");
+					self.file = None;
+				}
+				result => return result,
+			}
+		}
+	}
+}
+
+/// WAV files named after `path` with a number added, eg. `music-0001.wav`, switching to the next
+/// file once `roll` worth of audio has been written. Numbering carries on from the files already
+/// there, so that they aren't overwritten.
+struct RollingWav {
+	path: PathBuf,
+	spec: WavSpec,
+	/// Number of frames to write to each file.
+	frames_per_file: u64,
+	writer: Option<WavWriter<BufWriter<File>>>,
+	frames_written: u64,
+	index: u32,
+}
+
+impl RollingWav {
+	fn new(path: &Path, format: OutputFormat, roll: Duration) -> RollingWav {
+		let mut wav = RollingWav {
+			path: path.to_path_buf(),
+			spec: WavSpec {
+				channels: format.channels,
+				sample_rate: format.sample_rate,
+				bits_per_sample: 16,
+				sample_format: SampleFormat::Int,
+			},
+			frames_per_file: (roll.as_secs_f64() * format.sample_rate as f64).max(1.0) as u64,
+			writer: None,
+			frames_written: 0,
+			index: 0,
+		};
+		wav.index = wav.last_index();
+		wav
+	}
+
+	/// The highest number of the files that have already been written.
+	fn last_index(&self) -> u32 {
+		let prefix = format!("{}-", self.path.file_stem().unwrap_or_default().to_string_lossy());
+		let dir = match self.path.parent() {
+			Some(dir) if !dir.as_os_str().is_empty() => dir,
+			_ => Path::new("."),
+		};
+		let entries = match fs::read_dir(dir) {
+			Ok(entries) => entries,
+			Err(_) => return 0,
+		};
+		entries
+			.filter_map(|entry| {
+				let name = entry.ok()?.file_name().into_string().ok()?;
+				name.strip_prefix(&prefix)?.strip_suffix(".wav")?.parse().ok()
+			})
+			.max()
+			.unwrap_or(0)
+	}
+
+	fn file_path(&self, index: u32) -> PathBuf {
+		let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+		self.path.with_file_name(format!("{}-{:04}.wav", stem, index))
+	}
+
+	fn next_file(&mut self) -> io::Result<()> {
+		if let Some(writer) = self.writer.take() {
+			writer.finalize().map_err(hound_error)?;
+		}
+		self.index += 1;
+		let path = self.file_path(self.index);
+		info!(path = %path.display(), "Writing audio to file");
+		let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+		self.writer = Some(WavWriter::new(BufWriter::new(file), self.spec).map_err(hound_error)?);
+		self.frames_written = 0;
+		Ok(())
+	}
+}
+
+impl PcmWriter for RollingWav {
+	fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+		for frame in samples.chunks(self.spec.channels as usize) {
+			if self.writer.is_none() || self.frames_written >= self.frames_per_file {
+				self.next_file()?;
+			}
+			let writer = self.writer.as_mut().unwrap();
+			for sample in frame {
+				writer.write_sample(*sample).map_err(hound_error)?;
+			}
+			self.frames_written += 1;
+		}
+		// Keeps the header up to date, so the file can be read while it's being written.
+		self.writer.as_mut().unwrap().flush().map_err(hound_error)
+	}
+}
+
+fn hound_error(e: hound::Error) -> io::Error {
+	match e {
+		hound::Error::IoError(e) => e,
+		e => io::Error::other(e.to_string()),
+	}
+}
+
+/// Writes everything played to `writer`, at the speed it would be played on a device.
+fn write_pcm(mut mixer: DynamicMixer<f32>, mut writer: Box<dyn PcmWriter>, format: OutputFormat) -> io::Result<()> {
+	let chunk_frames = (format.sample_rate as f64 * CHUNK_DURATION.as_secs_f64()) as usize;
+	let mut chunk = Vec::with_capacity(chunk_frames * format.channels as usize);
+	let mut pacer = Pacer::new(format.sample_rate);
+	loop {
+		chunk.clear();
+		for _ in 0..chunk_frames * format.channels as usize {
+			// The mixer is empty before anything has been played.
+			let sample = mixer.next().unwrap_or(0.0);
+			chunk.push((sample * 32768.0).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+		}
+		writer.write(&chunk)?;
+		thread::sleep(pacer.wrote(chunk_frames as u64, Instant::now()));
+	}
+}
+
+/// Keeps track of how far ahead of real time a PCM output is.
+struct Pacer {
+	sample_rate: u32,
+	/// When the audio written so far started playing, as far as the reader is concerned.
+	started: Instant,
+	frames: u64,
+}
+
+impl Pacer {
+	fn new(sample_rate: u32) -> Pacer {
+		Pacer {
+			sample_rate,
+			started: Instant::now(),
+			frames: 0,
+		}
+	}
+
+	/// Counts frames that have just been written, and returns how long to wait before writing
+	/// more. After a write blocks for longer than the audio written lasts, eg. while a pipe has no
+	/// reader, pacing starts over from `now` instead of catching up all at once.
+	fn wrote(&mut self, frames: u64, now: Instant) -> Duration {
+		self.frames += frames;
+		let written = Duration::from_secs_f64(self.frames as f64 / self.sample_rate as f64);
+		let elapsed = now.saturating_duration_since(self.started);
+		if written < elapsed {
+			self.started = now;
+			self.frames = 0;
+			return Duration::from_secs(0);
+		}
+		(written - elapsed).saturating_sub(MAX_LEAD)
+	}
+}
+
+#[cfg(test)]
+mod test_output {
+	use super::*;
+
+	#[test]
+	fn test_pacing_after_stall() {
+		let mut pacer = Pacer::new(1000);
+		let start = pacer.started;
+		assert_eq!(pacer.wrote(1000, start), Duration::from_secs(1) - MAX_LEAD);
+		// The write blocked for 10 seconds, so the reader has missed 9 seconds of audio.
+		let stalled = start + Duration::from_secs(11);
+		assert_eq!(pacer.wrote(1000, stalled), Duration::from_secs(0));
+		assert_eq!(pacer.wrote(1000, stalled), Duration::from_secs(1) - MAX_LEAD);
+	}
+
+	#[test]
+	fn test_parse_output_target() {
+		assert_eq!("device".parse(), Ok(OutputTarget::Device));
+		#[cfg(unix)]
+		assert_eq!("stdout".parse(), Ok(OutputTarget::Stdout));
+		#[cfg(unix)]
+		assert_eq!(
+			"fifo:/tmp/music".parse(),
+			Ok(OutputTarget::Fifo(PathBuf::from("/tmp/music")))
+		);
+		#[cfg(not(unix))]
+		assert_eq!(
+			"fifo:/tmp/music".parse::<OutputTarget>(),
+			Err(DjError::UnsupportedOutput("fifo:/tmp/music".to_string()))
+		);
+		assert_eq!(
+			"wav:recordings/music.wav".parse(),
+			Ok(OutputTarget::Wav(PathBuf::from("recordings/music.wav")))
+		);
+		assert_eq!(
+			"http:0.0.0.0:8000/radio.ogg".parse(),
+			Ok(OutputTarget::Http {
+				addr: "0.0.0.0:8000".to_string(),
+				mount: "/radio.ogg".to_string(),
+			})
+		);
+		assert_eq!(
+			"http:localhost:8000".parse(),
+			Ok(OutputTarget::Http {
+				addr: "localhost:8000".to_string(),
+				mount: "/stream.ogg".to_string(),
+			})
+		);
+		assert!("wav:".parse::<OutputTarget>().is_err());
+		assert!("http:/stream.ogg".parse::<OutputTarget>().is_err());
+		assert!("speakers".parse::<OutputTarget>().is_err());
+	}
+
+	#[test]
+	fn test_rolling_wav() {
+		let dir = std::env::temp_dir().join(format!("autodj-test-rolling-wav-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let format = OutputFormat {
+			channels: 2,
+			sample_rate: 10,
+		};
+		let mut wav = RollingWav::new(&dir.join("music.wav"), format, Duration::from_secs(1));
+		wav.write(&[1; 30]).unwrap();
+		wav.next_file().unwrap();
+
+		let first = hound::WavReader::open(dir.join("music-0001.wav")).unwrap();
+		assert_eq!(first.spec().channels, 2);
+		assert_eq!(first.len(), 20);
+		let second = hound::WavReader::open(dir.join("music-0002.wav")).unwrap();
+		assert_eq!(second.len(), 10);
+
+		// Starting again carries on after the files that are already there.
+		std::fs::write(dir.join("music-old.wav"), "").unwrap();
+		let mut wav = RollingWav::new(&dir.join("music.wav"), format, Duration::from_secs(1));
+		wav.write(&[1; 2]).unwrap();
+		assert_eq!(wav.index, 4);
+		assert_eq!(hound::WavReader::open(dir.join("music-0001.wav")).unwrap().len(), 20);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,738 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt, fs,
+	str::FromStr,
+	time::Duration,
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	errors::DjError,
+	metadata::TransitionMode,
+	planner::{SegmentGraph, SongGraphs},
+	Song, SongSegment,
+};
+
+/// How long songs without an ending take to fade out.
+pub const FADE_OUT_DURATION: Duration = Duration::from_secs(8);
+/// The fewest times a loop is played.
+const MIN_REPEATS: u32 = 5;
+/// How far start offsets can be from the durations before them, to allow for rounding.
+const OFFSET_TOLERANCE: f64 = 0.001;
+
+/// How much of a song a plan plays.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlanMode {
+	/// Wanders between the song's loops, repeating each of them several times.
+	#[default]
+	Full,
+	/// The shortest way through the song, playing each loop once, for short breaks.
+	Bumper,
+	/// Wanders between the song's loops until told to wrap up, never heading for an ending. The
+	/// plan is made a part at a time.
+	Endless,
+}
+
+impl FromStr for PlanMode {
+	type Err = DjError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"full" => Ok(PlanMode::Full),
+			"bumper" => Ok(PlanMode::Bumper),
+			"endless" => Ok(PlanMode::Endless),
+			_ => Err(DjError::PlanModeInvalidValue),
+		}
+	}
+}
+
+/// Everything that will be played for a song, in order, and when.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+	pub song: String,
+	/// The segment that this plan carries on after, for the parts of an endless song's plan that
+	/// come after the first.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub after: Option<String>,
+	pub entries: Vec<PlanEntry>,
+	/// Whether the song carries on after this plan instead of finishing, for endless songs.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub endless: bool,
+	/// How long the last segment is played again for while it fades out, in seconds. Only songs
+	/// without an ending fade out.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub fade_out: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanEntry {
+	pub id: String,
+	/// How many times the segment is played in a row, for loops.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub repeat_count: Option<u32>,
+	/// When the entry starts, in seconds from the start of the song.
+	pub start: f64,
+	/// How long the entry plays for, including its repeats, in seconds.
+	pub duration: f64,
+	/// Seconds that the entry fades in over the end of the one before it, when cutting straight
+	/// from one loop to another.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub crossfade: Option<f64>,
+}
+
+impl Plan {
+	/// Makes a randomized plan for playing the song, beginning on the bridge from `previous_song` if
+	/// the song has one. In full and endless plans, loops are played at least `MIN_REPEATS` times,
+	/// and fewer than `max_repeats` times. Bumpers play each loop once.
+	pub fn make<R: Rng + ?Sized>(
+		song: &Song, graph: &SegmentGraph, mode: PlanMode, previous_song: Option<&str>, max_repeats: u32,
+		durations: &mut SegmentDurations, rng: &mut R,
+	) -> Result<Plan, DjError> {
+		let segments = match mode {
+			PlanMode::Full => graph.plan(previous_song, rng),
+			PlanMode::Bumper => graph.shortest_plan(previous_song, rng),
+			PlanMode::Endless => graph
+				.wander(None, previous_song, rng)
+				.ok_or_else(|| DjError::NotEndless(song.id.to_string()))?,
+		};
+		Plan::from_segments(song, segments, mode, None, max_repeats, durations, rng)
+	}
+
+	/// Makes the next part of an endless song's plan, carrying on after the segment `after`. It
+	/// wanders between the song's loops like the first part, unless `wrap_up` is set, in which case
+	/// it takes the shortest way to an ending, playing each loop once.
+	pub fn carry_on<R: Rng + ?Sized>(
+		song: &Song, graph: &SegmentGraph, after: &str, wrap_up: bool, max_repeats: u32,
+		durations: &mut SegmentDurations, rng: &mut R,
+	) -> Result<Plan, DjError> {
+		let (segments, mode) = if wrap_up {
+			(graph.finish_after(after, rng), PlanMode::Bumper)
+		}
+		else {
+			(graph.wander(Some(after), None, rng), PlanMode::Endless)
+		};
+		let segments = segments.ok_or_else(|| DjError::NotEndless(song.id.to_string()))?;
+		Plan::from_segments(song, segments, mode, Some(after), max_repeats, durations, rng)
+	}
+
+	fn from_segments<R: Rng + ?Sized>(
+		song: &Song, segments: Vec<SongSegment>, mode: PlanMode, after: Option<&str>, max_repeats: u32,
+		durations: &mut SegmentDurations, rng: &mut R,
+	) -> Result<Plan, DjError> {
+		let mut entries: Vec<PlanEntry> = Vec::new();
+		let mut end = 0.0;
+		// The segment before, and how long one play of it lasts.
+		let mut previous: Option<(SongSegment, f64)> = None;
+		for segment in segments {
+			let repeat_count = match mode {
+				_ if !segment.is_loop() => None,
+				PlanMode::Full | PlanMode::Endless => Some(rng.gen_range(MIN_REPEATS, max_repeats)),
+				PlanMode::Bumper => Some(1),
+			};
+			let single = durations.get(song, &segment)?.as_secs_f64();
+			let duration = single * repeat_count.unwrap_or(1) as f64;
+			if let (Some(count), Some((previous, _))) = (repeat_count, &previous) {
+				if previous.id == segment.id {
+					// A loop with nowhere else to go keeps repeating.
+					let last = entries.last_mut().unwrap();
+					last.repeat_count = last.repeat_count.map(|repeats| repeats + count);
+					last.duration += duration;
+					end += duration;
+					continue;
+				}
+			}
+			let crossfade = match (&previous, cut_crossfade(song)) {
+				(Some((previous, previous_single)), Some(crossfade)) if previous.is_loop() && segment.is_loop() => {
+					Some(crossfade.min(*previous_single).min(single))
+				}
+				_ => None,
+			};
+			let start = end - crossfade.unwrap_or(0.0);
+			entries.push(PlanEntry {
+				id: segment.id.to_string(),
+				repeat_count,
+				start,
+				duration,
+				crossfade,
+			});
+			end = start + duration;
+			previous = Some((segment, single));
+		}
+		let endless = mode == PlanMode::Endless;
+		Ok(Plan {
+			song: song.id.to_string(),
+			after: after.map(String::from),
+			entries,
+			endless,
+			fade_out: if song.has_end || endless {
+				None
+			}
+			else {
+				Some(FADE_OUT_DURATION.as_secs_f64())
+			},
+		})
+	}
+
+	/// How long the whole song plays for, including its fade out.
+	pub fn duration(&self) -> f64 {
+		let end = self.entries.last().map_or(0.0, |entry| entry.start + entry.duration);
+		end + self.fade_out.unwrap_or(0.0)
+	}
+
+	/// Reads a plan saved as JSON, eg. by `plan --json`, and checks that it can be played.
+	pub fn load(path: &str, songs: &HashMap<String, Song>, graphs: &SongGraphs) -> Result<Plan, DjError> {
+		let json = fs::read_to_string(path).map_err(|e| DjError::UnreadableFile(path.to_string(), e.to_string()))?;
+		let plan: Plan =
+			serde_json::from_str(&json).map_err(|e| DjError::UnreadableFile(path.to_string(), e.to_string()))?;
+		match (songs.get(&plan.song), graphs.get(&plan.song)) {
+			(Some(song), Some(graph)) => plan.validate(song, graph)?,
+			_ => return Err(DjError::UnknownSong(plan.song)),
+		}
+		Ok(plan)
+	}
+
+	/// Checks that the plan can be played as it is, eg. after being read from a file.
+	pub fn validate(&self, song: &Song, graph: &SegmentGraph) -> Result<(), DjError> {
+		let invalid = |reason: String| Err(DjError::InvalidPlan(self.song.to_string(), reason));
+		if self.song != song.id {
+			return invalid(format!("it is for another song, '{}'", song.id));
+		}
+		let after = match &self.after {
+			Some(after) => match song.segments.get(after) {
+				Some(segment) => Some(segment),
+				None => return invalid(format!("the song has no segment '{}'", after)),
+			},
+			None => None,
+		};
+		match (after, self.entries.first()) {
+			(None, Some(entry))
+				if song
+					.segments
+					.get(&entry.id)
+					.is_some_and(|segment| segment.is_start() || segment.is_bridge()) => {}
+			(Some(after), Some(entry))
+				if (after.is_loop() && after.id == entry.id) || after.allowed_transitions.contains(&entry.id) => {}
+			(None, _) => return invalid("it doesn't begin with a start segment".to_string()),
+			(Some(after), _) => return invalid(format!("it can't carry on after '{}'", after.id)),
+		}
+		let mut end = 0.0;
+		let mut previous: Option<(&SongSegment, &PlanEntry)> = None;
+		for entry in &self.entries {
+			let segment = match song.segments.get(&entry.id) {
+				Some(segment) => segment,
+				None => return invalid(format!("the song has no segment '{}'", entry.id)),
+			};
+			if let Some((previous, _)) = previous {
+				if !previous.allowed_transitions.contains(&entry.id) {
+					return invalid(format!("'{}' can't transition to '{}'", previous.id, entry.id));
+				}
+			}
+			if let Some(crossfade) = entry.crossfade {
+				match previous {
+					Some((previous_segment, previous_entry))
+						if cut_crossfade(song).is_some()
+							&& previous_segment.is_loop()
+							&& segment.is_loop() && (0.0..=previous_entry.duration.min(entry.duration))
+							.contains(&crossfade) => {}
+					_ => return invalid(format!("'{}' can't crossfade with the segment before it", entry.id)),
+				}
+			}
+			match entry.repeat_count {
+				Some(0) => return invalid(format!("'{}' is repeated 0 times", entry.id)),
+				Some(_) if !segment.is_loop() => return invalid(format!("'{}' isn't a loop", entry.id)),
+				_ => {}
+			}
+			let start = end - entry.crossfade.unwrap_or(0.0);
+			if (entry.start - start).abs() > OFFSET_TOLERANCE || entry.duration < 0.0 {
+				return invalid(format!("'{}' doesn't start where the segment before it ends", entry.id));
+			}
+			end = entry.start + entry.duration;
+			previous = Some((segment, entry));
+		}
+		let last = &self.entries.last().unwrap().id;
+		if self.endless {
+			if !graph.carries_on_from(last) {
+				return invalid(format!("the song can't carry on after '{}'", last));
+			}
+			if self.fade_out.is_some() {
+				return invalid("endless plans don't fade out".to_string());
+			}
+			return Ok(());
+		}
+		if !graph.finishes_on(last) {
+			return invalid(format!("the song can't finish on '{}'", last));
+		}
+		if self.fade_out.is_some() == song.has_end {
+			return invalid("only songs without an ending fade out".to_string());
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for Plan {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match &self.after {
+			Some(after) => writeln!(
+				f,
+				"{}, after {} ({})",
+				self.song,
+				after,
+				format_seconds(self.duration())
+			)?,
+			None => writeln!(f, "{} ({})", self.song, format_seconds(self.duration()))?,
+		}
+		for entry in &self.entries {
+			write!(f, "  {}  {}", format_seconds(entry.start), entry.id)?;
+			if let Some(count) = entry.repeat_count {
+				write!(f, " x{}", count)?;
+			}
+			match entry.crossfade {
+				Some(crossfade) => writeln!(
+					f,
+					" ({}, crossfading in over {:.2}s)",
+					format_seconds(entry.duration),
+					crossfade
+				)?,
+				None => writeln!(f, " ({})", format_seconds(entry.duration))?,
+			}
+		}
+		if let Some(fade_out) = self.fade_out {
+			let end = self.duration() - fade_out;
+			writeln!(f, "  {}  fade out ({})", format_seconds(end), format_seconds(fade_out))?;
+		}
+		if self.endless {
+			writeln!(f, "  {}  carries on", format_seconds(self.duration()))?;
+		}
+		Ok(())
+	}
+}
+
+/// How a number of plans for the same song turned out, to see how the song's segments get used.
+#[derive(Debug, Default, PartialEq)]
+pub struct PlanStats {
+	pub plans: usize,
+	/// Segments in every plan, added up.
+	pub segments: usize,
+	/// Seconds of every plan, added up.
+	pub duration: f64,
+	/// How many plans visit each segment.
+	pub plans_visiting: BTreeMap<String, usize>,
+	/// How many times each segment is visited, over every plan.
+	pub visits: BTreeMap<String, usize>,
+	/// How many plans finish on each segment.
+	pub endings: BTreeMap<String, usize>,
+}
+
+impl PlanStats {
+	pub fn new(plans: &[Plan]) -> PlanStats {
+		let mut stats = PlanStats {
+			plans: plans.len(),
+			..PlanStats::default()
+		};
+		for plan in plans {
+			stats.segments += plan.entries.len();
+			stats.duration += plan.duration();
+			let mut visited = Vec::new();
+			for entry in &plan.entries {
+				*stats.visits.entry(entry.id.to_string()).or_default() += 1;
+				if !visited.contains(&&entry.id) {
+					visited.push(&entry.id);
+					*stats.plans_visiting.entry(entry.id.to_string()).or_default() += 1;
+				}
+			}
+			if let Some(last) = plan.entries.last() {
+				*stats.endings.entry(last.id.to_string()).or_default() += 1;
+			}
+		}
+		stats
+	}
+}
+
+impl fmt::Display for PlanStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.plans == 0 {
+			return writeln!(f, "No plans were made.");
+		}
+		let plans = self.plans as f64;
+		let percent = |count: usize| count as f64 * 100.0 / plans;
+		writeln!(f, "Over {} plans:", self.plans)?;
+		writeln!(
+			f,
+			"  Average length: {:.1} segments, {}",
+			self.segments as f64 / plans,
+			format_seconds(self.duration / plans)
+		)?;
+		writeln!(f, "  Segments:")?;
+		for (segment_id, visits) in &self.visits {
+			writeln!(
+				f,
+				"    {}: in {:.0}% of plans, {:.1} times per plan",
+				segment_id,
+				percent(self.plans_visiting[segment_id]),
+				*visits as f64 / plans
+			)?;
+		}
+		writeln!(f, "  Endings:")?;
+		for (segment_id, count) in &self.endings {
+			writeln!(f, "    {}: {:.0}% of plans", segment_id, percent(*count))?;
+		}
+		Ok(())
+	}
+}
+
+/// Formats seconds as minutes and seconds, eg. `1:02.5`.
+fn format_seconds(seconds: f64) -> String {
+	let tenths = (seconds * 10.0).round() as u64;
+	format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+}
+
+/// How long direct cuts from one loop to another crossfade for, if they do. Only the cuts that mixed
+/// transitions add, between loops with no dedicated transition, are crossfaded.
+fn cut_crossfade(song: &Song) -> Option<f64> {
+	match song.metadata.crossfade {
+		Some(crossfade)
+			if crossfade > 0.0
+				&& song.metadata.transitions == TransitionMode::Mixed
+				&& song.has_dedicated_transitions =>
+		{
+			Some(crossfade as f64)
+		}
+		_ => None,
+	}
+}
+
+/// Remembers how long segments are, since finding out can mean decoding the whole file.
+#[derive(Debug, Default)]
+pub struct SegmentDurations {
+	known: HashMap<(String, String), Duration>,
+}
+
+impl SegmentDurations {
+	/// How long one play of the segment lasts.
+	pub fn get(&mut self, song: &Song, segment: &SongSegment) -> Result<Duration, DjError> {
+		let key = (song.id.to_string(), segment.id.to_string());
+		if let Some(duration) = self.known.get(&key) {
+			return Ok(*duration);
+		}
+		let duration = song.segment_duration(segment)?;
+		self.known.insert(key, duration);
+		Ok(duration)
+	}
+}
+
+#[cfg(test)]
+mod test_plan {
+	use super::*;
+	use crate::{initialize_songs, initialize_transitions};
+	use rand::{rngs::StdRng, SeedableRng};
+
+	fn tone() -> (Song, SegmentGraph) {
+		let mut songs = initialize_songs(&[
+			"test-data/test_song_audio/tone_start.wav",
+			"test-data/test_song_audio/tone_loop.wav",
+		])
+		.unwrap();
+		initialize_transitions(&mut songs);
+		let song = songs.remove("tone").unwrap();
+		let graph = SegmentGraph::new(&song).unwrap();
+		(song, graph)
+	}
+
+	#[test]
+	fn test_make_plan() {
+		let (song, graph) = tone();
+		let plan = Plan::make(
+			&song,
+			&graph,
+			PlanMode::Full,
+			None,
+			6,
+			&mut SegmentDurations::default(),
+			&mut rand::thread_rng(),
+		)
+		.unwrap();
+		assert_eq!(
+			plan,
+			Plan {
+				song: "tone".to_string(),
+				after: None,
+				entries: vec![
+					PlanEntry {
+						id: "start".to_string(),
+						repeat_count: None,
+						start: 0.0,
+						duration: 0.05,
+						crossfade: None,
+					},
+					PlanEntry {
+						id: "loop".to_string(),
+						repeat_count: Some(5),
+						start: 0.05,
+						duration: 0.25,
+						crossfade: None,
+					},
+				],
+				endless: false,
+				fade_out: Some(8.0),
+			}
+		);
+		assert!((plan.duration() - 8.3).abs() < 1e-9);
+		plan.validate(&song, &graph).unwrap();
+		assert_eq!(
+			plan.to_string(),
+			"tone (0:08.3)\n  0:00.0  start (0:00.1)\n  0:00.1  loop x5 (0:00.3)\n  0:00.3  fade out (0:08.0)\n"
+		);
+
+		let json = serde_json::to_string(&plan).unwrap();
+		assert_eq!(
+			json,
+			r#"{"song":"tone","entries":[{"id":"start","start":0.0,"duration":0.05},{"id":"loop","repeat_count":5,"start":0.05,"duration":0.25}],"fade_out":8.0}"#
+		);
+		assert_eq!(serde_json::from_str::<Plan>(&json).unwrap(), plan);
+	}
+
+	#[test]
+	fn test_make_bumper() {
+		let (song, graph) = tone();
+		let plan = Plan::make(
+			&song,
+			&graph,
+			PlanMode::Bumper,
+			None,
+			6,
+			&mut SegmentDurations::default(),
+			&mut rand::thread_rng(),
+		)
+		.unwrap();
+		assert_eq!(
+			plan.to_string(),
+			"tone (0:08.1)\n  0:00.0  start (0:00.1)\n  0:00.1  loop x1 (0:00.1)\n  0:00.1  fade out (0:08.0)\n"
+		);
+		plan.validate(&song, &graph).unwrap();
+		assert_eq!("bumper".parse::<PlanMode>(), Ok(PlanMode::Bumper));
+		assert_eq!("short".parse::<PlanMode>(), Err(DjError::PlanModeInvalidValue));
+	}
+
+	#[test]
+	fn test_endless_plan() {
+		let (song, graph) = tone();
+		let mut durations = SegmentDurations::default();
+		let mut rng = rand::thread_rng();
+		let plan = Plan::make(&song, &graph, PlanMode::Endless, None, 6, &mut durations, &mut rng).unwrap();
+		// The song only has one loop, so it keeps repeating.
+		assert_eq!(
+			plan.to_string(),
+			"tone (0:01.3)\n  0:00.0  start (0:00.1)\n  0:00.1  loop x25 (0:01.3)\n  0:01.3  carries on\n"
+		);
+		plan.validate(&song, &graph).unwrap();
+
+		let more = Plan::carry_on(&song, &graph, "loop", false, 6, &mut durations, &mut rng).unwrap();
+		assert_eq!(more.entries.len(), 1);
+		assert_eq!(more.entries[0].repeat_count, Some(30));
+		assert!(more.endless);
+		more.validate(&song, &graph).unwrap();
+
+		let wrap_up = Plan::carry_on(&song, &graph, "loop", true, 6, &mut durations, &mut rng).unwrap();
+		assert_eq!(
+			wrap_up.to_string(),
+			"tone, after loop (0:08.1)\n  0:00.0  loop x1 (0:00.1)\n  0:00.1  fade out (0:08.0)\n"
+		);
+		wrap_up.validate(&song, &graph).unwrap();
+
+		let mut restart = more.clone();
+		restart.entries[0].id = "start".to_string();
+		assert_eq!(
+			restart.validate(&song, &graph),
+			Err(DjError::InvalidPlan(
+				"tone".to_string(),
+				"it can't carry on after 'loop'".to_string()
+			))
+		);
+	}
+
+	#[test]
+	fn test_seeded_plans_repeat() {
+		let (song, graph) = tone();
+		let mut durations = SegmentDurations::default();
+		let mut make = |seed| {
+			let mut rng = StdRng::seed_from_u64(seed);
+			Plan::make(&song, &graph, PlanMode::Full, None, 13, &mut durations, &mut rng).unwrap()
+		};
+		assert_eq!(make(7), make(7));
+	}
+
+	#[test]
+	fn test_plan_stats() {
+		let entry = |id: &str, start: f64| PlanEntry {
+			id: id.to_string(),
+			repeat_count: None,
+			start,
+			duration: 10.0,
+			crossfade: None,
+		};
+		let plan = |ids: &[&str]| Plan {
+			song: "song".to_string(),
+			after: None,
+			entries: ids
+				.iter()
+				.enumerate()
+				.map(|(i, id)| entry(id, i as f64 * 10.0))
+				.collect(),
+			endless: false,
+			fade_out: None,
+		};
+		let stats = PlanStats::new(&[
+			plan(&["start", "loop0", "loop1", "loop0", "loop0-end"]),
+			plan(&["start", "loop0", "loop1", "loop1-end"]),
+		]);
+		assert_eq!(stats.visits["loop0"], 3);
+		assert_eq!(stats.plans_visiting["loop0"], 2);
+		assert_eq!(
+			stats.to_string(),
+			"Over 2 plans:
+  Average length: 4.5 segments, 0:45.0
+  Segments:
+    loop0: in 100% of plans, 1.5 times per plan
+    loop0-end: in 50% of plans, 0.5 times per plan
+    loop1: in 100% of plans, 1.0 times per plan
+    loop1-end: in 50% of plans, 0.5 times per plan
+    start: in 100% of plans, 1.0 times per plan
+  Endings:
+    loop0-end: 50% of plans
+    loop1-end: 50% of plans
+"
+		);
+	}
+
+	#[test]
+	fn test_validate() {
+		let (song, graph) = tone();
+		let plan = Plan::make(
+			&song,
+			&graph,
+			PlanMode::Full,
+			None,
+			6,
+			&mut SegmentDurations::default(),
+			&mut rand::thread_rng(),
+		)
+		.unwrap();
+		let invalid = |reason: &str| Err(DjError::InvalidPlan("tone".to_string(), reason.to_string()));
+
+		let mut backwards = plan.clone();
+		backwards.entries.reverse();
+		assert_eq!(
+			backwards.validate(&song, &graph),
+			invalid("it doesn't begin with a start segment")
+		);
+
+		let mut unfinished = plan.clone();
+		unfinished.entries.pop();
+		unfinished.fade_out = Some(8.0);
+		assert_eq!(
+			unfinished.validate(&song, &graph),
+			invalid("the song can't finish on 'start'")
+		);
+
+		let mut unknown = plan.clone();
+		unknown.entries[1].id = "loop7".to_string();
+		assert_eq!(
+			unknown.validate(&song, &graph),
+			invalid("the song has no segment 'loop7'")
+		);
+
+		let mut repeated_start = plan.clone();
+		repeated_start.entries[0].repeat_count = Some(2);
+		assert_eq!(repeated_start.validate(&song, &graph), invalid("'start' isn't a loop"));
+
+		let mut gap = plan.clone();
+		gap.entries[1].start = 3.0;
+		assert_eq!(
+			gap.validate(&song, &graph),
+			invalid("'loop' doesn't start where the segment before it ends")
+		);
+
+		let mut no_fade = plan;
+		no_fade.fade_out = None;
+		assert_eq!(
+			no_fade.validate(&song, &graph),
+			invalid("only songs without an ending fade out")
+		);
+	}
+
+	#[test]
+	fn test_crossfade_only_mixed_cuts() {
+		let mut songs =
+			initialize_songs(&["songs/cut_start.ogg", "songs/cut_loop0.ogg", "songs/cut_loop1.ogg"]).unwrap();
+		initialize_transitions(&mut songs);
+		let mut song = songs.remove("cut").unwrap();
+		song.metadata.transitions = TransitionMode::Mixed;
+		song.metadata.crossfade = Some(0.05);
+		let graph = SegmentGraph::new(&song).unwrap();
+		let plan: Plan = serde_json::from_str(
+			r#"{"song":"cut","entries":[
+				{"id":"start","start":0,"duration":1},
+				{"id":"loop0","repeat_count":5,"start":1,"duration":5},
+				{"id":"loop1","repeat_count":5,"start":5.95,"duration":5,"crossfade":0.05}
+			],"fade_out":8}"#,
+		)
+		.unwrap();
+		// Without dedicated transitions, loops always cut straight to each other and don't crossfade.
+		assert_eq!(cut_crossfade(&song), None);
+		assert_eq!(
+			plan.validate(&song, &graph),
+			Err(DjError::InvalidPlan(
+				"cut".to_string(),
+				"'loop1' can't crossfade with the segment before it".to_string()
+			))
+		);
+		song.has_dedicated_transitions = true;
+		assert_eq!(cut_crossfade(&song), Some(0.05f32 as f64));
+		plan.validate(&song, &graph).unwrap();
+	}
+
+	#[test]
+	fn test_load_plan() {
+		let (song, graph) = tone();
+		let plan = Plan::make(
+			&song,
+			&graph,
+			PlanMode::Full,
+			None,
+			6,
+			&mut SegmentDurations::default(),
+			&mut rand::thread_rng(),
+		)
+		.unwrap();
+		let songs = vec![("tone".to_string(), song)].into_iter().collect::<HashMap<_, _>>();
+		let graphs = vec![("tone".to_string(), graph)].into_iter().collect::<HashMap<_, _>>();
+		let path = std::env::temp_dir().join(format!("autodj-test-plan-{}.json", std::process::id()));
+		let path = path.to_str().unwrap();
+		let load = |plan: &Plan| {
+			fs::write(path, serde_json::to_string(plan).unwrap()).unwrap();
+			Plan::load(path, &songs, &graphs)
+		};
+
+		assert_eq!(load(&plan), Ok(plan.clone()));
+		let mut backwards = plan.clone();
+		backwards.entries.reverse();
+		assert_eq!(
+			load(&backwards),
+			Err(DjError::InvalidPlan(
+				"tone".to_string(),
+				"it doesn't begin with a start segment".to_string()
+			))
+		);
+		let mut other = plan;
+		other.song = "other".to_string();
+		assert_eq!(load(&other), Err(DjError::UnknownSong("other".to_string())));
+		fs::write(path, "{").unwrap();
+		assert!(matches!(
+			Plan::load(path, &songs, &graphs),
+			Err(DjError::UnreadableFile(_, _))
+		));
+		fs::remove_file(path).unwrap();
+	}
+}
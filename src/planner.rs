@@ -0,0 +1,631 @@
+use std::collections::{HashMap, VecDeque};
+
+use rand::{seq::SliceRandom, Rng};
+use tracing::warn;
+
+use crate::{errors::DjError, Song, SongSegment, BRIDGE_PREFIX};
+
+/// Plans don't finish before they are this long, unless the song's segments don't allow it.
+const MIN_PLAN_LEN: usize = 6;
+/// Plans are kept this short, unless the song's shortest way to finish is longer.
+const MAX_PLAN_LEN: usize = 12;
+/// How many segments each part of an endless song's plan wanders through.
+const ENDLESS_PLAN_LEN: usize = 6;
+
+/// A song's segments and the transitions between them, made once per song so that plans can be
+/// made quickly.
+///
+/// Every segment knows how far it is from a segment the song can finish on, so that a plan can
+/// wander randomly and still be sure to finish within `MAX_PLAN_LEN` segments.
+#[derive(Debug, Clone)]
+pub struct SegmentGraph {
+	/// Sorted by id, so that plans only depend on the random number generator.
+	segments: Vec<SongSegment>,
+	/// The start segments that have a way to finish the song.
+	starts: Vec<usize>,
+	/// The segments each segment can transition to.
+	transitions: Vec<Vec<usize>>,
+	/// Whether the song can finish on each segment.
+	finishes: Vec<bool>,
+	/// How many transitions it takes to get from each segment to one the song can finish on, or
+	/// `None` if there's no way to.
+	distances: Vec<Option<usize>>,
+	/// How many transitions it takes to get from each segment to a loop, only going through
+	/// segments with a way to finish, so that endless plans never head somewhere they can't carry
+	/// on from or wrap up from.
+	loop_distances: Vec<Option<usize>>,
+	has_end: bool,
+}
+
+impl SegmentGraph {
+	/// Makes the graph of a song whose transitions have been initialized.
+	pub fn new(song: &Song) -> Result<SegmentGraph, DjError> {
+		let mut segments = song.segments.values().cloned().collect::<Vec<_>>();
+		segments.sort_by(|a, b| a.id.cmp(&b.id));
+		let indexes = segments
+			.iter()
+			.enumerate()
+			.map(|(i, segment)| (segment.id.as_str(), i))
+			.collect::<HashMap<_, _>>();
+		let transitions = segments
+			.iter()
+			.map(|segment| {
+				let mut next = segment
+					.allowed_transitions
+					.iter()
+					.filter_map(|id| indexes.get(id.as_str()).copied())
+					.collect::<Vec<_>>();
+				next.sort_unstable();
+				next
+			})
+			.collect::<Vec<_>>();
+		let finishes = segments
+			.iter()
+			.map(|segment| can_finish_on(song, segment))
+			.collect::<Vec<_>>();
+		let distances = distances_to_finish(&transitions, &finishes);
+		let can_finish = transitions
+			.iter()
+			.map(|next| {
+				next.iter()
+					.copied()
+					.filter(|i| distances[*i].is_some())
+					.collect::<Vec<_>>()
+			})
+			.collect::<Vec<_>>();
+		let loops = (0..segments.len())
+			.map(|i| segments[i].is_loop() && distances[i].is_some())
+			.collect::<Vec<_>>();
+		let loop_distances = distances_to_finish(&can_finish, &loops);
+		let starts = (0..segments.len())
+			.filter(|i| segments[*i].is_start() && distances[*i].is_some())
+			.collect::<Vec<_>>();
+		if starts.is_empty() {
+			return Err(DjError::UnplannableSong(song.id.to_string()));
+		}
+		Ok(SegmentGraph {
+			segments,
+			starts,
+			transitions,
+			finishes,
+			distances,
+			loop_distances,
+			has_end: song.has_end,
+		})
+	}
+
+	/// The segments a plan can begin on: the bridge from the song played before, or the song's
+	/// starts if there's no bridge from it. Only segments with a distance in `distances` count.
+	fn openings(&self, previous_song: Option<&str>, distances: &[Option<usize>]) -> Vec<usize> {
+		let bridge = previous_song.and_then(|song_id| self.index(&format!("{}{}", BRIDGE_PREFIX, song_id)));
+		match bridge {
+			Some(bridge) if distances[bridge].is_some() => vec![bridge],
+			_ => self
+				.starts
+				.iter()
+				.copied()
+				.filter(|i| distances[*i].is_some())
+				.collect(),
+		}
+	}
+
+	/// Makes a randomized plan for playing the song, starting on one of its start segments, or
+	/// the bridge from `previous_song`, and finishing on a segment it can finish on.
+	pub fn plan<R: Rng + ?Sized>(&self, previous_song: Option<&str>, rng: &mut R) -> Vec<SongSegment> {
+		let start = *self.openings(previous_song, &self.distances).choose(rng).unwrap();
+		// Every segment in the plan has a way to finish before the plan gets longer than this.
+		let max_len = MAX_PLAN_LEN.max(1 + self.distances[start].unwrap());
+		let mut plan = vec![start];
+		let mut current = start;
+		loop {
+			if self.finishes[current] && (self.has_end || plan.len() >= MIN_PLAN_LEN) {
+				break;
+			}
+			let distance = self.distances[current].unwrap();
+			let reachable = self.transitions[current]
+				.iter()
+				.copied()
+				.filter(|next| self.distances[*next].is_some_and(|d| plan.len() + 1 + d <= max_len))
+				.collect::<Vec<_>>();
+			let choices = if plan.len() + 1 < MIN_PLAN_LEN {
+				// Don't finish the song too early, unless there's no other way to go.
+				let keep_going = reachable
+					.iter()
+					.copied()
+					.filter(|next| !self.finishes[*next])
+					.collect::<Vec<_>>();
+				if keep_going.is_empty() {
+					reachable
+				}
+				else {
+					keep_going
+				}
+			}
+			else if plan.len() < MIN_PLAN_LEN {
+				// The next segment makes the plan long enough, wherever it goes.
+				reachable
+			}
+			else {
+				// Head for the nearest place to finish.
+				reachable
+					.into_iter()
+					.filter(|next| self.distances[*next].unwrap() < distance)
+					.collect()
+			};
+			match choices.choose(rng) {
+				Some(next) => {
+					plan.push(*next);
+					current = *next;
+				}
+				// Only segments the song can finish on have nowhere to go.
+				None => break,
+			}
+		}
+		plan.into_iter().map(|i| self.segments[i].clone()).collect()
+	}
+
+	/// Makes the shortest plan there is for the song, from whichever start is nearest to a segment
+	/// it can finish on, or from the bridge from `previous_song`. Ties between equally short ways
+	/// are broken at random.
+	pub fn shortest_plan<R: Rng + ?Sized>(&self, previous_song: Option<&str>, rng: &mut R) -> Vec<SongSegment> {
+		let openings = self.openings(previous_song, &self.distances);
+		let shortest = openings.iter().map(|i| self.distances[*i].unwrap()).min().unwrap();
+		let starts = openings
+			.into_iter()
+			.filter(|i| self.distances[*i] == Some(shortest))
+			.collect::<Vec<_>>();
+		let start = *starts.choose(rng).unwrap();
+		let mut plan = vec![start];
+		plan.extend(self.shortest_after(start, rng));
+		plan.into_iter().map(|i| self.segments[i].clone()).collect()
+	}
+
+	/// Makes part of an endless plan, which wanders between the song's loops and never heads for an
+	/// ending. It carries on after the segment `after`, or begins like a plan from `plan` does.
+	/// Returns `None` if there's no loop to carry on to.
+	pub fn wander<R: Rng + ?Sized>(
+		&self, after: Option<&str>, previous_song: Option<&str>, rng: &mut R,
+	) -> Option<Vec<SongSegment>> {
+		let mut plan = Vec::new();
+		let mut current = match after {
+			Some(id) => self.index(id)?,
+			None => {
+				let start = *self.openings(previous_song, &self.loop_distances).choose(rng)?;
+				plan.push(start);
+				start
+			}
+		};
+		self.loop_distances[current]?;
+		while plan.len() < ENDLESS_PLAN_LEN {
+			let choices = self.transitions[current]
+				.iter()
+				.copied()
+				.filter(|next| self.loop_distances[*next].is_some())
+				.collect::<Vec<_>>();
+			// A loop with nowhere else to go plays again.
+			current = choices.choose(rng).copied().unwrap_or(current);
+			plan.push(current);
+		}
+		Some(plan.into_iter().map(|i| self.segments[i].clone()).collect())
+	}
+
+	/// Makes the rest of a plan that has got as far as the segment `after`, taking the shortest way
+	/// from there to a segment the song can finish on. If the song can finish on `after` itself, it
+	/// is played once more. Returns `None` if there's no way to finish.
+	pub fn finish_after<R: Rng + ?Sized>(&self, after: &str, rng: &mut R) -> Option<Vec<SongSegment>> {
+		let after = self.index(after)?;
+		let plan = match self.distances[after]? {
+			0 => vec![after],
+			_ => self.shortest_after(after, rng),
+		};
+		Some(plan.into_iter().map(|i| self.segments[i].clone()).collect())
+	}
+
+	/// The shortest way from a segment to one the song can finish on, not including the segment.
+	fn shortest_after<R: Rng + ?Sized>(&self, mut current: usize, rng: &mut R) -> Vec<usize> {
+		let mut plan = Vec::new();
+		while let Some(distance) = self.distances[current].filter(|d| *d > 0) {
+			let closer = self.transitions[current]
+				.iter()
+				.copied()
+				.filter(|next| self.distances[*next] == Some(distance - 1))
+				.collect::<Vec<_>>();
+			current = *closer.choose(rng).unwrap();
+			plan.push(current);
+		}
+		plan
+	}
+
+	fn index(&self, id: &str) -> Option<usize> {
+		self.segments
+			.binary_search_by(|segment| segment.id.as_str().cmp(id))
+			.ok()
+	}
+
+	/// Whether an endless plan can carry on after a segment.
+	pub fn carries_on_from(&self, id: &str) -> bool {
+		self.index(id).is_some_and(|i| self.loop_distances[i].is_some())
+	}
+
+	/// Whether the song can finish on a segment.
+	pub fn finishes_on(&self, id: &str) -> bool {
+		self.index(id).is_some_and(|i| self.finishes[i])
+	}
+}
+
+/// Whether a song can finish on a segment. Songs with an ending finish on an end segment, and songs
+/// without one fade out on any segment that isn't a start, a bridge or a dedicated transition.
+pub fn can_finish_on(song: &Song, segment: &SongSegment) -> bool {
+	if song.has_end {
+		segment.is_end()
+	}
+	else {
+		!segment.is_start() && !segment.is_bridge() && !segment.is_dedicated_transition()
+	}
+}
+
+/// Finds how far each segment is from one the song can finish on, searching backwards from the
+/// segments it can finish on.
+pub fn distances_to_finish(transitions: &[Vec<usize>], finishes: &[bool]) -> Vec<Option<usize>> {
+	let mut previous = vec![Vec::new(); transitions.len()];
+	for (from, next) in transitions.iter().enumerate() {
+		for to in next {
+			previous[*to].push(from);
+		}
+	}
+	let mut distances = finishes
+		.iter()
+		.map(|finishes| {
+			if *finishes {
+				Some(0)
+			}
+			else {
+				None
+			}
+		})
+		.collect::<Vec<_>>();
+	let mut work_queue = (0..finishes.len()).filter(|i| finishes[*i]).collect::<VecDeque<_>>();
+	while let Some(to) = work_queue.pop_front() {
+		let distance = distances[to].unwrap() + 1;
+		for from in &previous[to] {
+			if distances[*from].is_none() {
+				distances[*from] = Some(distance);
+				work_queue.push_back(*from);
+			}
+		}
+	}
+	distances
+}
+
+/// The graph of every song, by song id.
+pub type SongGraphs = HashMap<String, SegmentGraph>;
+
+/// Makes the graph of every song, dropping songs that can't be planned.
+pub fn build_graphs(songs: &mut HashMap<String, Song>) -> SongGraphs {
+	let mut graphs = HashMap::new();
+	songs.retain(|song_id, song| match SegmentGraph::new(song) {
+		Ok(graph) => {
+			graphs.insert(song_id.to_string(), graph);
+			true
+		}
+		Err(e) => {
+			warn!("{}, dropping", e);
+			false
+		}
+	});
+	graphs
+}
+
+#[cfg(test)]
+mod test_planner {
+	use super::*;
+	use crate::{metadata::SongMetadata, song_source::LooseFiles};
+	use proptest::{collection::vec, prelude::*};
+	use std::{collections::HashSet, sync::Arc};
+
+	fn segment(id: &str, transitions: &[&str]) -> SongSegment {
+		SongSegment {
+			id: id.to_string(),
+			format: "ogg".to_string(),
+			allowed_transitions: transitions.iter().map(|s| s.to_string()).collect(),
+			layers: vec![],
+		}
+	}
+
+	fn song(has_end: bool, segments: Vec<SongSegment>) -> Song {
+		Song {
+			id: "song".to_string(),
+			segments: segments
+				.into_iter()
+				.map(|segment| (segment.id.to_string(), segment))
+				.collect(),
+			stingers: HashMap::new(),
+			metadata: SongMetadata::default(),
+			has_end,
+			has_global_ending: false,
+			has_multiple_loops: true,
+			has_dedicated_transitions: false,
+			source: Arc::new(LooseFiles::new("", "")),
+		}
+	}
+
+	#[test]
+	fn test_unreachable_end() {
+		let song = song(
+			true,
+			vec![
+				segment("start", &["loop0"]),
+				segment("loop0", &["loop1"]),
+				segment("loop1", &["loop0"]),
+				segment("loop2", &["loop2-end"]),
+				segment("loop2-end", &[]),
+			],
+		);
+		assert_eq!(
+			SegmentGraph::new(&song).unwrap_err(),
+			DjError::UnplannableSong("song".to_string())
+		);
+	}
+
+	#[test]
+	fn test_avoids_dead_ends() {
+		// loop1 can't reach the end, so it's never planned.
+		let song = song(
+			true,
+			vec![
+				segment("start", &["loop0"]),
+				segment("loop0", &["loop1", "loop2"]),
+				segment("loop1", &["loop1-to-3"]),
+				segment("loop1-to-3", &["loop3"]),
+				segment("loop2", &["loop0", "loop2-end"]),
+				segment("loop2-end", &[]),
+			],
+		);
+		let graph = SegmentGraph::new(&song).unwrap();
+		let mut rng = rand::thread_rng();
+		for _ in 0..100 {
+			let plan = graph.plan(None, &mut rng).into_iter().map(|s| s.id).collect::<Vec<_>>();
+			assert!(!plan.contains(&"loop1".to_string()), "{:?}", plan);
+			assert_eq!(plan.last().unwrap(), "loop2-end");
+			assert!(plan.len() >= MIN_PLAN_LEN && plan.len() <= MAX_PLAN_LEN, "{:?}", plan);
+		}
+	}
+
+	#[test]
+	fn test_min_len_without_end() {
+		let song = song(
+			false,
+			vec![
+				segment("start", &["loop0"]),
+				segment("loop0", &["loop1", "loop2"]),
+				segment("loop1", &["loop0", "loop2"]),
+				segment("loop2", &["loop0", "loop1"]),
+			],
+		);
+		let graph = SegmentGraph::new(&song).unwrap();
+		let mut rng = rand::thread_rng();
+		for _ in 0..100 {
+			let plan = graph.plan(None, &mut rng);
+			assert!(plan.len() >= MIN_PLAN_LEN && plan.len() <= MAX_PLAN_LEN, "{:?}", plan);
+		}
+	}
+
+	#[test]
+	fn test_long_way_to_end() {
+		let mut segments = vec![segment("start", &["loop0"])];
+		for i in 0..20 {
+			segments.push(segment(&format!("loop{}", i), &[&format!("loop{}", i + 1)]));
+		}
+		segments.push(segment("loop20", &["loop20-end"]));
+		segments.push(segment("loop20-end", &[]));
+		let plan = SegmentGraph::new(&song(true, segments))
+			.unwrap()
+			.plan(None, &mut rand::thread_rng());
+		assert_eq!(plan.len(), 23);
+	}
+
+	#[test]
+	fn test_start_variants() {
+		// start-to-2 can't reach the end, so it's never planned.
+		let song = song(
+			true,
+			vec![
+				segment("start", &["loop0"]),
+				segment("start1", &["loop0"]),
+				segment("start-to-1", &["loop1"]),
+				segment("start-to-2", &["loop2"]),
+				segment("loop0", &["loop1", "loop0-end"]),
+				segment("loop1", &["loop0"]),
+				segment("loop2", &[]),
+				segment("loop0-end", &[]),
+			],
+		);
+		let graph = SegmentGraph::new(&song).unwrap();
+		let mut rng = rand::thread_rng();
+		let starts = (0..100)
+			.map(|_| graph.plan(None, &mut rng).remove(0).id)
+			.collect::<HashSet<_>>();
+		assert_eq!(
+			starts,
+			["start", "start1", "start-to-1"]
+				.iter()
+				.map(|s| s.to_string())
+				.collect()
+		);
+	}
+
+	#[test]
+	fn test_bridge() {
+		let song = song(
+			true,
+			vec![
+				segment("start", &["loop0"]),
+				segment("from-other", &["loop1"]),
+				segment("loop0", &["loop1"]),
+				segment("loop1", &["loop0", "loop1-end"]),
+				segment("loop1-end", &[]),
+			],
+		);
+		let graph = SegmentGraph::new(&song).unwrap();
+		let mut rng = rand::thread_rng();
+		for _ in 0..20 {
+			assert_eq!(graph.plan(Some("other"), &mut rng)[0].id, "from-other");
+			assert_eq!(graph.plan(Some("another"), &mut rng)[0].id, "start");
+			assert_eq!(graph.plan(None, &mut rng)[0].id, "start");
+		}
+		let shortest = graph
+			.shortest_plan(Some("other"), &mut rng)
+			.into_iter()
+			.map(|s| s.id)
+			.collect::<Vec<_>>();
+		assert_eq!(shortest, vec!["from-other", "loop1", "loop1-end"]);
+	}
+
+	#[test]
+	fn test_shortest_plan() {
+		let with_end = song(
+			true,
+			vec![
+				segment("start", &["loop0"]),
+				segment("start-to-1", &["loop1"]),
+				segment("loop0", &["loop1"]),
+				segment("loop1", &["loop0", "loop1-end"]),
+				segment("loop1-end", &[]),
+			],
+		);
+		let plan = SegmentGraph::new(&with_end)
+			.unwrap()
+			.shortest_plan(None, &mut rand::thread_rng())
+			.into_iter()
+			.map(|s| s.id)
+			.collect::<Vec<_>>();
+		assert_eq!(plan, vec!["start-to-1", "loop1", "loop1-end"]);
+
+		// Songs without an ending fade out on their first loop.
+		let without_end = song(false, vec![segment("start", &["loop"]), segment("loop", &["loop"])]);
+		let plan = SegmentGraph::new(&without_end)
+			.unwrap()
+			.shortest_plan(None, &mut rand::thread_rng());
+		assert_eq!(plan.len(), 2);
+	}
+
+	/// Generates songs with any number of starts, loops and ends, transitioning to any other segment.
+	fn graph_strategy() -> impl Strategy<Value = (bool, Vec<SongSegment>)> {
+		(1..16usize, 0..4usize, any::<bool>(), any::<bool>()).prop_flat_map(
+			|(loop_count, end_count, has_end, two_starts)| {
+				let mut ids = vec!["start".to_string()];
+				if two_starts {
+					ids.push("start1".to_string());
+				}
+				ids.extend((0..loop_count).map(|i| format!("loop{}", i)));
+				ids.extend((0..end_count).map(|i| format!("loop{}-end", i)));
+				if loop_count > 1 {
+					ids.push("loop0-to-1".to_string());
+				}
+				let count = ids.len();
+				vec(vec(0..count, 0..4), count).prop_map(move |transitions| {
+					let segments = ids
+						.iter()
+						.zip(transitions)
+						.map(|(id, next)| {
+							let next = if id.ends_with("end") {
+								HashSet::new()
+							}
+							else {
+								next.into_iter().map(|i| ids[i].to_string()).collect()
+							};
+							SongSegment {
+								id: id.to_string(),
+								format: "ogg".to_string(),
+								allowed_transitions: next,
+								layers: vec![],
+							}
+						})
+						.collect();
+					(has_end, segments)
+				})
+			},
+		)
+	}
+
+	/// Whether the song can finish on a segment, worked out from the segment names rather than with
+	/// `can_finish_on`, so the planner is checked against the naming rules instead of against itself.
+	fn finishes_on(song: &Song, segment: &SongSegment) -> bool {
+		let id = segment.id.as_str();
+		if song.has_end {
+			id == "end" || id.ends_with("-end")
+		}
+		else {
+			!id.starts_with("start") && !id.starts_with("from-") && !id.contains("-to-")
+		}
+	}
+
+	/// Whether the song can get from its start to a segment it can finish on, found the slow way.
+	fn can_finish(song: &Song) -> bool {
+		let mut stack = song
+			.segments
+			.values()
+			.filter(|segment| segment.is_start())
+			.map(|segment| segment.id.to_string())
+			.collect::<Vec<_>>();
+		let mut seen = stack.iter().cloned().collect::<HashSet<_>>();
+		while let Some(id) = stack.pop() {
+			let segment = &song.segments[&id];
+			if finishes_on(song, segment) {
+				return true;
+			}
+			for next in &segment.allowed_transitions {
+				if seen.insert(next.to_string()) {
+					stack.push(next.to_string());
+				}
+			}
+		}
+		false
+	}
+
+	proptest! {
+		#[test]
+		fn prop_plans_follow_transitions_and_finish((has_end, segments) in graph_strategy()) {
+			let song = song(has_end, segments);
+			let graph = match SegmentGraph::new(&song) {
+				Ok(graph) => graph,
+				Err(e) => {
+					prop_assert!(!can_finish(&song));
+					prop_assert_eq!(e, DjError::UnplannableSong("song".to_string()));
+					return Ok(());
+				}
+			};
+			prop_assert!(can_finish(&song));
+			let plan = graph.plan(None, &mut rand::thread_rng());
+			prop_assert!(plan[0].is_start());
+			prop_assert!(plan.len() <= MAX_PLAN_LEN.max(song.segments.len()));
+			for pair in plan.windows(2) {
+				prop_assert!(pair[0].allowed_transitions.contains(&pair[1].id));
+			}
+			prop_assert!(finishes_on(&song, plan.last().unwrap()));
+			let shortest = graph.shortest_plan(None, &mut rand::thread_rng());
+			prop_assert!(shortest[0].is_start());
+			prop_assert!(shortest.len() <= plan.len());
+			for pair in shortest.windows(2) {
+				prop_assert!(pair[0].allowed_transitions.contains(&pair[1].id));
+			}
+			prop_assert!(finishes_on(&song, shortest.last().unwrap()));
+
+			let endless = match graph.wander(None, None, &mut rand::thread_rng()) {
+				Some(endless) => endless,
+				None => return Ok(()),
+			};
+			prop_assert!(endless[0].is_start());
+			prop_assert_eq!(endless.len(), ENDLESS_PLAN_LEN);
+			let more = graph.wander(Some(&endless.last().unwrap().id), None, &mut rand::thread_rng()).unwrap();
+			let wrap_up = graph.finish_after(&more.last().unwrap().id, &mut rand::thread_rng()).unwrap();
+			prop_assert!(finishes_on(&song, wrap_up.last().unwrap()));
+			let whole = endless.iter().chain(&more).chain(&wrap_up).collect::<Vec<_>>();
+			for pair in whole.windows(2) {
+				let repeats = pair[0].id == pair[1].id && pair[0].is_loop();
+				prop_assert!(repeats || pair[0].allowed_transitions.contains(&pair[1].id));
+			}
+			prop_assert!(endless.iter().chain(&more).all(|segment| !segment.is_end()));
+		}
+	}
+}
@@ -0,0 +1,501 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{mpsc::Receiver, Arc},
+	thread,
+	time::{Duration, Instant, SystemTime},
+};
+
+use rodio::{Sample, Sink, Source};
+use tracing::{info, warn};
+
+use crate::{
+	control::{ControlCommand, ControlRequest},
+	errors::DjError,
+	history::{History, PlannedSegment, Play, PlayEnd},
+	http_stream::StreamTitle,
+	layered_source::Intensity,
+	output::WriterFailure,
+	plan::PlanMode,
+	playlist::Playlists,
+	position_source::{self, PlaybackPosition},
+	resample::{self, OutputFormat},
+	shutdown,
+	stinger::{quantize_delay, Quantize, StingerLayer},
+	Song,
+};
+
+/// How often to check for control commands while waiting for the music to finish.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A segment that has been queued up, logged once it starts playing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedSegment {
+	pub song_id: String,
+	pub segment_id: String,
+	pub repeat_count: Option<u32>,
+	/// The song's whole plan, given with its first segment.
+	pub plan: Option<Vec<PlannedSegment>>,
+	/// Whether `plan` is the next part of an endless song that is already playing, rather than
+	/// the start of a new play.
+	pub carries_on: bool,
+	/// The title shown to listeners once the segment starts, given with the first segment of a song.
+	pub title: Option<String>,
+	/// Whether the song plays endlessly.
+	pub endless: bool,
+}
+
+/// The song that is playing, added to the history once the next one starts.
+struct CurrentPlay {
+	song_id: String,
+	plan: Vec<PlannedSegment>,
+	started: SystemTime,
+	started_at: Instant,
+	/// The position's interruption count when the song started.
+	interruptions: u64,
+	endless: bool,
+	/// Whether the song was asked to head for an ending.
+	wrapped_up: bool,
+	/// How many of the segments in `plan` have started.
+	segments_started: usize,
+}
+
+/// Plays song segments and reacts to control commands while they play.
+pub struct Player {
+	sink: Sink,
+	/// Every song, to look up the one that is playing.
+	songs: Arc<HashMap<String, Song>>,
+	position: Arc<PlaybackPosition>,
+	/// What each source in the sink is, in order, for those that haven't started yet.
+	queued: VecDeque<Option<QueuedSegment>>,
+	/// How many of the position's started sources have been logged.
+	sources_logged: u64,
+	current: Option<CurrentPlay>,
+	history: Option<History>,
+	stingers: StingerLayer,
+	control: Option<Receiver<ControlRequest>>,
+	intensity: Arc<Intensity>,
+	playlists: Playlists,
+	/// How the next song should be planned, if a control command asked for it.
+	next_plan_mode: Option<PlanMode>,
+	/// Whether the song that was queued last is endless.
+	endless: bool,
+	/// Whether the endless song should head for an ending.
+	wrap_up: bool,
+	/// The format that stingers are converted to, to match the music.
+	format: OutputFormat,
+	/// Stops the player if the output stops writing.
+	output_failure: WriterFailure,
+	/// Where the title of the song that is playing is shown to listeners, for outputs that have them.
+	stream_title: Option<Arc<StreamTitle>>,
+}
+
+impl Player {
+	pub fn new(
+		sink: Sink, songs: Arc<HashMap<String, Song>>, stingers: StingerLayer,
+		control: Option<Receiver<ControlRequest>>, playlists: Playlists, format: OutputFormat,
+		history: Option<History>,
+	) -> Player {
+		Player {
+			sink,
+			songs,
+			position: PlaybackPosition::new(),
+			queued: VecDeque::new(),
+			sources_logged: 0,
+			current: None,
+			history,
+			stingers,
+			control,
+			intensity: Intensity::new(1.0),
+			playlists,
+			next_plan_mode: None,
+			endless: false,
+			wrap_up: false,
+			format,
+			output_failure: WriterFailure::default(),
+			stream_title: None,
+		}
+	}
+
+	/// Stops waiting for the music with an error once writing to the output fails.
+	pub fn watch_output(&mut self, failure: WriterFailure) {
+		self.output_failure = failure;
+	}
+
+	/// Shows listeners the title of each song as it starts.
+	pub fn show_titles(&mut self, stream_title: Option<Arc<StreamTitle>>) {
+		self.stream_title = stream_title;
+	}
+
+	/// How the next song should be planned, if a control command asked for it.
+	pub fn next_plan_mode(&self) -> Option<PlanMode> {
+		self.next_plan_mode
+	}
+
+	/// Takes the plan mode asked for, once the song it was for is about to play.
+	pub fn take_next_plan_mode(&mut self) -> Option<PlanMode> {
+		self.next_plan_mode.take()
+	}
+
+	/// Sets whether the song that is about to play is endless, so that it can be wrapped up.
+	pub fn set_endless(&mut self, endless: bool) {
+		self.endless = endless;
+		if !endless {
+			self.wrap_up = false;
+		}
+	}
+
+	/// Whether the endless song that was queued last has been asked to head for an ending.
+	pub fn wrap_up_requested(&self) -> bool {
+		self.endless && self.wrap_up
+	}
+
+	/// The playlists that the next song should be picked from.
+	pub fn playlists(&mut self) -> &mut Playlists {
+		&mut self.playlists
+	}
+
+	/// The playback position of the music.
+	pub fn position(&self) -> Arc<PlaybackPosition> {
+		self.position.clone()
+	}
+
+	/// The intensity of layered segments, shared with every layered segment that gets queued.
+	pub fn intensity(&self) -> Arc<Intensity> {
+		self.intensity.clone()
+	}
+
+	/// Queues up a source to be played after everything that is already queued. `segment` is
+	/// logged when the source starts playing.
+	pub fn append<S>(&mut self, source: S, segment: Option<QueuedSegment>)
+	where
+		S: Source + Send + 'static,
+		S::Item: Sample + Send,
+	{
+		self.queued.push_back(segment);
+		self.sink.append(position_source::track(source, self.position.clone()));
+	}
+
+	/// Logs the segments that have started playing since this was last called, and records songs
+	/// in the history once they're over.
+	fn log_started_segments(&mut self) {
+		let started = self.position.sources_started();
+		while self.sources_logged < started {
+			self.sources_logged += 1;
+			if let Some(Some(segment)) = self.queued.pop_front() {
+				info!(
+					song = %segment.song_id,
+					segment = %segment.segment_id,
+					repeat_count = segment.repeat_count,
+					"Segment started"
+				);
+				if let (Some(title), Some(stream_title)) = (&segment.title, &self.stream_title) {
+					stream_title.set(title);
+				}
+				if let Some(plan) = segment.plan {
+					if segment.carries_on {
+						if let Some(current) = &mut self.current {
+							current.plan.extend(plan);
+							current.endless = segment.endless;
+							current.segments_started += 1;
+							continue;
+						}
+					}
+					self.finish_play(false);
+					self.current = Some(CurrentPlay {
+						song_id: segment.song_id,
+						plan,
+						started: SystemTime::now(),
+						started_at: Instant::now(),
+						interruptions: self.position.interruptions(),
+						endless: segment.endless,
+						wrapped_up: false,
+						segments_started: 1,
+					});
+				}
+				else if let Some(current) = &mut self.current {
+					current.segments_started += 1;
+				}
+			}
+		}
+	}
+
+	/// Adds the song that was playing to the history, as `stopped` if the DJ is stopping.
+	fn finish_play(&mut self, stopped: bool) {
+		let (current, history) = match (self.current.take(), &mut self.history) {
+			(Some(current), Some(history)) => (current, history),
+			_ => return,
+		};
+		let end = if stopped {
+			PlayEnd::Stopped
+		}
+		else if self.position.interruptions() != current.interruptions {
+			PlayEnd::Interrupted
+		}
+		else if current.wrapped_up {
+			PlayEnd::WrappedUp
+		}
+		else {
+			PlayEnd::Finished
+		};
+		let play = Play::new(
+			&current.song_id,
+			current.plan,
+			current.started,
+			current.started_at.elapsed(),
+			end,
+		);
+		if let Err(e) = history.record(play) {
+			warn!("{}", e);
+		}
+	}
+
+	/// Blocks until everything queued has been played, handling control commands in the meantime.
+	/// If that was the whole song, it is added to the history.
+	pub fn wait_until_end(&mut self) -> Result<(), DjError> {
+		self.wait_until_queued(0)?;
+		let over = self
+			.current
+			.as_ref()
+			.is_some_and(|current| !current.endless && current.segments_started >= current.plan.len());
+		if over {
+			self.finish_play(false);
+		}
+		Ok(())
+	}
+
+	/// Blocks until at most `remaining` sources are left in the queue, handling control commands
+	/// in the meantime. Fails if the output stops writing, since nothing queued would play then.
+	pub fn wait_until_queued(&mut self, remaining: usize) -> Result<(), DjError> {
+		while self.sink.len() > remaining {
+			self.output_failure.check()?;
+			if shutdown::requested() {
+				return Err(DjError::Stopped);
+			}
+			self.log_started_segments();
+			match &self.control {
+				Some(control) => {
+					if let Ok(request) = control.recv_timeout(POLL_INTERVAL) {
+						let result = self.handle_command(&request.command);
+						if let Err(e) = &result {
+							warn!("{}", e);
+						}
+						request.respond(result);
+					}
+				}
+				None => thread::sleep(POLL_INTERVAL),
+			}
+			self.stingers.update(&self.sink);
+		}
+		self.log_started_segments();
+		Ok(())
+	}
+
+	/// Handles a control command. Commands about the song apply to the one that is playing, rather
+	/// than one that has only been queued.
+	fn handle_command(&mut self, command: &ControlCommand) -> Result<(), DjError> {
+		let songs = self.songs.clone();
+		let current = self.current.as_ref();
+		let song = current.and_then(|current| songs.get(&current.song_id));
+		let song_id = song.map_or("(none)", |song| song.id.as_str());
+		match command {
+			ControlCommand::Stinger { name, quantize } => {
+				let tempo = song.and_then(|song| Some((song.metadata.bpm?, song.metadata.beats_per_bar())));
+				let delay = match (quantize, tempo) {
+					(Quantize::Immediate, _) => Duration::from_secs(0),
+					(Quantize::Beat, Some((bpm, _))) => quantize_delay(self.position.elapsed(), bpm, 1),
+					(Quantize::Bar, Some((bpm, beats_per_bar))) => {
+						quantize_delay(self.position.elapsed(), bpm, beats_per_bar)
+					}
+					(_, None) => {
+						warn!(
+							song = %song_id,
+							"Song has no bpm in its metadata, playing stinger immediately"
+						);
+						Duration::from_secs(0)
+					}
+				};
+				info!(stinger = %name, ?delay, "Playing stinger");
+				let source = match song.and_then(|song| song.stingers.get(name).map(|segment| (song, segment))) {
+					Some((song, segment)) => song.read_segment(segment)?,
+					None => self.stingers.read_stinger(name)?,
+				};
+				self.stingers.trigger(resample::convert(source, self.format)?, delay);
+				Ok(())
+			}
+			ControlCommand::Intensity(intensity) => {
+				info!(intensity, "Setting intensity");
+				self.intensity.set(*intensity);
+				Ok(())
+			}
+			ControlCommand::Playlist(playlist) => {
+				self.playlists.switch(playlist.as_deref())?;
+				info!(
+					playlist = playlist.as_deref().unwrap_or("(all songs)"),
+					song = %song_id,
+					"Switching playlist after the current song ends"
+				);
+				Ok(())
+			}
+			ControlCommand::WrapUp => {
+				if !current.is_some_and(|current| current.endless) {
+					return Err(DjError::InvalidControlCommand(format!(
+						"song '{}' isn't playing endlessly",
+						song_id
+					)));
+				}
+				info!(song = %song_id, "Wrapping up the endless song");
+				self.wrap_up = true;
+				if let Some(current) = &mut self.current {
+					current.wrapped_up = true;
+				}
+				Ok(())
+			}
+			ControlCommand::PlanMode(mode) => {
+				info!(
+					?mode,
+					song = %song_id,
+					"Changing how the song after the current one is planned"
+				);
+				self.next_plan_mode = Some(*mode);
+				Ok(())
+			}
+		}
+	}
+}
+
+impl Drop for Player {
+	/// Adds the song that was playing when the DJ stopped to the history.
+	fn drop(&mut self) {
+		self.log_started_segments();
+		self.finish_play(true);
+	}
+}
+
+#[cfg(test)]
+mod test_player {
+	use super::*;
+	use crate::{initialize_songs, initialize_transitions};
+	use rodio::{buffer::SamplesBuffer, queue::SourcesQueueOutput};
+
+	fn segment(song_id: &str, endless: bool) -> QueuedSegment {
+		QueuedSegment {
+			song_id: song_id.to_string(),
+			segment_id: "loop".to_string(),
+			repeat_count: None,
+			plan: Some(vec![PlannedSegment {
+				id: "loop".to_string(),
+				repeat_count: None,
+			}]),
+			carries_on: false,
+			title: None,
+			endless,
+		}
+	}
+
+	/// A player for the tone song, and the output of its music.
+	fn player(history: Option<History>) -> (Player, SourcesQueueOutput<f32>) {
+		let mut songs = initialize_songs(&[
+			"test-data/test_song_audio/tone_start.wav",
+			"test-data/test_song_audio/tone_loop.wav",
+		])
+		.unwrap();
+		initialize_transitions(&mut songs);
+		let (sink, output) = Sink::new_idle();
+		let (stinger_sink, _) = Sink::new_idle();
+		let player = Player::new(
+			sink,
+			Arc::new(songs),
+			StingerLayer::new(stinger_sink, None, 0.35).unwrap(),
+			None,
+			Playlists::new(HashMap::new(), None, Vec::new()).unwrap(),
+			OutputFormat::default(),
+			history,
+		);
+		(player, output)
+	}
+
+	#[test]
+	fn test_commands_apply_to_playing_song() {
+		let (mut player, mut output) = player(None);
+		assert!(player.handle_command(&ControlCommand::WrapUp).is_err());
+
+		player.append(
+			SamplesBuffer::new(1, 8000, vec![0i16; 100]),
+			Some(segment("tone", false)),
+		);
+		output.next();
+		player.set_endless(true);
+		player.append(
+			SamplesBuffer::new(1, 8000, vec![0i16; 100]),
+			Some(segment("tone", true)),
+		);
+		player.log_started_segments();
+		// The endless song has only been queued, so it can't be wrapped up yet.
+		assert_eq!(
+			player.handle_command(&ControlCommand::WrapUp),
+			Err(DjError::InvalidControlCommand(
+				"song 'tone' isn't playing endlessly".to_string()
+			))
+		);
+
+		output.by_ref().take(200).for_each(drop);
+		player.log_started_segments();
+		assert_eq!(player.handle_command(&ControlCommand::WrapUp), Ok(()));
+		assert!(player.wrap_up_requested());
+	}
+
+	#[test]
+	fn test_history_on_end_and_stop() {
+		let path = std::env::temp_dir().join(format!("autodj-test-player-history-{}.jsonl", std::process::id()));
+		let path = path.to_str().unwrap();
+		let (mut player, mut output) = player(Some(History::open(path).unwrap()));
+		player.append(
+			SamplesBuffer::new(1, 8000, vec![0i16; 100]),
+			Some(segment("tone", false)),
+		);
+		output.by_ref().take(200).for_each(drop);
+		player.wait_until_end().unwrap();
+
+		player.append(
+			SamplesBuffer::new(1, 8000, vec![0i16; 100]),
+			Some(segment("tone", false)),
+		);
+		// The queue finishes the bit of silence it plays while empty first.
+		output.by_ref().take(1000).for_each(drop);
+		drop(player);
+		let ends = History::open(path)
+			.unwrap()
+			.plays()
+			.iter()
+			.map(|play| play.end)
+			.collect::<Vec<_>>();
+		std::fs::remove_file(path).unwrap();
+		assert_eq!(ends, vec![PlayEnd::Finished, PlayEnd::Stopped]);
+	}
+
+	#[test]
+	fn test_history_on_wrap_up() {
+		let path = std::env::temp_dir().join(format!("autodj-test-player-wrap-up-{}.jsonl", std::process::id()));
+		let path = path.to_str().unwrap();
+		let (mut player, mut output) = player(Some(History::open(path).unwrap()));
+		player.set_endless(true);
+		player.append(
+			SamplesBuffer::new(1, 8000, vec![0i16; 100]),
+			Some(segment("tone", true)),
+		);
+		output.next();
+		player.log_started_segments();
+		assert_eq!(player.handle_command(&ControlCommand::WrapUp), Ok(()));
+
+		// The play is over once the next song starts.
+		player.append(
+			SamplesBuffer::new(1, 8000, vec![0i16; 100]),
+			Some(segment("tone", false)),
+		);
+		output.by_ref().take(1000).for_each(drop);
+		player.log_started_segments();
+		assert_eq!(History::open(path).unwrap().plays()[0].end, PlayEnd::WrappedUp);
+		drop(player);
+		std::fs::remove_file(path).unwrap();
+	}
+}
@@ -0,0 +1,276 @@
+use std::{
+	collections::HashMap,
+	str::FromStr,
+	time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::{errors::DjError, Song};
+
+/// A boolean expression over song tags, eg. `calm and not menu or brb`.
+///
+/// `not` binds tighter than `and`, which binds tighter than `or`. Parentheses can be used for
+/// grouping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagQuery {
+	Tag(String),
+	Not(Box<TagQuery>),
+	And(Box<TagQuery>, Box<TagQuery>),
+	Or(Box<TagQuery>, Box<TagQuery>),
+}
+
+impl TagQuery {
+	pub fn matches(&self, tags: &[String]) -> bool {
+		match self {
+			TagQuery::Tag(tag) => tags.contains(tag),
+			TagQuery::Not(query) => !query.matches(tags),
+			TagQuery::And(a, b) => a.matches(tags) && b.matches(tags),
+			TagQuery::Or(a, b) => a.matches(tags) || b.matches(tags),
+		}
+	}
+}
+
+impl FromStr for TagQuery {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let spaced = s.replace('(', " ( ").replace(')', " ) ");
+		let tokens = spaced.split_whitespace().collect::<Vec<_>>();
+		let mut parser = QueryParser { tokens, pos: 0 };
+		let query = parser.parse_or()?;
+		match parser.tokens.get(parser.pos) {
+			Some(token) => Err(format!("unexpected '{}'", token)),
+			None => Ok(query),
+		}
+	}
+}
+
+struct QueryParser<'a> {
+	tokens: Vec<&'a str>,
+	pos: usize,
+}
+
+impl<'a> QueryParser<'a> {
+	fn next(&mut self) -> Option<&'a str> {
+		let token = self.tokens.get(self.pos).copied();
+		self.pos += 1;
+		token
+	}
+
+	fn peek(&self) -> Option<&'a str> {
+		self.tokens.get(self.pos).copied()
+	}
+
+	fn parse_or(&mut self) -> Result<TagQuery, String> {
+		let mut query = self.parse_and()?;
+		while self.peek() == Some("or") {
+			self.pos += 1;
+			query = TagQuery::Or(Box::new(query), Box::new(self.parse_and()?));
+		}
+		Ok(query)
+	}
+
+	fn parse_and(&mut self) -> Result<TagQuery, String> {
+		let mut query = self.parse_not()?;
+		while self.peek() == Some("and") {
+			self.pos += 1;
+			query = TagQuery::And(Box::new(query), Box::new(self.parse_not()?));
+		}
+		Ok(query)
+	}
+
+	fn parse_not(&mut self) -> Result<TagQuery, String> {
+		match self.next() {
+			Some("not") => Ok(TagQuery::Not(Box::new(self.parse_not()?))),
+			Some("(") => {
+				let query = self.parse_or()?;
+				match self.next() {
+					Some(")") => Ok(query),
+					_ => Err("missing ')'".to_string()),
+				}
+			}
+			Some(token @ ("and" | "or" | ")")) => Err(format!("unexpected '{}'", token)),
+			Some(tag) => Ok(TagQuery::Tag(tag.to_string())),
+			None => Err("unexpected end of query".to_string()),
+		}
+	}
+}
+
+/// Switches to `playlist` once the DJ has been running for `at`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledSwitch {
+	pub at: Duration,
+	pub playlist: String,
+}
+
+/// Named playlists, and which one songs are currently being picked from.
+#[derive(Debug)]
+pub struct Playlists {
+	playlists: HashMap<String, TagQuery>,
+	/// `None` means songs are picked from every song.
+	active: Option<String>,
+	/// Switches that haven't happened yet, ordered by time.
+	schedule: Vec<ScheduledSwitch>,
+	started: Instant,
+}
+
+impl Playlists {
+	pub fn new(
+		playlists: HashMap<String, TagQuery>, active: Option<String>, mut schedule: Vec<ScheduledSwitch>,
+	) -> Result<Playlists, DjError> {
+		for name in active.iter().chain(schedule.iter().map(|s| &s.playlist)) {
+			if !playlists.contains_key(name) {
+				return Err(DjError::UnknownPlaylist(name.to_string()));
+			}
+		}
+		schedule.sort_by_key(|s| s.at);
+		Ok(Playlists {
+			playlists,
+			active,
+			schedule,
+			started: Instant::now(),
+		})
+	}
+
+	pub fn active(&self) -> Option<&str> {
+		self.active.as_deref()
+	}
+
+	/// Changes the active playlist. The change applies from the next song on.
+	pub fn switch(&mut self, playlist: Option<&str>) -> Result<(), DjError> {
+		if let Some(name) = playlist {
+			if !self.playlists.contains_key(name) {
+				return Err(DjError::UnknownPlaylist(name.to_string()));
+			}
+		}
+		self.active = playlist.map(String::from);
+		Ok(())
+	}
+
+	/// Performs any scheduled switches that are due.
+	pub fn update_schedule(&mut self) {
+		let elapsed = self.started.elapsed();
+		while self.schedule.first().is_some_and(|s| s.at <= elapsed) {
+			let switch = self.schedule.remove(0);
+			info!(playlist = %switch.playlist, "Scheduled switch to playlist");
+			self.active = Some(switch.playlist);
+		}
+	}
+
+	/// Ids of the songs in the active playlist.
+	pub fn songs<'a>(&self, songs: &'a HashMap<String, Song>) -> Vec<&'a String> {
+		let query = match self.active.as_ref().and_then(|name| self.playlists.get(name)) {
+			Some(query) => query,
+			None => return songs.keys().collect(),
+		};
+		songs
+			.values()
+			.filter(|song| query.matches(&song.metadata.tags))
+			.map(|song| &song.id)
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod test_playlists {
+	use super::*;
+	use crate::map;
+
+	fn tags(tags: &[&str]) -> Vec<String> {
+		tags.iter().map(|t| t.to_string()).collect()
+	}
+
+	#[test]
+	fn test_parse_tag_query() {
+		assert_eq!("calm".parse(), Ok(TagQuery::Tag("calm".to_string())));
+		assert_eq!(
+			"calm and not menu or brb".parse(),
+			Ok(TagQuery::Or(
+				Box::new(TagQuery::And(
+					Box::new(TagQuery::Tag("calm".to_string())),
+					Box::new(TagQuery::Not(Box::new(TagQuery::Tag("menu".to_string())))),
+				)),
+				Box::new(TagQuery::Tag("brb".to_string())),
+			))
+		);
+		assert_eq!(
+			"calm and (menu or brb)".parse(),
+			Ok(TagQuery::And(
+				Box::new(TagQuery::Tag("calm".to_string())),
+				Box::new(TagQuery::Or(
+					Box::new(TagQuery::Tag("menu".to_string())),
+					Box::new(TagQuery::Tag("brb".to_string())),
+				)),
+			))
+		);
+		assert!("".parse::<TagQuery>().is_err());
+		assert!("calm and".parse::<TagQuery>().is_err());
+		assert!("(calm".parse::<TagQuery>().is_err());
+		assert!("calm menu".parse::<TagQuery>().is_err());
+	}
+
+	#[test]
+	fn test_tag_query_matches() {
+		let query = "calm and not menu or brb".parse::<TagQuery>().unwrap();
+		assert!(query.matches(&tags(&["calm"])));
+		assert!(!query.matches(&tags(&["calm", "menu"])));
+		assert!(query.matches(&tags(&["menu", "brb"])));
+		assert!(!query.matches(&tags(&[])));
+	}
+
+	#[test]
+	fn test_switch_playlist() {
+		let mut playlists = Playlists::new(
+			map!("calm".to_string() => TagQuery::Tag("calm".to_string())),
+			None,
+			vec![],
+		)
+		.unwrap();
+		assert_eq!(playlists.active(), None);
+		playlists.switch(Some("calm")).unwrap();
+		assert_eq!(playlists.active(), Some("calm"));
+		assert_eq!(
+			playlists.switch(Some("hype")),
+			Err(DjError::UnknownPlaylist("hype".to_string()))
+		);
+		assert_eq!(playlists.active(), Some("calm"));
+		playlists.switch(None).unwrap();
+		assert_eq!(playlists.active(), None);
+	}
+
+	#[test]
+	fn test_scheduled_switch() {
+		let mut playlists = Playlists::new(
+			map!(
+				"starting-soon".to_string() => TagQuery::Tag("menu".to_string()),
+				"calm".to_string() => TagQuery::Tag("calm".to_string()),
+			),
+			Some("calm".to_string()),
+			vec![
+				ScheduledSwitch {
+					at: Duration::from_secs(600),
+					playlist: "calm".to_string(),
+				},
+				ScheduledSwitch {
+					at: Duration::from_secs(0),
+					playlist: "starting-soon".to_string(),
+				},
+			],
+		)
+		.unwrap();
+		playlists.update_schedule();
+		assert_eq!(playlists.active(), Some("starting-soon"));
+		assert_eq!(playlists.schedule.len(), 1);
+
+		assert!(Playlists::new(
+			HashMap::new(),
+			None,
+			vec![ScheduledSwitch {
+				at: Duration::from_secs(0),
+				playlist: "calm".to_string(),
+			}]
+		)
+		.is_err());
+	}
+}
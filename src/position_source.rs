@@ -0,0 +1,195 @@
+use std::{
+	sync::{
+		atomic::{AtomicU32, AtomicU64, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use rodio::{Sample, Source};
+
+/// Shared playback position of whichever tracked source is currently being played.
+#[derive(Debug, Default)]
+pub struct PlaybackPosition {
+	samples: AtomicU64,
+	channels: AtomicU32,
+	sample_rate: AtomicU32,
+	/// Incremented to end whichever source is playing.
+	interruptions: AtomicU64,
+	/// How many tracked sources have started playing.
+	sources_started: AtomicU64,
+}
+
+impl PlaybackPosition {
+	pub fn new() -> Arc<PlaybackPosition> {
+		Arc::new(PlaybackPosition::default())
+	}
+
+	/// How long the current source has been playing for.
+	pub fn elapsed(&self) -> Duration {
+		let samples = self.samples.load(Ordering::Relaxed);
+		let channels = self.channels.load(Ordering::Relaxed) as u64;
+		let sample_rate = self.sample_rate.load(Ordering::Relaxed) as u64;
+		if channels == 0 || sample_rate == 0 {
+			return Duration::from_secs(0);
+		}
+		let frames = samples / channels;
+		Duration::from_secs(frames / sample_rate)
+			+ Duration::from_nanos(frames % sample_rate * 1_000_000_000 / sample_rate)
+	}
+
+	/// How many sources have started playing so far.
+	pub fn sources_started(&self) -> u64 {
+		self.sources_started.load(Ordering::Relaxed)
+	}
+
+	/// How many times playing sources have been ended early.
+	pub fn interruptions(&self) -> u64 {
+		self.interruptions.load(Ordering::Relaxed)
+	}
+
+	/// Ends the source that is currently playing, so that playback continues with the next one.
+	pub fn interrupt(&self) {
+		self.interruptions.fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn restart(&self, channels: u16, sample_rate: u32) {
+		self.samples.store(0, Ordering::Relaxed);
+		self.sources_started.fetch_add(1, Ordering::Relaxed);
+		self.channels.store(channels as u32, Ordering::Relaxed);
+		self.sample_rate.store(sample_rate, Ordering::Relaxed);
+	}
+}
+
+/// Internal function that builds a `Tracked` object.
+pub fn track<I>(input: I, position: Arc<PlaybackPosition>) -> Tracked<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	Tracked {
+		inner: input,
+		position,
+		started: false,
+		interruptions: 0,
+	}
+}
+
+/// A source that reports how far into it playback is to a `PlaybackPosition`.
+///
+/// The position is reset when the source yields its first sample, so the position always
+/// refers to the source that is actually being played, not the last one queued. The source ends
+/// early if the position is interrupted while it plays.
+pub struct Tracked<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	inner: I,
+	position: Arc<PlaybackPosition>,
+	started: bool,
+	/// The position's interruption count when this source started.
+	interruptions: u64,
+}
+
+impl<I> Iterator for Tracked<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	type Item = <I as Iterator>::Item;
+
+	#[inline]
+	fn next(&mut self) -> Option<Self::Item> {
+		if !self.started {
+			self.started = true;
+			self.interruptions = self.position.interruptions.load(Ordering::Relaxed);
+			self.position.restart(self.inner.channels(), self.inner.sample_rate());
+		}
+		// Only ends between frames, so that the channels of the next source don't get mixed up.
+		if self.position.interruptions.load(Ordering::Relaxed) != self.interruptions
+			&& self
+				.position
+				.samples
+				.load(Ordering::Relaxed)
+				.is_multiple_of(self.inner.channels().max(1) as u64)
+		{
+			return None;
+		}
+		let value = self.inner.next();
+		if value.is_some() {
+			self.position.samples.fetch_add(1, Ordering::Relaxed);
+		}
+		value
+	}
+
+	#[inline]
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		self.inner.size_hint()
+	}
+}
+
+impl<I> Source for Tracked<I>
+where
+	I: Source,
+	I::Item: Sample,
+{
+	#[inline]
+	fn current_frame_len(&self) -> Option<usize> {
+		self.inner.current_frame_len()
+	}
+
+	#[inline]
+	fn channels(&self) -> u16 {
+		self.inner.channels()
+	}
+
+	#[inline]
+	fn sample_rate(&self) -> u32 {
+		self.inner.sample_rate()
+	}
+
+	#[inline]
+	fn total_duration(&self) -> Option<Duration> {
+		self.inner.total_duration()
+	}
+}
+
+#[cfg(test)]
+mod test_position_source {
+	use super::*;
+	use rodio::source::Zero;
+
+	#[test]
+	fn test_tracks_elapsed_time() {
+		let position = PlaybackPosition::new();
+		let mut source = track(Zero::<f32>::new(2, 100), position.clone());
+		assert_eq!(position.elapsed(), Duration::from_secs(0));
+		for _ in 0..100 {
+			source.next();
+		}
+		assert_eq!(position.elapsed(), Duration::from_millis(500));
+
+		// a new source resets the position once it starts playing
+		let mut next_source = track(Zero::<f32>::new(1, 100), position.clone());
+		assert_eq!(position.elapsed(), Duration::from_millis(500));
+		next_source.next();
+		assert_eq!(position.elapsed(), Duration::from_millis(10));
+		assert_eq!(position.sources_started(), 2);
+	}
+
+	#[test]
+	fn test_interrupt() {
+		let position = PlaybackPosition::new();
+		let mut source = track(Zero::<f32>::new(2, 100), position.clone());
+		source.next();
+		position.interrupt();
+		// finishes the frame before ending
+		assert!(source.next().is_some());
+		assert!(source.next().is_none());
+
+		// sources that start after the interruption aren't affected
+		let next_source = track(Zero::<f32>::new(2, 100), position.clone());
+		assert_eq!(next_source.take(10).count(), 10);
+	}
+}
@@ -0,0 +1,502 @@
+use std::{
+	collections::HashMap,
+	sync::{
+		mpsc::{self, Receiver, Sender},
+		Arc,
+	},
+	thread,
+	time::Duration,
+};
+
+use rand::Rng;
+use rodio::{buffer::SamplesBuffer, source::Zero, Source};
+
+use crate::{
+	errors::DjError,
+	layered_source,
+	layered_source::Intensity,
+	plan::{Plan, PlanMode, SegmentDurations},
+	planner::{SegmentGraph, SongGraphs},
+	resample::{self, Chain, OutputFormat},
+	Song, SongSegment,
+};
+
+/// A source that is ready to be appended to the player.
+pub type QueuedSource = Box<dyn Source<Item = i16> + Send>;
+
+/// A song whose plan has been made and whose files have been read, ready to be played.
+pub struct PreparedSong {
+	pub song_id: String,
+	pub plan: Plan,
+	/// The sources to play, in order: for each entry of the plan, one for every time its segment
+	/// plays, and then the fade out if there is one. Unless it is layered, the first source has
+	/// already been decoded.
+	pub sources: Vec<Vec<QueuedSource>>,
+}
+
+/// Settings that affect how songs are prepared.
+#[derive(Clone)]
+pub struct PrepareOptions {
+	pub max_repeats: u32,
+	pub intensity: Arc<Intensity>,
+	/// The format that every segment is converted to.
+	pub format: OutputFormat,
+}
+
+/// Something for the prefetcher to prepare.
+enum Request {
+	/// A song, planned from one of its starts, or from its bridge from the previous song.
+	Song {
+		song_id: String,
+		mode: PlanMode,
+		previous: Option<String>,
+	},
+	/// The next part of an endless song's plan.
+	CarryOn {
+		song_id: String,
+		after: String,
+		wrap_up: bool,
+	},
+	/// A plan that has already been made, eg. one read from a file.
+	Replay(Plan),
+}
+
+/// Prepares songs on a background thread, so that the next song is ready to play as soon as the
+/// current one ends.
+pub struct Prefetcher {
+	requests: Sender<Request>,
+	prepared: Receiver<Result<PreparedSong, DjError>>,
+}
+
+impl Prefetcher {
+	pub fn spawn(songs: Arc<HashMap<String, Song>>, graphs: SongGraphs, options: PrepareOptions) -> Prefetcher {
+		let (requests, request_rx) = mpsc::channel::<Request>();
+		let (prepared_tx, prepared) = mpsc::channel();
+		thread::spawn(move || {
+			let mut rng = rand::thread_rng();
+			let mut durations = SegmentDurations::default();
+			for request in request_rx {
+				let prepared = match request {
+					Request::Song {
+						song_id,
+						mode,
+						previous,
+					} => find_song(&songs, &graphs, &song_id).and_then(|(song, graph)| {
+						prepare_song(
+							song,
+							graph,
+							mode,
+							previous.as_deref(),
+							&options,
+							&mut durations,
+							&mut rng,
+						)
+					}),
+					Request::CarryOn {
+						song_id,
+						after,
+						wrap_up,
+					} => find_song(&songs, &graphs, &song_id).and_then(|(song, graph)| {
+						carry_on(song, graph, &after, wrap_up, &options, &mut durations, &mut rng)
+					}),
+					Request::Replay(plan) => find_song(&songs, &graphs, &plan.song)
+						.and_then(|(song, graph)| prepare_plan(song, graph, plan, &options)),
+				};
+				if prepared_tx.send(prepared).is_err() {
+					break;
+				}
+			}
+		});
+		Prefetcher { requests, prepared }
+	}
+
+	/// Starts preparing a song in the background, to be played after the song `previous`.
+	pub fn request(&self, song_id: &str, mode: PlanMode, previous: Option<&str>) {
+		self.requests
+			.send(Request::Song {
+				song_id: song_id.to_string(),
+				mode,
+				previous: previous.map(str::to_string),
+			})
+			.unwrap();
+	}
+
+	/// Starts preparing the next part of an endless song in the background, carrying on after the
+	/// segment `after`, or heading for an ending if `wrap_up` is set.
+	pub fn carry_on(&self, song_id: &str, after: &str, wrap_up: bool) {
+		self.requests
+			.send(Request::CarryOn {
+				song_id: song_id.to_string(),
+				after: after.to_string(),
+				wrap_up,
+			})
+			.unwrap();
+	}
+
+	/// Starts reading the segments of a plan that has already been made in the background.
+	pub fn replay(&self, plan: Plan) {
+		self.requests.send(Request::Replay(plan)).unwrap();
+	}
+
+	/// Waits for the song that was requested first to be ready.
+	pub fn take(&self) -> Result<PreparedSong, DjError> {
+		self.prepared.recv().unwrap()
+	}
+}
+
+/// Looks up a song and its graph, failing for songs that weren't found or couldn't be planned.
+fn find_song<'a>(
+	songs: &'a HashMap<String, Song>, graphs: &'a SongGraphs, song_id: &str,
+) -> Result<(&'a Song, &'a SegmentGraph), DjError> {
+	match (songs.get(song_id), graphs.get(song_id)) {
+		(Some(song), Some(graph)) => Ok((song, graph)),
+		_ => Err(DjError::UnknownSong(song_id.to_string())),
+	}
+}
+
+/// Makes a plan for the song and reads every segment in it.
+pub fn prepare_song<R: Rng + ?Sized>(
+	song: &Song, graph: &SegmentGraph, mode: PlanMode, previous_song: Option<&str>, options: &PrepareOptions,
+	durations: &mut SegmentDurations, rng: &mut R,
+) -> Result<PreparedSong, DjError> {
+	let plan = Plan::make(song, graph, mode, previous_song, options.max_repeats, durations, rng)?;
+	prepare_plan(song, graph, plan, options)
+}
+
+/// Makes the next part of an endless song's plan and reads every segment in it.
+pub fn carry_on<R: Rng + ?Sized>(
+	song: &Song, graph: &SegmentGraph, after: &str, wrap_up: bool, options: &PrepareOptions,
+	durations: &mut SegmentDurations, rng: &mut R,
+) -> Result<PreparedSong, DjError> {
+	let plan = Plan::carry_on(song, graph, after, wrap_up, options.max_repeats, durations, rng)?;
+	prepare_plan(song, graph, plan, options)
+}
+
+/// Reads every segment in a plan that has already been made, so that it can be played again.
+pub fn prepare_plan(
+	song: &Song, graph: &SegmentGraph, plan: Plan, options: &PrepareOptions,
+) -> Result<PreparedSong, DjError> {
+	plan.validate(song, graph)?;
+	let mut converter = Converter::new(options.format);
+	let mut sources: Vec<Vec<QueuedSource>> = Vec::new();
+	for (i, entry) in plan.entries.iter().enumerate() {
+		let segment = &song.segments[&entry.id];
+		let mut plays = read_segment(song, segment, entry.repeat_count.unwrap_or(1), &mut converter, options)?;
+		let crossfade = match entry.crossfade {
+			Some(crossfade) => Duration::from_secs_f64(crossfade),
+			None => {
+				sources.push(plays);
+				continue;
+			}
+		};
+		// The segment before stops early, and its end plays again fading out under this one.
+		let previous = &plan.entries[i - 1];
+		let single = previous.duration / previous.repeat_count.unwrap_or(1) as f64;
+		let previous_plays = sources.last_mut().unwrap();
+		let cut_short = previous_plays
+			.pop()
+			.unwrap()
+			.take_duration(Duration::from_secs_f64((single - crossfade.as_secs_f64()).max(0.0)));
+		previous_plays.push(Box::new(cut_short));
+		let tail = read_tail(song, &song.segments[&previous.id], crossfade, options)?;
+		let empty_source: Zero<f32> = Zero::new(tail.channels(), tail.sample_rate());
+		let first = plays.remove(0);
+		plays.insert(
+			0,
+			Box::new(
+				first
+					.fade_in(crossfade)
+					.mix(tail.take_crossfade_with(empty_source, crossfade)),
+			),
+		);
+		sources.push(plays);
+	}
+	if let Some(fade_out) = plan.fade_out {
+		let last = &song.segments[&plan.entries.last().unwrap().id];
+		let source_end = read_segment(song, last, 1, &mut converter, options)?.remove(0);
+		let empty_source: Zero<f32> = Zero::new(source_end.channels(), source_end.sample_rate());
+		sources.push(vec![Box::new(
+			source_end.take_crossfade_with(empty_source, Duration::from_secs_f64(fade_out)),
+		)]);
+	}
+	// Layered segments are mixed as they play, so that they follow changes in intensity.
+	if song.segments[&plan.entries[0].id].layers.is_empty() {
+		let first = sources[0].remove(0);
+		sources[0].insert(0, decode(first));
+	}
+	Ok(PreparedSong {
+		song_id: song.id.to_string(),
+		plan,
+		sources,
+	})
+}
+
+/// Converts the segments of a plan to the output format, carrying the resampler on from one
+/// segment to the next so that there are no filter edges between them.
+struct Converter {
+	format: OutputFormat,
+	chain: Option<Chain>,
+}
+
+impl Converter {
+	fn new(format: OutputFormat) -> Converter {
+		Converter { format, chain: None }
+	}
+
+	/// Converts a source that plays straight after the one converted before it.
+	fn convert<I>(&mut self, source: I) -> Result<QueuedSource, DjError>
+	where
+		I: Source<Item = i16> + Send + 'static,
+	{
+		if source.sample_rate() == self.format.sample_rate {
+			self.chain = None;
+			return Ok(Box::new(resample::convert(source, self.format)?));
+		}
+		if !self.chain.as_ref().is_some_and(|chain| chain.accepts(&source)) {
+			self.chain = Some(Chain::new(source.channels(), source.sample_rate(), self.format)?);
+		}
+		Ok(Box::new(self.chain.as_mut().unwrap().push(source)))
+	}
+
+	/// Starts over for the next source, which doesn't carry on from the one before it.
+	fn start_over(&mut self) {
+		self.chain = None;
+	}
+}
+
+/// Reads a segment, with a source for each of the `plays` times it plays. The resampler carries
+/// on from the end of one play to the start of the next.
+fn read_segment(
+	song: &Song, segment: &SongSegment, plays: u32, converter: &mut Converter, options: &PrepareOptions,
+) -> Result<Vec<QueuedSource>, DjError> {
+	if segment.layers.is_empty() {
+		let source = song.read_segment(segment)?.buffered();
+		return (0..plays).map(|_| converter.convert(source.clone())).collect();
+	}
+	converter.start_over();
+	let (always_on, layers) = song.read_layers(segment)?;
+	// Each layer has its own resampler, carried on from one play to the next.
+	let mut always_on = always_on
+		.into_iter()
+		.map(|source| (source.buffered(), Converter::new(options.format)))
+		.collect::<Vec<_>>();
+	let mut layers = layers
+		.into_iter()
+		.map(|source| (source.buffered(), Converter::new(options.format)))
+		.collect::<Vec<_>>();
+	(0..plays)
+		.map(|_| {
+			let always_on = always_on
+				.iter_mut()
+				.map(|(source, converter)| converter.convert(source.clone()))
+				.collect::<Result<Vec<_>, _>>()?;
+			let layers = layers
+				.iter_mut()
+				.map(|(source, converter)| converter.convert(source.clone()))
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(Box::new(layered_source::mix_layers(always_on, layers, options.intensity.clone())) as QueuedSource)
+		})
+		.collect()
+}
+
+/// Reads the last `duration` of one play of a segment.
+fn read_tail(
+	song: &Song, segment: &SongSegment, duration: Duration, options: &PrepareOptions,
+) -> Result<SamplesBuffer<i16>, DjError> {
+	let channels = options.format.channels;
+	let sample_rate = options.format.sample_rate;
+	let mut converter = Converter::new(options.format);
+	let mut samples = read_segment(song, segment, 1, &mut converter, options)?
+		.remove(0)
+		.collect::<Vec<_>>();
+	let frames = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+	let len = (frames * channels as usize).min(samples.len());
+	let tail = samples.split_off(samples.len() - len);
+	Ok(SamplesBuffer::new(channels, sample_rate, tail))
+}
+
+/// Decodes the whole source into memory, so that playing it doesn't need any decoding.
+fn decode(source: QueuedSource) -> QueuedSource {
+	let channels = source.channels();
+	let sample_rate = source.sample_rate();
+	Box::new(SamplesBuffer::new(channels, sample_rate, source.collect::<Vec<_>>()))
+}
+
+#[cfg(test)]
+mod test_prefetch {
+	use super::*;
+	use crate::{initialize_songs, initialize_transitions};
+
+	#[test]
+	fn test_unknown_song() {
+		let prefetcher = Prefetcher::spawn(
+			Arc::new(HashMap::new()),
+			HashMap::new(),
+			PrepareOptions {
+				max_repeats: 6,
+				intensity: Intensity::new(1.0),
+				format: OutputFormat {
+					channels: 1,
+					sample_rate: 8000,
+				},
+			},
+		);
+		prefetcher.request("missing", PlanMode::Full, None);
+		assert_eq!(
+			prefetcher.take().err(),
+			Some(DjError::UnknownSong("missing".to_string()))
+		);
+	}
+
+	#[test]
+	fn test_prepare_song() {
+		let mut songs = initialize_songs(&[
+			"test-data/test_song_audio/tone_start.wav",
+			"test-data/test_song_audio/tone_loop.wav",
+		])
+		.unwrap();
+		initialize_transitions(&mut songs);
+		let options = PrepareOptions {
+			max_repeats: 6,
+			intensity: Intensity::new(1.0),
+			format: OutputFormat {
+				channels: 1,
+				sample_rate: 8000,
+			},
+		};
+		let graph = SegmentGraph::new(&songs["tone"]).unwrap();
+		let mut durations = SegmentDurations::default();
+		let prepared = prepare_song(
+			&songs["tone"],
+			&graph,
+			PlanMode::Full,
+			None,
+			&options,
+			&mut durations,
+			&mut rand::thread_rng(),
+		)
+		.unwrap();
+		assert_eq!(prepared.song_id, "tone");
+		assert_eq!(
+			prepared
+				.plan
+				.entries
+				.iter()
+				.map(|entry| (entry.id.as_str(), entry.repeat_count))
+				.collect::<Vec<_>>(),
+			vec![("start", None), ("loop", Some(5))]
+		);
+		// The song has no end, so the loop is played once more while fading out.
+		assert_eq!(prepared.sources.len(), 3);
+		let lengths = prepared
+			.sources
+			.into_iter()
+			.take(2)
+			.map(|plays| plays.into_iter().map(|s| s.count()).collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+		// Each play of the loop is a source of its own.
+		assert_eq!(lengths, vec![vec![400], vec![400; 5]]);
+	}
+
+	#[test]
+	fn test_replay_plan() {
+		let mut songs = initialize_songs(&[
+			"test-data/test_song_audio/tone_start.wav",
+			"test-data/test_song_audio/tone_loop.wav",
+		])
+		.unwrap();
+		initialize_transitions(&mut songs);
+		let song = &songs["tone"];
+		let graph = SegmentGraph::new(song).unwrap();
+		let plan: Plan = serde_json::from_str(
+			r#"{"song":"tone","entries":[{"id":"start","start":0,"duration":0.05},{"id":"loop","repeat_count":2,"start":0.05,"duration":0.1}],"fade_out":8}"#,
+		)
+		.unwrap();
+		let options = PrepareOptions {
+			max_repeats: 6,
+			intensity: Intensity::new(1.0),
+			format: OutputFormat {
+				channels: 1,
+				sample_rate: 8000,
+			},
+		};
+		let prepared = prepare_plan(song, &graph, plan.clone(), &options).unwrap();
+		assert_eq!(prepared.plan, plan);
+		let lengths = prepared
+			.sources
+			.into_iter()
+			.map(|plays| plays.into_iter().map(|s| s.count()).collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+		assert_eq!(lengths[..2], [vec![400], vec![400, 400]]);
+		// Give or take a sample of rounding.
+		assert!((lengths[2][0] as i64 - 8000 * 8).abs() <= 1);
+	}
+
+	#[test]
+	fn test_crossfade_cut() {
+		let dir = std::env::temp_dir().join(format!("autodj-test-crossfade-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let audio = "test-data/test_song_audio";
+		std::fs::copy(format!("{}/tone_start.wav", audio), dir.join("mix_start.wav")).unwrap();
+		for segment in &["loop0", "loop1", "loop0-to-1"] {
+			std::fs::copy(
+				format!("{}/tone_loop.wav", audio),
+				dir.join(format!("mix_{}.wav", segment)),
+			)
+			.unwrap();
+		}
+		std::fs::write(dir.join("mix.toml"), "transitions = \"mixed\"\ncrossfade = 0.01\n").unwrap();
+		let paths = std::fs::read_dir(&dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().path().display().to_string())
+			.collect::<Vec<_>>();
+		let mut songs = initialize_songs(&paths).unwrap();
+		initialize_transitions(&mut songs);
+
+		let song = &songs["mix"];
+		assert!(!song.segments["loop0"].allowed_transitions.contains("loop1"));
+		assert!(song.segments["loop1"].allowed_transitions.contains("loop0"));
+		let graph = SegmentGraph::new(song).unwrap();
+		let plan: Plan = serde_json::from_str(
+			r#"{"song":"mix","entries":[
+				{"id":"start","start":0,"duration":0.05},
+				{"id":"loop0","repeat_count":2,"start":0.05,"duration":0.1},
+				{"id":"loop0-to-1","start":0.15,"duration":0.05},
+				{"id":"loop1","repeat_count":2,"start":0.2,"duration":0.1},
+				{"id":"loop0","repeat_count":2,"start":0.29,"duration":0.1,"crossfade":0.01}
+			],"fade_out":8}"#,
+		)
+		.unwrap();
+		let options = PrepareOptions {
+			max_repeats: 6,
+			intensity: Intensity::new(1.0),
+			format: OutputFormat {
+				channels: 1,
+				sample_rate: 8000,
+			},
+		};
+		let prepared = prepare_plan(song, &graph, plan, &options).unwrap();
+		let lengths = prepared
+			.sources
+			.into_iter()
+			.take(5)
+			.map(|plays| plays.into_iter().map(|s| s.count()).collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(lengths[..3], [vec![400], vec![400, 400], vec![400]]);
+		// The last play of loop1 is cut short by the crossfade, which plays under the start of loop0.
+		// rodio's take_duration can stop a sample early.
+		assert_eq!(lengths[3][0], 400);
+		assert!((400 - 80 - 1..=400 - 80).contains(&lengths[3][1]), "{:?}", lengths);
+		assert_eq!(lengths[4], [400, 400]);
+	}
+
+	#[test]
+	fn test_decode() {
+		let source: QueuedSource = Box::new(SamplesBuffer::new(2, 48000, vec![1i16, 2, 3, 4]));
+		let decoded = decode(source);
+		assert_eq!(decoded.channels(), 2);
+		assert_eq!(decoded.sample_rate(), 48000);
+		assert_eq!(decoded.collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+	}
+}
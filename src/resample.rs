@@ -0,0 +1,604 @@
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use rodio::Source;
+use rubato::{FftFixedIn, Resampler};
+
+use crate::errors::DjError;
+
+/// Number of input frames the resampler works on at a time.
+const CHUNK_SIZE: usize = 1024;
+/// Number of samples a part of a chain takes from it at a time.
+const PART_CHUNK_SIZE: usize = 1024;
+
+/// The sample rate and channel count that every segment is converted to before playing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+	pub channels: u16,
+	pub sample_rate: u32,
+}
+
+impl Default for OutputFormat {
+	fn default() -> OutputFormat {
+		OutputFormat {
+			channels: 2,
+			sample_rate: 48000,
+		}
+	}
+}
+
+/// Converts a source to the given output format, so that segments with different formats can be
+/// played back to back and crossfaded.
+///
+/// Sources that are already in the right format are passed through untouched.
+pub fn convert<I>(input: I, format: OutputFormat) -> Result<Converted<I>, DjError>
+where
+	I: Source<Item = i16>,
+{
+	let in_channels = input.channels();
+	let in_rate = input.sample_rate();
+	let resampler = if in_rate == format.sample_rate {
+		None
+	}
+	else {
+		Some(
+			FftFixedIn::<f32>::new(
+				in_rate as usize,
+				format.sample_rate as usize,
+				CHUNK_SIZE,
+				2,
+				format.channels as usize,
+			)
+			.map_err(|e| DjError::UnsupportedSampleRate(in_rate, format.sample_rate, e.to_string()))?,
+		)
+	};
+	let delay = resampler.as_ref().map_or(0, |r| r.output_delay());
+	let (chunk, resampled) = match &resampler {
+		Some(resampler) => (
+			resampler.input_buffer_allocate(false),
+			resampler.output_buffer_allocate(true),
+		),
+		None => (Vec::new(), Vec::new()),
+	};
+	Ok(Converted {
+		passthrough: resampler.is_none() && in_channels == format.channels,
+		input,
+		in_channels,
+		in_rate,
+		format,
+		resampler,
+		output: VecDeque::new(),
+		input_frame: Vec::with_capacity(in_channels as usize),
+		frame: Vec::with_capacity(format.channels as usize),
+		chunk,
+		resampled,
+		delay_remaining: delay,
+		input_frames: 0,
+		output_frames: 0,
+		input_done: false,
+	})
+}
+
+pub struct Converted<I> {
+	input: I,
+	in_channels: u16,
+	in_rate: u32,
+	format: OutputFormat,
+	resampler: Option<FftFixedIn<f32>>,
+	/// Whether the input is already in the output format.
+	passthrough: bool,
+	/// Converted samples that haven't been played yet.
+	output: VecDeque<i16>,
+	/// Buffers reused for every frame and chunk, so that nothing is allocated while playing.
+	input_frame: Vec<i16>,
+	frame: Vec<i16>,
+	chunk: Vec<Vec<f32>>,
+	resampled: Vec<Vec<f32>>,
+	/// The resampler's output starts with this many frames of latency, which are dropped.
+	delay_remaining: usize,
+	input_frames: u64,
+	output_frames: u64,
+	input_done: bool,
+}
+
+impl<I> Converted<I>
+where
+	I: Source<Item = i16>,
+{
+	/// Reads one frame from the input into `frame`, converted to the output channel count.
+	fn read_frame(&mut self) -> bool {
+		self.input_frame.clear();
+		for _ in 0..self.in_channels {
+			match self.input.next() {
+				Some(sample) => self.input_frame.push(sample),
+				None => return false,
+			}
+		}
+		self.frame.clear();
+		map_channels(&self.input_frame, self.format.channels, &mut self.frame);
+		true
+	}
+
+	/// Resamples the next chunk of input. Returns false once everything has been converted.
+	fn resample_chunk(&mut self) -> bool {
+		let channels = self.format.channels as usize;
+		if self.input_done && self.output_frames >= self.expected_output_frames() {
+			return false;
+		}
+		let needed = self.resampler.as_ref().unwrap().input_frames_next();
+		for channel in &mut self.chunk {
+			channel.clear();
+		}
+		let mut frames = 0;
+		while frames < needed && !self.input_done {
+			if self.read_frame() {
+				for (channel, sample) in self.chunk.iter_mut().zip(&self.frame) {
+					channel.push(*sample as f32 / 32768.0);
+				}
+				frames += 1;
+			}
+			else {
+				self.input_done = true;
+			}
+		}
+		self.input_frames += frames as u64;
+		// The end of the input is padded with silence, which also pushes out the resampler's delay.
+		for channel in &mut self.chunk {
+			channel.resize(needed, 0.0);
+		}
+
+		let resampler = self.resampler.as_mut().unwrap();
+		let (_, output_frames) = resampler
+			.process_into_buffer(&self.chunk, &mut self.resampled, None)
+			.unwrap();
+
+		let expected = self.expected_output_frames();
+		for i in 0..output_frames {
+			if self.delay_remaining > 0 {
+				self.delay_remaining -= 1;
+				continue;
+			}
+			if self.input_done && self.output_frames >= expected {
+				break;
+			}
+			for channel in &self.resampled[..channels] {
+				self.output
+					.push_back((channel[i] * 32768.0).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+			}
+			self.output_frames += 1;
+		}
+		true
+	}
+
+	/// How many frames the output should have in total. Only known once the input has ended.
+	fn expected_output_frames(&self) -> u64 {
+		(self.input_frames * self.format.sample_rate as u64).div_ceil(self.in_rate as u64)
+	}
+}
+
+/// Maps a frame onto a different number of channels. Mono is spread over every channel, and
+/// downmixing to mono averages the channels. Otherwise, channels are repeated or dropped.
+fn map_channels(input: &[i16], channels: u16, output: &mut Vec<i16>) {
+	let channels = channels as usize;
+	if input.len() == channels {
+		output.extend_from_slice(input);
+	}
+	else if channels == 1 {
+		let sum = input.iter().map(|s| *s as i32).sum::<i32>();
+		output.push((sum / input.len() as i32) as i16);
+	}
+	else {
+		output.extend((0..channels).map(|c| input[c % input.len()]));
+	}
+}
+
+impl<I> Iterator for Converted<I>
+where
+	I: Source<Item = i16>,
+{
+	type Item = i16;
+
+	fn next(&mut self) -> Option<i16> {
+		if self.passthrough {
+			return self.input.next();
+		}
+		loop {
+			if let Some(sample) = self.output.pop_front() {
+				return Some(sample);
+			}
+			if self.resampler.is_some() {
+				if !self.resample_chunk() {
+					return None;
+				}
+			}
+			else {
+				if !self.read_frame() {
+					return None;
+				}
+				self.output.extend(&self.frame);
+			}
+		}
+	}
+}
+
+impl<I> Source for Converted<I>
+where
+	I: Source<Item = i16>,
+{
+	fn current_frame_len(&self) -> Option<usize> {
+		None
+	}
+
+	fn channels(&self) -> u16 {
+		self.format.channels
+	}
+
+	fn sample_rate(&self) -> u32 {
+		self.format.sample_rate
+	}
+
+	fn total_duration(&self) -> Option<Duration> {
+		self.input.total_duration()
+	}
+}
+
+/// Resamples sources that are played back to back as one continuous stream, so that there is no
+/// filter edge where one ends and the next begins. Each source added gets the part of the output
+/// that lines up with it, and the parts are played in the order they were added.
+///
+/// Every source has to be added before any of the parts is played.
+pub struct Chain {
+	state: Arc<Mutex<ChainState>>,
+	in_channels: u16,
+	in_rate: u32,
+	parts: usize,
+}
+
+struct ChainState {
+	converted: Converted<ChainInput>,
+	/// How many samples of the output have been taken so far.
+	taken: u64,
+}
+
+/// The sources of a chain, one after another.
+struct ChainInput {
+	/// The sources that haven't ended yet. Sources whose part was dropped before they ended are
+	/// left out.
+	sources: VecDeque<Option<Box<dyn Iterator<Item = i16> + Send>>>,
+	channels: u16,
+	sample_rate: u32,
+	samples: u64,
+	/// How many frames had been read when each of the sources ended.
+	ends: Vec<u64>,
+}
+
+impl Chain {
+	/// Starts a chain of sources with this sample rate and channel count.
+	pub fn new(in_channels: u16, in_rate: u32, format: OutputFormat) -> Result<Chain, DjError> {
+		let input = ChainInput {
+			sources: VecDeque::new(),
+			channels: in_channels,
+			sample_rate: in_rate,
+			samples: 0,
+			ends: Vec::new(),
+		};
+		Ok(Chain {
+			state: Arc::new(Mutex::new(ChainState {
+				converted: convert(input, format)?,
+				taken: 0,
+			})),
+			in_channels,
+			in_rate,
+			parts: 0,
+		})
+	}
+
+	/// Whether the source can carry on the chain, which it can if its format matches.
+	pub fn accepts<I: Source<Item = i16>>(&self, source: &I) -> bool {
+		source.channels() == self.in_channels && source.sample_rate() == self.in_rate
+	}
+
+	/// Adds a source to the end of the chain, and returns its part of the output.
+	pub fn push<I>(&mut self, source: I) -> ChainPart
+	where
+		I: Source<Item = i16> + Send + 'static,
+	{
+		let mut state = self.state.lock().unwrap();
+		state.converted.input.sources.push_back(Some(Box::new(source)));
+		let format = state.converted.format;
+		self.parts += 1;
+		ChainPart {
+			state: self.state.clone(),
+			index: self.parts - 1,
+			format,
+			taken: VecDeque::with_capacity(PART_CHUNK_SIZE),
+			done: false,
+		}
+	}
+}
+
+impl ChainState {
+	/// The index in the output of the first sample of a part, once it is known where the part
+	/// before it ends.
+	fn part_start(&self, index: usize) -> Option<u64> {
+		if index == 0 {
+			return Some(0);
+		}
+		let in_frames = *self.converted.input.ends.get(index - 1)?;
+		let format = self.converted.format;
+		let out_frames = (in_frames * format.sample_rate as u64).div_ceil(self.converted.in_rate as u64);
+		Some(out_frames * format.channels as u64)
+	}
+
+	/// Moves up to `PART_CHUNK_SIZE` samples of a part into `out`. Returns false once the part
+	/// has ended. Samples of earlier parts that weren't played, eg. because they were cut short,
+	/// are skipped.
+	fn take(&mut self, index: usize, out: &mut VecDeque<i16>) -> bool {
+		while out.len() < PART_CHUNK_SIZE {
+			let end = self.part_start(index + 1);
+			if end.is_some_and(|end| self.taken >= end) {
+				return !out.is_empty();
+			}
+			let sample = match self.converted.next() {
+				Some(sample) => sample,
+				None => return !out.is_empty(),
+			};
+			// The start is always known by the time the output reaches it, since the input is
+			// read ahead of the output.
+			if self.part_start(index).is_some_and(|start| self.taken >= start) {
+				out.push_back(sample);
+			}
+			self.taken += 1;
+		}
+		true
+	}
+}
+
+impl Iterator for ChainInput {
+	type Item = i16;
+
+	fn next(&mut self) -> Option<i16> {
+		while let Some(source) = self.sources.front_mut() {
+			if let Some(sample) = source.as_mut().and_then(Iterator::next) {
+				self.samples += 1;
+				return Some(sample);
+			}
+			self.sources.pop_front();
+			self.ends.push(self.samples / self.channels as u64);
+		}
+		None
+	}
+}
+
+impl Source for ChainInput {
+	fn current_frame_len(&self) -> Option<usize> {
+		None
+	}
+
+	fn channels(&self) -> u16 {
+		self.channels
+	}
+
+	fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+
+	fn total_duration(&self) -> Option<Duration> {
+		None
+	}
+}
+
+/// The output of a chain that lines up with one of its sources.
+pub struct ChainPart {
+	state: Arc<Mutex<ChainState>>,
+	index: usize,
+	format: OutputFormat,
+	/// Samples taken from the chain that haven't been played yet, so that the chain isn't locked
+	/// for every sample.
+	taken: VecDeque<i16>,
+	done: bool,
+}
+
+impl Iterator for ChainPart {
+	type Item = i16;
+
+	fn next(&mut self) -> Option<i16> {
+		if self.taken.is_empty() && !self.done {
+			self.done = !self.state.lock().unwrap().take(self.index, &mut self.taken);
+		}
+		self.taken.pop_front()
+	}
+}
+
+impl Drop for ChainPart {
+	/// Leaves out the rest of the source once its part has stopped playing, eg. because it was
+	/// interrupted, so that the next part doesn't have to get through it first.
+	fn drop(&mut self) {
+		let mut state = match self.state.lock() {
+			Ok(state) => state,
+			Err(_) => return,
+		};
+		let input = &mut state.converted.input;
+		if let Some(source) = self
+			.index
+			.checked_sub(input.ends.len())
+			.and_then(|i| input.sources.get_mut(i))
+		{
+			*source = None;
+		}
+	}
+}
+
+impl Source for ChainPart {
+	fn current_frame_len(&self) -> Option<usize> {
+		None
+	}
+
+	fn channels(&self) -> u16 {
+		self.format.channels
+	}
+
+	fn sample_rate(&self) -> u32 {
+		self.format.sample_rate
+	}
+
+	fn total_duration(&self) -> Option<Duration> {
+		None
+	}
+}
+
+#[cfg(test)]
+mod test_resample {
+	use super::*;
+	use rodio::buffer::SamplesBuffer;
+
+	fn tone(channels: u16, sample_rate: u32, frames: usize) -> SamplesBuffer<i16> {
+		let samples = (0..frames)
+			.flat_map(|i| {
+				let t = i as f32 / sample_rate as f32;
+				let s = ((t * 440.0 * std::f32::consts::TAU).sin() * 8000.0) as i16;
+				std::iter::repeat_n(s, channels as usize)
+			})
+			.collect::<Vec<_>>();
+		SamplesBuffer::new(channels, sample_rate, samples)
+	}
+
+	#[test]
+	fn test_passthrough() {
+		let format = OutputFormat {
+			channels: 2,
+			sample_rate: 48000,
+		};
+		let converted = convert(tone(2, 48000, 100), format).unwrap().collect::<Vec<_>>();
+		assert_eq!(converted, tone(2, 48000, 100).collect::<Vec<_>>());
+	}
+
+	/// A broken source that claims to have no samples per second.
+	struct NoRate;
+
+	impl Iterator for NoRate {
+		type Item = i16;
+
+		fn next(&mut self) -> Option<i16> {
+			None
+		}
+	}
+
+	impl Source for NoRate {
+		fn current_frame_len(&self) -> Option<usize> {
+			None
+		}
+
+		fn channels(&self) -> u16 {
+			1
+		}
+
+		fn sample_rate(&self) -> u32 {
+			0
+		}
+
+		fn total_duration(&self) -> Option<Duration> {
+			None
+		}
+	}
+
+	#[test]
+	fn test_unsupported_sample_rate() {
+		assert!(matches!(
+			convert(NoRate, OutputFormat::default()),
+			Err(DjError::UnsupportedSampleRate(0, 48000, _))
+		));
+	}
+
+	#[test]
+	fn test_channel_conversion() {
+		let mut output = Vec::new();
+		map_channels(&[1], 2, &mut output);
+		assert_eq!(output, vec![1, 1]);
+		output.clear();
+		map_channels(&[100, 200], 1, &mut output);
+		assert_eq!(output, vec![150]);
+		output.clear();
+		map_channels(&[1, 2, 3, 4, 5, 6], 2, &mut output);
+		assert_eq!(output, vec![1, 2]);
+	}
+
+	#[test]
+	fn test_resample_length() {
+		for (in_rate, frames) in [(44100, 44100), (8000, 400), (96000, 12345)] {
+			let format = OutputFormat::default();
+			let converted = convert(tone(1, in_rate, frames), format).unwrap();
+			assert_eq!(converted.channels(), 2);
+			assert_eq!(converted.sample_rate(), 48000);
+			let expected = (frames as u64 * 48000).div_ceil(in_rate as u64) as usize;
+			assert_eq!(converted.count(), expected * 2, "converting from {} Hz", in_rate);
+		}
+	}
+
+	#[test]
+	fn test_resample_preserves_signal() {
+		let converted = convert(tone(1, 44100, 44100), OutputFormat::default())
+			.unwrap()
+			.collect::<Vec<_>>();
+		let expected = tone(2, 48000, 48000).collect::<Vec<_>>();
+		// Skip the edges, where the resampler's filter has nothing to work with.
+		let error = converted[2000..90000]
+			.iter()
+			.zip(&expected[2000..90000])
+			.map(|(a, b)| (*a as i32 - *b as i32).abs())
+			.max()
+			.unwrap();
+		assert!(error < 100, "max error {}", error);
+	}
+
+	#[test]
+	fn test_chain_repeated_loop() {
+		let format = OutputFormat::default();
+		let lp = tone(1, 44100, 4410).buffered();
+		let mut chain = Chain::new(1, 44100, format).unwrap();
+		let parts = (0..4).map(|_| chain.push(lp.clone())).collect::<Vec<_>>();
+		let played = parts
+			.into_iter()
+			.map(|part| part.collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+		assert_eq!(played[0].len(), 4800 * 2);
+		let chained = played.concat();
+		let samples = (0..4).flat_map(|_| lp.clone()).collect::<Vec<_>>();
+		let whole = convert(SamplesBuffer::new(1, 44100, samples), format)
+			.unwrap()
+			.collect::<Vec<_>>();
+		assert_eq!(chained, whole);
+	}
+
+	#[test]
+	fn test_chain_cut_short() {
+		let format = OutputFormat::default();
+		let mut chain = Chain::new(1, 8000, format).unwrap();
+		assert!(chain.accepts(&tone(1, 8000, 1)));
+		assert!(!chain.accepts(&tone(2, 8000, 1)));
+		let mut first = chain.push(tone(1, 8000, 4000));
+		let second = chain.push(tone(1, 8000, 4000));
+		assert_eq!(first.by_ref().take(1000).count(), 1000);
+		let samples = tone(1, 8000, 4000).chain(tone(1, 8000, 4000)).collect::<Vec<_>>();
+		let whole = convert(SamplesBuffer::new(1, 8000, samples), format)
+			.unwrap()
+			.collect::<Vec<_>>();
+		assert_eq!(second.collect::<Vec<_>>(), whole[48000..]);
+	}
+
+	#[test]
+	fn test_chain_dropped_part() {
+		let format = OutputFormat::default();
+		let mut chain = Chain::new(1, 8000, format).unwrap();
+		let mut first = chain.push(tone(1, 8000, 4000));
+		let second = chain.push(tone(1, 8000, 4000));
+		assert_eq!(first.by_ref().take(1000).count(), 1000);
+		drop(first);
+		// Only what the resampler had already read of the first source is left to skip.
+		let second = second.collect::<Vec<_>>();
+		assert!((47000..=48000).contains(&second.len()), "{}", second.len());
+	}
+}
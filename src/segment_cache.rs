@@ -0,0 +1,296 @@
+use std::{
+	collections::HashMap,
+	fmt,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use crate::{errors::DjError, song_source::SongSource};
+
+/// The contents of a song file, shared between the cache and everything playing it.
+pub type SegmentData = Arc<[u8]>;
+
+pub const MIB: usize = 1024 * 1024;
+
+/// Keeps recently played song files in memory, so that repeated loops and frequently played
+/// songs don't have to be read from disk again.
+///
+/// Files are evicted least recently used first once the cache grows past its budget. Files that
+/// are bigger than the whole budget are never cached.
+#[derive(Debug)]
+pub struct SegmentCache {
+	budget: usize,
+	state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+	/// Entries by the path of the file they were read from.
+	entries: HashMap<PathBuf, CacheEntry>,
+	used: usize,
+	/// Incremented on every lookup, used to find the least recently used entry.
+	clock: u64,
+	hits: u64,
+	misses: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+	data: SegmentData,
+	last_used: u64,
+}
+
+/// A snapshot of how well the cache is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+	pub hits: u64,
+	pub misses: u64,
+	/// Bytes currently held by the cache.
+	pub used: usize,
+	pub budget: usize,
+}
+
+impl CacheStats {
+	/// Fraction of reads that were served from memory, between 0 and 1.
+	pub fn hit_rate(&self) -> f32 {
+		match self.hits + self.misses {
+			0 => 0.0,
+			total => self.hits as f32 / total as f32,
+		}
+	}
+}
+
+impl fmt::Display for CacheStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{} hits, {} misses ({:.0}% hit rate), {:.1} of {:.1} MiB used",
+			self.hits,
+			self.misses,
+			self.hit_rate() * 100.0,
+			self.used as f32 / MIB as f32,
+			self.budget as f32 / MIB as f32
+		)
+	}
+}
+
+impl SegmentCache {
+	/// Creates a cache that holds at most `budget` bytes. A budget of 0 disables caching.
+	pub fn new(budget: usize) -> Arc<SegmentCache> {
+		Arc::new(SegmentCache {
+			budget,
+			state: Mutex::new(CacheState::default()),
+		})
+	}
+
+	/// Reads a file from `source`, or from memory if it has been read recently.
+	pub fn read(&self, source: &dyn SongSource, file: &str) -> Result<SegmentData, DjError> {
+		let key = source.file_path(file);
+		{
+			let mut state = self.state.lock().unwrap();
+			state.clock += 1;
+			let clock = state.clock;
+			if let Some(entry) = state.entries.get_mut(&key) {
+				entry.last_used = clock;
+				let data = entry.data.clone();
+				state.hits += 1;
+				return Ok(data);
+			}
+			state.misses += 1;
+		}
+
+		// The lock isn't held while reading, so slow reads don't block other readers.
+		let data: SegmentData = source.read_file(file)?.into();
+		self.insert(key, data.clone());
+		Ok(data)
+	}
+
+	fn insert(&self, key: PathBuf, data: SegmentData) {
+		if data.len() > self.budget {
+			return;
+		}
+		let mut state = self.state.lock().unwrap();
+		while state.used + data.len() > self.budget {
+			let oldest = state
+				.entries
+				.iter()
+				.min_by_key(|(_, entry)| entry.last_used)
+				.map(|(key, _)| key.clone())
+				.unwrap();
+			let evicted = state.entries.remove(&oldest).unwrap();
+			state.used -= evicted.data.len();
+		}
+		let last_used = state.clock;
+		state.used += data.len();
+		if let Some(replaced) = state.entries.insert(key, CacheEntry { data, last_used }) {
+			state.used -= replaced.data.len();
+		}
+	}
+
+	pub fn stats(&self) -> CacheStats {
+		let state = self.state.lock().unwrap();
+		CacheStats {
+			hits: state.hits,
+			misses: state.misses,
+			used: state.used,
+			budget: self.budget,
+		}
+	}
+}
+
+/// A song source that reads its files through a shared cache.
+#[derive(Debug)]
+pub struct CachedSource {
+	inner: Arc<dyn SongSource>,
+	cache: Arc<SegmentCache>,
+}
+
+impl CachedSource {
+	pub fn new(inner: Arc<dyn SongSource>, cache: Arc<SegmentCache>) -> CachedSource {
+		CachedSource { inner, cache }
+	}
+}
+
+impl SongSource for CachedSource {
+	fn kind(&self) -> &'static str {
+		self.inner.kind()
+	}
+
+	fn path(&self) -> &Path {
+		self.inner.path()
+	}
+
+	fn file_path(&self, file: &str) -> PathBuf {
+		self.inner.file_path(file)
+	}
+
+	fn list_files(&self) -> Result<Vec<String>, DjError> {
+		self.inner.list_files()
+	}
+
+	fn read_file(&self, file: &str) -> Result<Vec<u8>, DjError> {
+		Ok(self.read_shared(file)?.to_vec())
+	}
+
+	fn read_shared(&self, file: &str) -> Result<SegmentData, DjError> {
+		self.cache.read(&*self.inner, file)
+	}
+}
+
+#[cfg(test)]
+mod test_segment_cache {
+	use super::*;
+	use crate::song_source::LooseFiles;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	/// A source that makes up files of a given size, and counts how often it is read.
+	#[derive(Debug)]
+	struct FakeSource {
+		reads: AtomicUsize,
+	}
+
+	impl SongSource for FakeSource {
+		fn kind(&self) -> &'static str {
+			"fake"
+		}
+
+		fn path(&self) -> &Path {
+			Path::new("fake")
+		}
+
+		fn list_files(&self) -> Result<Vec<String>, DjError> {
+			Ok(vec![])
+		}
+
+		fn read_file(&self, file: &str) -> Result<Vec<u8>, DjError> {
+			self.reads.fetch_add(1, Ordering::SeqCst);
+			Ok(vec![0; file.parse().unwrap()])
+		}
+	}
+
+	#[test]
+	fn test_cache_hits() {
+		let source = FakeSource {
+			reads: AtomicUsize::new(0),
+		};
+		let cache = SegmentCache::new(100);
+		assert_eq!(cache.read(&source, "10").unwrap().len(), 10);
+		assert_eq!(cache.read(&source, "10").unwrap().len(), 10);
+		assert_eq!(source.reads.load(Ordering::SeqCst), 1);
+		assert_eq!(
+			cache.stats(),
+			CacheStats {
+				hits: 1,
+				misses: 1,
+				used: 10,
+				budget: 100,
+			}
+		);
+		assert_eq!(cache.stats().hit_rate(), 0.5);
+	}
+
+	#[test]
+	fn test_cache_evicts_least_recently_used() {
+		let source = FakeSource {
+			reads: AtomicUsize::new(0),
+		};
+		let cache = SegmentCache::new(100);
+		cache.read(&source, "40").unwrap();
+		cache.read(&source, "50").unwrap();
+		cache.read(&source, "40").unwrap();
+		cache.read(&source, "30").unwrap();
+		assert_eq!(cache.stats().used, 70);
+		assert_eq!(source.reads.load(Ordering::SeqCst), 3);
+		cache.read(&source, "40").unwrap();
+		cache.read(&source, "30").unwrap();
+		assert_eq!(source.reads.load(Ordering::SeqCst), 3);
+		cache.read(&source, "50").unwrap();
+		assert_eq!(source.reads.load(Ordering::SeqCst), 4);
+
+		cache.read(&source, "101").unwrap();
+		assert!(cache.stats().used <= 100);
+		cache.read(&source, "101").unwrap();
+		assert_eq!(source.reads.load(Ordering::SeqCst), 6);
+	}
+
+	#[test]
+	fn test_disabled_cache() {
+		let source = FakeSource {
+			reads: AtomicUsize::new(0),
+		};
+		let cache = SegmentCache::new(0);
+		cache.read(&source, "1").unwrap();
+		cache.read(&source, "1").unwrap();
+		assert_eq!(source.reads.load(Ordering::SeqCst), 2);
+		assert_eq!(cache.stats().used, 0);
+	}
+
+	#[test]
+	fn test_loose_files_in_one_directory() {
+		let dir = std::env::temp_dir().join(format!("stream_autodj_cache_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("a_loop.wav"), b"a").unwrap();
+		std::fs::write(dir.join("b_loop.wav"), b"b").unwrap();
+		let cache = SegmentCache::new(100);
+		let a = CachedSource::new(Arc::new(LooseFiles::new(&dir, "a")), cache.clone());
+		let b = CachedSource::new(Arc::new(LooseFiles::new(&dir, "b")), cache.clone());
+		let read = |source: &CachedSource| source.read_shared("loop.wav").map(|data| data.to_vec());
+		let (first_a, first_b, second_a) = (read(&a), read(&b), read(&a));
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(first_a.unwrap(), b"a");
+		assert_eq!(first_b.unwrap(), b"b");
+		assert_eq!(second_a.unwrap(), b"a");
+		assert_eq!(cache.stats().hits, 1);
+	}
+
+	#[test]
+	fn test_cached_source() {
+		let source = Arc::new(FakeSource {
+			reads: AtomicUsize::new(0),
+		});
+		let cached = CachedSource::new(source.clone(), SegmentCache::new(100));
+		assert_eq!(cached.read_shared("10").unwrap().len(), 10);
+		assert_eq!(cached.read_file("10").unwrap(), vec![0; 10]);
+		assert_eq!(source.reads.load(Ordering::SeqCst), 1);
+	}
+}
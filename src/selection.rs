@@ -0,0 +1,147 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::{errors::DjError, metadata::SongMetadata, Song};
+
+/// How far apart two tempos can be, as a fraction of the slower one, and still mix well.
+const TEMPO_TOLERANCE: f32 = 0.06;
+
+/// How the next song is picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Selection {
+	/// Any song, at random.
+	#[default]
+	Random,
+	/// Songs in a key that's compatible with the song before, and with a similar tempo. Any song is
+	/// picked when none of them are.
+	Harmonic,
+}
+
+impl FromStr for Selection {
+	type Err = DjError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"random" => Ok(Selection::Random),
+			"harmonic" => Ok(Selection::Harmonic),
+			_ => Err(DjError::SelectionInvalidValue),
+		}
+	}
+}
+
+/// Why a song was picked by the harmonic selection, from the best reason to the worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+	KeyAndTempo,
+	Key,
+	Tempo,
+	NoneCompatible,
+}
+
+impl fmt::Display for Reason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Reason::KeyAndTempo => write!(f, "compatible key and similar tempo"),
+			Reason::Key => write!(f, "compatible key"),
+			Reason::Tempo => write!(f, "similar tempo"),
+			Reason::NoneCompatible => write!(f, "no compatible song, picked at random"),
+		}
+	}
+}
+
+/// Whether two tempos are close enough to mix, counting half and double time.
+pub fn similar_tempo(a: f32, b: f32) -> bool {
+	[b, b * 2.0, b / 2.0]
+		.iter()
+		.any(|b| (a - b).abs() <= a.min(*b) * TEMPO_TOLERANCE)
+}
+
+/// The candidates that mix best into a song with the `previous` metadata: songs in a compatible key
+/// with a similar tempo, then songs in a compatible key, then songs with a similar tempo. Songs
+/// without a key or tempo don't match on it. Every candidate is returned if none of them match.
+pub fn harmonic_candidates<'a>(
+	candidates: &[&'a String], songs: &HashMap<String, Song>, previous: &SongMetadata,
+) -> (Vec<&'a String>, Reason) {
+	let key = |song_id: &String| match (previous.key, songs[song_id.as_str()].metadata.key) {
+		(Some(previous), Some(key)) => previous.is_compatible(&key),
+		_ => false,
+	};
+	let tempo = |song_id: &String| match (previous.bpm, songs[song_id.as_str()].metadata.bpm) {
+		(Some(previous), Some(bpm)) => similar_tempo(previous, bpm),
+		_ => false,
+	};
+	let reason = |song_id: &String| match (key(song_id), tempo(song_id)) {
+		(true, true) => Reason::KeyAndTempo,
+		(true, false) => Reason::Key,
+		(false, true) => Reason::Tempo,
+		(false, false) => Reason::NoneCompatible,
+	};
+	let best = candidates
+		.iter()
+		.map(|song_id| reason(song_id))
+		.min()
+		.unwrap_or(Reason::NoneCompatible);
+	let matching = candidates
+		.iter()
+		.filter(|song_id| reason(song_id) == best)
+		.copied()
+		.collect();
+	(matching, best)
+}
+
+#[cfg(test)]
+mod test_selection {
+	use super::*;
+	use crate::initialize_songs;
+
+	fn songs(metadata: &[(&str, Option<&str>, Option<f32>)]) -> HashMap<String, Song> {
+		let paths = metadata
+			.iter()
+			.map(|(song_id, _, _)| format!("songs/{}_loop.ogg", song_id))
+			.collect::<Vec<_>>();
+		let mut songs = initialize_songs(&paths).unwrap();
+		for (song_id, key, bpm) in metadata {
+			let song = songs.get_mut(*song_id).unwrap();
+			song.metadata.key = key.map(|key| key.parse().unwrap());
+			song.metadata.bpm = *bpm;
+		}
+		songs
+	}
+
+	#[test]
+	fn test_similar_tempo() {
+		assert!(similar_tempo(128.0, 128.0));
+		assert!(similar_tempo(128.0, 124.0));
+		assert!(similar_tempo(70.0, 140.0));
+		assert!(!similar_tempo(128.0, 110.0));
+	}
+
+	#[test]
+	fn test_harmonic_candidates() {
+		let songs = songs(&[
+			("previous", Some("8A"), Some(128.0)),
+			("both", Some("9A"), Some(126.0)),
+			("key", Some("8B"), Some(90.0)),
+			("tempo", Some("2B"), Some(128.0)),
+			("clash", Some("3A"), Some(100.0)),
+			("unknown", None, None),
+		]);
+		let previous = &songs["previous"].metadata;
+		let mut candidates = songs.keys().filter(|id| *id != "previous").collect::<Vec<_>>();
+		candidates.sort();
+		let pick = |candidates: &[&String]| {
+			let (picked, reason) = harmonic_candidates(candidates, &songs, previous);
+			(picked.iter().map(|id| id.to_string()).collect::<Vec<_>>(), reason)
+		};
+		assert_eq!(pick(&candidates), (vec!["both".to_string()], Reason::KeyAndTempo));
+		candidates.retain(|id| *id != "both");
+		assert_eq!(pick(&candidates), (vec!["key".to_string()], Reason::Key));
+		candidates.retain(|id| *id != "key");
+		assert_eq!(pick(&candidates), (vec!["tempo".to_string()], Reason::Tempo));
+		candidates.retain(|id| *id != "tempo");
+		assert_eq!(
+			pick(&candidates),
+			(vec!["clash".to_string(), "unknown".to_string()], Reason::NoneCompatible)
+		);
+		assert_eq!("shuffle".parse::<Selection>(), Err(DjError::SelectionInvalidValue));
+	}
+}
@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops the DJ cleanly on Ctrl+C or SIGTERM, so that the song that was playing still makes it
+/// into the history. Asking a second time ends it straight away. Elsewhere than on Unix, the DJ
+/// just ends.
+pub fn listen() {
+	#[cfg(unix)]
+	unsafe {
+		let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+		libc::signal(libc::SIGINT, handler);
+		libc::signal(libc::SIGTERM, handler);
+	}
+}
+
+#[cfg(unix)]
+extern "C" fn handle(signal: libc::c_int) {
+	REQUESTED.store(true, Ordering::Relaxed);
+	unsafe {
+		libc::signal(signal, libc::SIG_DFL);
+	}
+}
+
+/// Whether the DJ has been asked to stop.
+pub fn requested() -> bool {
+	REQUESTED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,472 @@
+use std::{
+	collections::{BTreeSet, HashMap},
+	fmt, fs,
+	fs::File,
+	io::Read,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use tar::Archive as TarArchive;
+use tracing::warn;
+use zip::ZipArchive;
+
+use crate::{detect_file_type, errors::DjError, get_song_name, segment_cache::SegmentData, FileType};
+
+/// The kinds of archive a song can be packed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+	Zip,
+	Tar,
+	/// A tar archive compressed with zstd.
+	TarZst,
+}
+
+/// Name that a song's metadata file is listed under, regardless of what it's called on disk.
+pub const METADATA_FILE: &str = "meta.toml";
+
+/// A place that a song's files can be loaded from.
+///
+/// Files are always named by segment, eg. `start.ogg`, `loop0.drums.ogg` or `meta.toml`, no
+/// matter how the source stores them.
+pub trait SongSource: fmt::Debug + Send + Sync {
+	/// A short description of the kind of source, eg. `archive`.
+	fn kind(&self) -> &'static str;
+
+	/// Where the song is stored.
+	fn path(&self) -> &Path;
+
+	/// Where one of the song's files is stored. Unlike `path`, this is never shared with another
+	/// song's files.
+	fn file_path(&self, file: &str) -> PathBuf {
+		self.path().join(file)
+	}
+
+	/// Lists the names of all the song's files.
+	fn list_files(&self) -> Result<Vec<String>, DjError>;
+
+	/// Reads one of the song's files into memory.
+	fn read_file(&self, file: &str) -> Result<Vec<u8>, DjError>;
+
+	/// Reads one of the song's files for playback. Sources that keep files in memory can share
+	/// them instead of copying.
+	fn read_shared(&self, file: &str) -> Result<SegmentData, DjError> {
+		Ok(self.read_file(file)?.into())
+	}
+}
+
+impl PartialEq for dyn SongSource {
+	fn eq(&self, other: &Self) -> bool {
+		self.kind() == other.kind() && self.path() == other.path()
+	}
+}
+
+/// Loose files named `SONGNAME_SEGMENT.ext` in a directory shared with other songs, with
+/// metadata in `SONGNAME.toml`.
+#[derive(Debug, Clone)]
+pub struct LooseFiles {
+	dir: PathBuf,
+	song_id: String,
+	files: BTreeSet<String>,
+}
+
+impl LooseFiles {
+	pub fn new<P: AsRef<Path>>(dir: P, song_id: &str) -> LooseFiles {
+		LooseFiles {
+			dir: dir.as_ref().to_path_buf(),
+			song_id: song_id.to_string(),
+			files: BTreeSet::new(),
+		}
+	}
+}
+
+impl SongSource for LooseFiles {
+	fn kind(&self) -> &'static str {
+		"loose files"
+	}
+
+	fn path(&self) -> &Path {
+		&self.dir
+	}
+
+	fn file_path(&self, file: &str) -> PathBuf {
+		if file == METADATA_FILE {
+			self.dir.join(format!("{}.toml", self.song_id))
+		}
+		else {
+			self.dir.join(format!("{}_{}", self.song_id, file))
+		}
+	}
+
+	fn list_files(&self) -> Result<Vec<String>, DjError> {
+		Ok(self.files.iter().cloned().collect())
+	}
+
+	fn read_file(&self, file: &str) -> Result<Vec<u8>, DjError> {
+		read_from_disk(&self.file_path(file))
+	}
+}
+
+/// A zip archive named `SONGNAME.zip`.
+///
+/// The archive is opened on first use and kept open, so its central directory is only parsed once.
+#[derive(Debug)]
+pub struct ZipArchiveSource {
+	path: PathBuf,
+	archive: Mutex<Option<ZipArchive<File>>>,
+}
+
+impl ZipArchiveSource {
+	pub fn new<P: AsRef<Path>>(path: P) -> ZipArchiveSource {
+		ZipArchiveSource {
+			path: path.as_ref().to_path_buf(),
+			archive: Mutex::new(None),
+		}
+	}
+
+	fn with_archive<T, F>(&self, f: F) -> Result<T, DjError>
+	where
+		F: FnOnce(&mut ZipArchive<File>) -> Result<T, DjError>,
+	{
+		let mut archive = self.archive.lock().unwrap();
+		if archive.is_none() {
+			let file = File::open(&self.path).map_err(|e| unreadable(&self.path, e))?;
+			*archive = Some(ZipArchive::new(file).map_err(|e| unreadable(&self.path, e))?);
+		}
+		f(archive.as_mut().unwrap())
+	}
+}
+
+impl SongSource for ZipArchiveSource {
+	fn kind(&self) -> &'static str {
+		"archive"
+	}
+
+	fn path(&self) -> &Path {
+		&self.path
+	}
+
+	fn list_files(&self) -> Result<Vec<String>, DjError> {
+		let mut files = self.with_archive(|archive| Ok(archive.file_names().map(String::from).collect::<Vec<_>>()))?;
+		files.sort();
+		Ok(files)
+	}
+
+	fn read_file(&self, file: &str) -> Result<Vec<u8>, DjError> {
+		let mut data = Vec::new();
+		self.with_archive(|archive| {
+			archive
+				.by_name(file)
+				.map_err(|e| unreadable(&self.path.join(file), e))?
+				.read_to_end(&mut data)
+				.map_err(|e| unreadable(&self.path.join(file), e))
+		})?;
+		Ok(data)
+	}
+}
+
+/// A tar archive named `SONGNAME.tar`, or `SONGNAME.tar.zst` if compressed with zstd.
+///
+/// Tar archives can't be read at random, so every read scans the archive from the start.
+#[derive(Debug, Clone)]
+pub struct TarArchiveSource {
+	path: PathBuf,
+	zstd: bool,
+}
+
+impl TarArchiveSource {
+	pub fn new<P: AsRef<Path>>(path: P, zstd: bool) -> TarArchiveSource {
+		TarArchiveSource {
+			path: path.as_ref().to_path_buf(),
+			zstd,
+		}
+	}
+
+	fn open(&self) -> Result<TarArchive<Box<dyn Read>>, DjError> {
+		let f = File::open(&self.path).map_err(|e| unreadable(&self.path, e))?;
+		let reader: Box<dyn Read> = if self.zstd {
+			Box::new(zstd::stream::read::Decoder::new(f).map_err(|e| unreadable(&self.path, e))?)
+		}
+		else {
+			Box::new(f)
+		};
+		Ok(TarArchive::new(reader))
+	}
+
+	/// Calls `f` with the name and contents of each file in the archive until it returns `true`.
+	fn scan<F>(&self, mut f: F) -> Result<(), DjError>
+	where
+		F: FnMut(String, &mut dyn Read) -> Result<bool, DjError>,
+	{
+		let mut archive = self.open()?;
+		for entry in archive.entries().map_err(|e| unreadable(&self.path, e))? {
+			let mut entry = entry.map_err(|e| unreadable(&self.path, e))?;
+			if !entry.header().entry_type().is_file() {
+				continue;
+			}
+			let name = match entry.path().ok().and_then(|p| p.to_str().map(String::from)) {
+				Some(name) => name.trim_start_matches("./").to_string(),
+				None => {
+					warn!(path = %self.path.display(), "{}, dropping", DjError::PathNotValidUnicode);
+					continue;
+				}
+			};
+			if f(name, &mut entry)? {
+				break;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl SongSource for TarArchiveSource {
+	fn kind(&self) -> &'static str {
+		"archive"
+	}
+
+	fn path(&self) -> &Path {
+		&self.path
+	}
+
+	fn list_files(&self) -> Result<Vec<String>, DjError> {
+		let mut files = Vec::new();
+		self.scan(|name, _| {
+			files.push(name);
+			Ok(false)
+		})?;
+		files.sort();
+		Ok(files)
+	}
+
+	fn read_file(&self, file: &str) -> Result<Vec<u8>, DjError> {
+		let mut data = None;
+		self.scan(|name, entry| {
+			if name != file {
+				return Ok(false);
+			}
+			let mut buf = Vec::new();
+			entry
+				.read_to_end(&mut buf)
+				.map_err(|e| unreadable(&self.path.join(file), e))?;
+			data = Some(buf);
+			Ok(true)
+		})?;
+		data.ok_or_else(|| unreadable(&self.path.join(file), "no such file in archive"))
+	}
+}
+
+/// A directory named after the song, containing only that song's files.
+#[derive(Debug, Clone)]
+pub struct SongDirectory {
+	path: PathBuf,
+}
+
+impl SongDirectory {
+	pub fn new<P: AsRef<Path>>(path: P) -> SongDirectory {
+		SongDirectory {
+			path: path.as_ref().to_path_buf(),
+		}
+	}
+}
+
+impl SongSource for SongDirectory {
+	fn kind(&self) -> &'static str {
+		"song directory"
+	}
+
+	fn path(&self) -> &Path {
+		&self.path
+	}
+
+	fn list_files(&self) -> Result<Vec<String>, DjError> {
+		let entries = fs::read_dir(&self.path).map_err(|e| unreadable(&self.path, e))?;
+		let mut files = Vec::new();
+		for entry in entries {
+			let entry = entry.map_err(|e| unreadable(&self.path, e))?;
+			match entry.file_name().into_string() {
+				Ok(name) => files.push(name),
+				Err(_) => warn!(path = %self.path.display(), "{}, dropping", DjError::PathNotValidUnicode),
+			}
+		}
+		files.sort();
+		Ok(files)
+	}
+
+	fn read_file(&self, file: &str) -> Result<Vec<u8>, DjError> {
+		read_from_disk(&self.path.join(file))
+	}
+}
+
+fn read_from_disk(path: &Path) -> Result<Vec<u8>, DjError> {
+	fs::read(path).map_err(|e| unreadable(path, e))
+}
+
+fn unreadable<E: fmt::Display>(path: &Path, e: E) -> DjError {
+	DjError::UnreadableFile(path.display().to_string(), e.to_string())
+}
+
+/// Song ids paired with where their files come from.
+pub type FoundSources = Vec<(String, Arc<dyn SongSource>)>;
+
+/// Works out which songs the given paths belong to, and where each song's files come from.
+///
+/// Directories are treated as song directories. Files that aren't part of a song are
+/// dropped with a warning. Sources are returned in the order they were first encountered.
+pub fn find_sources<P: AsRef<Path>>(paths: &[P]) -> Result<FoundSources, DjError> {
+	let mut sources: FoundSources = Vec::new();
+	// Loose files are spread over many paths, so they are gathered up before being added.
+	let mut loose: Vec<LooseFiles> = Vec::new();
+	let mut loose_index: HashMap<(PathBuf, String), usize> = HashMap::new();
+	// Where each loose song should be placed in `sources`.
+	let mut loose_order: Vec<usize> = Vec::new();
+
+	for path in paths {
+		let path = path.as_ref();
+		let file_name = match path.file_name().unwrap().to_str().ok_or(DjError::PathNotValidUnicode) {
+			Ok(val) => val,
+			Err(e) => {
+				warn!(path = %path.display(), "{}, dropping", e);
+				continue;
+			}
+		};
+		if path.is_dir() {
+			sources.push((file_name.to_string(), Arc::new(SongDirectory::new(path))));
+			continue;
+		}
+		let file_type = match detect_file_type(file_name) {
+			Ok(val) => val,
+			Err(e) => {
+				warn!(path = %path.display(), "{}, dropping", e);
+				continue;
+			}
+		};
+
+		let (song_id, file) = match file_type {
+			FileType::SongArchiveFormat(format) => {
+				let song_id = file_name.split('.').next().unwrap();
+				if song_id.is_empty() {
+					return Err(DjError::InvalidFileName(file_name.to_string()));
+				}
+				let source: Arc<dyn SongSource> = match format {
+					ArchiveFormat::Zip => Arc::new(ZipArchiveSource::new(path)),
+					ArchiveFormat::Tar => Arc::new(TarArchiveSource::new(path, false)),
+					ArchiveFormat::TarZst => Arc::new(TarArchiveSource::new(path, true)),
+				};
+				sources.push((song_id.to_string(), source));
+				continue;
+			}
+			FileType::SegmentFormat => {
+				let song_id = match get_song_name(file_name) {
+					Ok(song_id) => song_id,
+					Err(e) => {
+						warn!(path = %path.display(), "{}, dropping", e);
+						continue;
+					}
+				};
+				let file = file_name[song_id.len() + 1..].to_string();
+				(song_id, file)
+			}
+			FileType::SongMetadataFormat => {
+				let song_id = file_name.split('.').next().unwrap().to_string();
+				(song_id, METADATA_FILE.to_string())
+			}
+		};
+		let dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+		let index = *loose_index.entry((dir.clone(), song_id.clone())).or_insert_with(|| {
+			loose.push(LooseFiles::new(&dir, &song_id));
+			loose_order.push(sources.len() + loose_order.len());
+			loose.len() - 1
+		});
+		loose[index].files.insert(file);
+	}
+
+	for (files, position) in loose.into_iter().zip(loose_order) {
+		let song_id = files.song_id.to_string();
+		sources.insert(position.min(sources.len()), (song_id, Arc::new(files)));
+	}
+	Ok(sources)
+}
+
+#[cfg(test)]
+mod test_song_sources {
+	use super::*;
+
+	#[test]
+	fn test_find_sources() {
+		let sources = find_sources(&[
+			"songs/song_1_start.ogg",
+			"songs/song_2.zip",
+			"songs/song_1_loop.ogg",
+			"songs/song_1.toml",
+			"songs/readme.txt",
+			"songs/album/start.wav",
+			"other/song_1_end.ogg",
+		])
+		.unwrap();
+		let found = sources
+			.iter()
+			.map(|(id, source)| (id.as_str(), source.kind(), source.path().to_str().unwrap()))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			found,
+			vec![
+				("song_1", "loose files", "songs"),
+				("song_2", "archive", "songs/song_2.zip"),
+				("song_1", "loose files", "other"),
+			]
+		);
+		assert_eq!(
+			sources[0].1.list_files().unwrap(),
+			vec!["loop.ogg", "meta.toml", "start.ogg"]
+		);
+	}
+
+	#[test]
+	fn test_loose_file_paths() {
+		let files = LooseFiles::new("songs", "song_1");
+		assert_eq!(files.file_path("loop0.ogg"), PathBuf::from("songs/song_1_loop0.ogg"));
+		assert_eq!(files.file_path(METADATA_FILE), PathBuf::from("songs/song_1.toml"));
+	}
+
+	#[test]
+	fn test_song_directory() {
+		let directory = SongDirectory::new("test-data/test_song_directory/directory_song");
+		assert_eq!(directory.list_files().unwrap(), vec!["loop.wav", "start.wav"]);
+		assert_eq!(directory.read_file("start.wav").unwrap(), Vec::<u8>::new());
+		assert!(directory.read_file("end.wav").is_err());
+	}
+
+	#[test]
+	fn test_zip_archive() {
+		let archive = ZipArchiveSource::new("test-data/test_song_archive/song_archive.zip");
+		assert_eq!(archive.list_files().unwrap(), vec!["loop.wav", "start.wav"]);
+		assert!(archive.read_file("start.wav").is_ok());
+		assert!(archive.read_file("end.wav").is_err());
+	}
+
+	#[test]
+	fn test_tar_archives() {
+		for (path, zstd) in [
+			("test-data/test_song_archive/song_tar.tar", false),
+			("test-data/test_song_archive/song_tar_zst.tar.zst", true),
+		] {
+			let archive = TarArchiveSource::new(path, zstd);
+			assert_eq!(archive.list_files().unwrap(), vec!["loop.wav", "start.wav"]);
+			assert_eq!(archive.read_file("loop.wav").unwrap(), Vec::<u8>::new());
+			assert!(archive.read_file("end.wav").is_err());
+		}
+		assert!(TarArchiveSource::new("test-data/test_song_archive/song_tar.tar", true)
+			.list_files()
+			.is_err());
+	}
+
+	#[test]
+	fn test_find_archive_sources() {
+		let sources = find_sources(&["songs/a.zip", "songs/b.tar", "songs/c.tar.zst", "songs/d.zst"]).unwrap();
+		let found = sources
+			.iter()
+			.map(|(id, source)| (id.as_str(), source.kind()))
+			.collect::<Vec<_>>();
+		assert_eq!(found, vec![("a", "archive"), ("b", "archive"), ("c", "archive")]);
+	}
+}
@@ -0,0 +1,174 @@
+use std::{
+	collections::HashMap,
+	fs,
+	fs::File,
+	io::{BufReader, Cursor, Read},
+	path::PathBuf,
+	str::FromStr,
+	time::{Duration, Instant},
+};
+
+use rodio::{decoder::Decoder, Sink, Source};
+use tracing::{info, warn};
+
+use crate::{detect_file_type, errors::DjError, FileType, SegmentDecoder};
+
+/// When a stinger should start playing, relative to the music.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantize {
+	Immediate,
+	Beat,
+	Bar,
+}
+
+impl FromStr for Quantize {
+	type Err = DjError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"now" => Ok(Quantize::Immediate),
+			"beat" => Ok(Quantize::Beat),
+			"bar" => Ok(Quantize::Bar),
+			_ => Err(DjError::InvalidControlCommand(format!(
+				"unknown quantization '{}', expected now, beat or bar",
+				s
+			))),
+		}
+	}
+}
+
+/// How long to wait from `elapsed` until the start of the next beat (or bar, if `beats` > 1).
+/// There's no waiting if `bpm` isn't a tempo.
+pub fn quantize_delay(elapsed: Duration, bpm: f32, beats: u32) -> Duration {
+	let unit = (60_000_000_000.0 / bpm as f64 * beats as f64).round();
+	if !unit.is_finite() || unit < 1.0 {
+		return Duration::from_secs(0);
+	}
+	let unit = unit as u128;
+	let into_unit = elapsed.as_nanos() % unit;
+	if into_unit == 0 {
+		return Duration::from_secs(0);
+	}
+	Duration::from_nanos((unit - into_unit) as u64)
+}
+
+/// Plays one-shot sounds over the music, ducking the music while they play.
+pub struct StingerLayer {
+	sink: Sink,
+	/// Global stingers, by name.
+	stingers: HashMap<String, PathBuf>,
+	duck_volume: f32,
+	duck_at: Option<Instant>,
+	ducked: bool,
+}
+
+impl StingerLayer {
+	pub fn new(sink: Sink, stingers_dir: Option<&str>, duck_volume: f32) -> Result<StingerLayer, DjError> {
+		let mut stingers = HashMap::new();
+		if let Some(dir) = stingers_dir {
+			let paths = fs::read_dir(dir).map_err(|_| DjError::UnreadableDirectory(dir.to_string()))?;
+			for path in paths {
+				let path = path.map_err(|_| DjError::UnreadableDirectory(dir.to_string()))?.path();
+				let file_name = path.file_name().unwrap().to_str().ok_or(DjError::PathNotValidUnicode)?;
+				match detect_file_type(file_name) {
+					Ok(FileType::SegmentFormat) => {
+						let name = file_name.split('.').next().unwrap().to_string();
+						stingers.insert(name, path.clone());
+					}
+					_ => {
+						warn!(file = file_name, "Not a stinger, dropping");
+					}
+				}
+			}
+			info!(count = stingers.len(), "Found global stingers");
+		}
+
+		Ok(StingerLayer {
+			sink,
+			stingers,
+			duck_volume,
+			duck_at: None,
+			ducked: false,
+		})
+	}
+
+	/// Reads a global stinger from disk for playback.
+	pub fn read_stinger(&self, name: &str) -> Result<SegmentDecoder, DjError> {
+		let path = self
+			.stingers
+			.get(name)
+			.ok_or_else(|| DjError::UnknownStinger(name.to_string()))?;
+		let mut data = Vec::new();
+		File::open(path)
+			.and_then(|mut file| file.read_to_end(&mut data))
+			.map_err(|e| DjError::UnreadableFile(path.display().to_string(), e.to_string()))?;
+		Decoder::new(BufReader::new(Cursor::new(data.into())))
+			.map_err(|_| DjError::UnrecognizedSongFormat(path.display().to_string()))
+	}
+
+	/// Starts playing `source` after `delay`.
+	pub fn trigger<S>(&mut self, source: S, delay: Duration)
+	where
+		S: Source<Item = i16> + Send + 'static,
+	{
+		self.sink.append(source.delay(delay));
+		let duck_at = Instant::now() + delay;
+		// Don't postpone ducking if a stinger is already playing.
+		if !self.ducked {
+			self.duck_at = Some(self.duck_at.map_or(duck_at, |at| at.min(duck_at)));
+		}
+	}
+
+	/// Ducks or restores the music volume depending on whether any stingers are playing.
+	pub fn update(&mut self, music: &Sink) {
+		if let Some(duck_at) = self.duck_at {
+			if Instant::now() >= duck_at {
+				music.set_volume(self.duck_volume);
+				self.duck_at = None;
+				self.ducked = true;
+			}
+		}
+		if self.ducked && self.sink.empty() {
+			music.set_volume(1.0);
+			self.ducked = false;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_stingers {
+	use super::*;
+
+	#[test]
+	fn test_quantize_delay() {
+		// 120 bpm = 1 beat every 0.5 seconds
+		assert_eq!(
+			quantize_delay(Duration::from_millis(0), 120.0, 1),
+			Duration::from_secs(0)
+		);
+		assert_eq!(quantize_delay(Duration::from_millis(100), 120.0, 1).as_millis(), 400);
+		assert_eq!(quantize_delay(Duration::from_millis(1600), 120.0, 1).as_millis(), 400);
+		// 4 beats to a bar = 1 bar every 2 seconds
+		assert_eq!(quantize_delay(Duration::from_millis(2500), 120.0, 4).as_millis(), 1500);
+		assert_eq!(
+			quantize_delay(Duration::from_millis(100), 0.0, 1),
+			Duration::from_secs(0)
+		);
+		assert_eq!(
+			quantize_delay(Duration::from_millis(100), f32::NAN, 1),
+			Duration::from_secs(0)
+		);
+		assert_eq!(
+			quantize_delay(Duration::from_millis(100), -120.0, 1),
+			Duration::from_secs(0)
+		);
+	}
+
+	#[test]
+	fn test_parse_quantize() {
+		assert_eq!("now".parse::<Quantize>(), Ok(Quantize::Immediate));
+		assert_eq!("beat".parse::<Quantize>(), Ok(Quantize::Beat));
+		assert_eq!("bar".parse::<Quantize>(), Ok(Quantize::Bar));
+		assert!("measure".parse::<Quantize>().is_err());
+	}
+}